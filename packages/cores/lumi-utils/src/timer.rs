@@ -1,3 +1,5 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 
@@ -22,6 +24,33 @@ impl Timer for SystemTimer {
     }
 }
 
+/// Manually driven clock for tests. Clones share the same time, so a test can
+/// keep a handle and advance the clock seen by the code under test.
+#[derive(Debug, Default, Clone)]
+pub struct MockTimer {
+    now: Arc<AtomicU64>,
+}
+
+impl MockTimer {
+    pub fn new(now_ms: u64) -> Self {
+        MockTimer { now: Arc::new(AtomicU64::new(now_ms)) }
+    }
+
+    pub fn set(&self, now_ms: u64) {
+        self.now.store(now_ms, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.now.fetch_add(ms, Ordering::SeqCst);
+    }
+}
+
+impl Timer for MockTimer {
+    fn now_ms(&mut self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -33,5 +62,17 @@ mod test {
 
         assert!(0 < now);
     }
-}
 
+    #[test]
+    fn test_mock_timer() {
+        let mut timer = MockTimer::new(1_000);
+        let handle = timer.clone();
+        assert_eq!(timer.now_ms(), 1_000);
+
+        handle.advance(500);
+        assert_eq!(timer.now_ms(), 1_500);
+
+        handle.set(42);
+        assert_eq!(timer.now_ms(), 42);
+    }
+}
@@ -0,0 +1,107 @@
+//! Delta patches: bsdiff of the installed image against the new one,
+//! gzip-compressed.
+
+use std::io::{self, Read, Write};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::PackageErr;
+
+/// Patch turning `old` into `new`.
+pub fn diff(old: &[u8], new: &[u8]) -> Result<Vec<u8>, PackageErr> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    bsdiff::diff(old, new, &mut encoder).map_err(|_| PackageErr::IoErr)?;
+    encoder.finish().map_err(|_| PackageErr::IoErr)
+}
+
+/// Applies `patch` to `old`, streaming the result to `out`. A patch made for
+/// another base gives garbage or fails, the result has to be checked against
+/// the expected digest.
+pub fn apply<R: Read, W: Write>(old: &[u8], patch: R, out: &mut W) -> Result<(), PackageErr> {
+    let mut patch = GzDecoder::new(patch);
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut oldpos: usize = 0;
+    // Blocks of: control (diff length, extra length, seek), diff bytes added
+    // to the old ones, extra bytes copied as is
+    while let Some(control) = read_control(&mut patch)? {
+        let [diff_len, extra_len, seek] = control;
+        let diff_len = usize::try_from(diff_len).map_err(|_| PackageErr::FormatErr)?;
+        let old_end = oldpos.checked_add(diff_len).ok_or(PackageErr::FormatErr)?;
+        let old = old.get(oldpos..old_end).ok_or(PackageErr::FormatErr)?;
+        for old in old.chunks(buf.len()) {
+            let diff = &mut buf[..old.len()];
+            patch.read_exact(diff).map_err(|_| PackageErr::FormatErr)?;
+            diff.iter_mut().zip(old).for_each(|(new, old)| *new = new.wrapping_add(*old));
+            out.write_all(diff).map_err(|_| PackageErr::IoErr)?;
+        }
+        let copied = io::copy(&mut (&mut patch).take(extra_len as u64), out).map_err(|_| PackageErr::FormatErr)?;
+        if copied != extra_len as u64 {
+            return Err(PackageErr::FormatErr);
+        }
+        oldpos = i64::try_from(old_end).ok()
+            .and_then(|pos| pos.checked_add(seek))
+            .and_then(|pos| usize::try_from(pos).ok())
+            .ok_or(PackageErr::FormatErr)?;
+    }
+    Ok(())
+}
+
+/// Next control triple, or `None` at the end of the patch.
+fn read_control<R: Read>(patch: &mut R) -> Result<Option<[i64; 3]>, PackageErr> {
+    let mut buf = [0u8; 24];
+    let mut filled = 0;
+    while filled < buf.len() {
+        match patch.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(PackageErr::FormatErr),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => return Err(PackageErr::FormatErr),
+        }
+    }
+    // Sign and magnitude, little endian
+    let field = |i: usize| {
+        let value = i64::from_le_bytes(buf[i * 8..i * 8 + 8].try_into().unwrap_or_default());
+        if value < 0 { -(value & i64::MAX) } else { value }
+    };
+    Ok(Some([field(0), field(1), field(2)]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_diff_and_apply() {
+        let old: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let mut new = old.clone();
+        new[5_000..5_100].fill(7);
+        new.extend_from_slice(b"appended");
+
+        let patch = diff(&old, &new).unwrap();
+        assert!(patch.len() < new.len() / 10);
+        let mut out = Vec::new();
+        apply(&old, &patch[..], &mut out).unwrap();
+        assert_eq!(out, new);
+
+        assert_eq!(apply(&old, &b"not a patch"[..], &mut Vec::new()), Err(PackageErr::FormatErr));
+        assert_eq!(apply(&old, &patch[..patch.len() / 2], &mut Vec::new()), Err(PackageErr::FormatErr));
+        assert_eq!(apply(&old[..4_000], &patch[..], &mut Vec::new()), Err(PackageErr::FormatErr));
+    }
+
+    #[test]
+    fn test_apply_matches_bsdiff() {
+        // Moved, changed and new blocks, so the patch seeks back and forth
+        let old: Vec<u8> = (0u32..300_000).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+        let mut new = [&old[200_000..], &old[..100_000], &[9u8; 5_000][..], &old[100_000..200_000]].concat();
+        new[250_000..250_500].iter_mut().for_each(|b| *b ^= 0x5a);
+
+        let patch = diff(&old, &new).unwrap();
+        let mut expected = Vec::new();
+        bsdiff::patch(&old, &mut GzDecoder::new(&patch[..]), &mut expected).unwrap();
+        let mut out = Vec::new();
+        apply(&old, &patch[..], &mut out).unwrap();
+        assert_eq!((out == expected, out == new), (true, true));
+    }
+}
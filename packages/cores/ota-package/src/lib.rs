@@ -0,0 +1,17 @@
+//! Formats shared by the release tooling and the controller: the signed
+//! package manifest, the image signatures and delta patches.
+
+pub mod delta;
+pub mod manifest;
+pub mod signature;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum PackageErr {
+    IoErr,
+    KeyErr,
+    SignErr,
+    FormatErr,
+    ChecksumErr,
+    VerifyNotEqualErr,
+    UnsignedErr,
+}
@@ -0,0 +1,243 @@
+use std::io::Read;
+use openssl::hash::{Hasher, MessageDigest};
+use openssl::pkey::{HasPublic, PKey, PKeyRef, Private};
+use serde::{Deserialize, Serialize};
+
+use crate::signature;
+use crate::PackageErr;
+
+/// Name of the manifest inside a package directory.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Describes a release package: which version the image is, which
+/// controllers may install it, and what the published image file must look
+/// like. Only trusted once opened from a `SignedManifest`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version_name: String,
+    pub version_number: u16,
+    pub version_min_id: u16,
+    /// Controller types the image is built for, e.g. `Hc01`
+    pub hc_types: Vec<String>,
+    /// Oldest version the update may be installed over, empty for any
+    #[serde(default)]
+    pub min_version: String,
+    /// May be installed over a newer version. Never below the security
+    /// counter (`version_number` of installed updates) though.
+    #[serde(default)]
+    pub allow_downgrade: bool,
+    #[serde(default)]
+    pub release_notes: String,
+    /// Unix time the manifest was signed at
+    pub created_at: u64,
+    /// Unix time after which the update must not be installed any more
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// Image file name, relative to the manifest
+    pub image: String,
+    /// Size of the published image file, trailer included
+    pub size: u64,
+    /// Hex SHA-256 of the published image file
+    pub sha256: String,
+    /// Hex SHA-512 of the published image file
+    pub sha512: String,
+    /// Detached signature file name, relative to the manifest
+    pub signature: String,
+    /// The image file also carries its signature as a trailer
+    pub embedded_signature: bool,
+    /// Patches producing the image from older versions, see `delta`
+    #[serde(default)]
+    pub deltas: Vec<Delta>,
+}
+
+/// Patch from an installed older version to the published image file.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Delta {
+    /// Version the patch applies to
+    pub base_version: String,
+    /// Size of the installed image of `base_version`, without signature
+    /// trailer
+    pub base_size: u64,
+    /// Hex SHA-256 of the installed image of `base_version`
+    pub base_sha256: String,
+    /// Patch file name, relative to the manifest
+    pub patch: String,
+    /// Size of the patch file
+    pub size: u64,
+    /// Hex SHA-256 of the patch file
+    pub sha256: String,
+}
+
+impl Manifest {
+    /// Checksum in the form the check-update server returns it.
+    pub fn checksum(&self) -> String {
+        format!("sha256:{}", self.sha256)
+    }
+
+    /// Checks the manifest is complete, not whether it fits a controller.
+    pub fn validate(&self) -> Result<(), PackageErr> {
+        let is_hex = |value: &str, len: usize| value.len() == len && value.chars().all(|c| c.is_ascii_hexdigit());
+        if self.version_name.is_empty()
+            || self.hc_types.is_empty()
+            || self.image.is_empty()
+            || self.size == 0
+            || self.version_min_id > self.version_number
+            || !is_hex(&self.sha256, 64)
+            || !is_hex(&self.sha512, 128)
+            || self.expires_at.is_some_and(|expires_at| expires_at <= self.created_at)
+            || self.deltas.iter().any(|delta| {
+                delta.base_version.is_empty()
+                    || delta.patch.is_empty()
+                    || delta.base_size == 0
+                    || delta.size == 0
+                    || !is_hex(&delta.base_sha256, 64)
+                    || !is_hex(&delta.sha256, 64)
+            })
+        {
+            return Err(PackageErr::FormatErr);
+        }
+        Ok(())
+    }
+}
+
+/// A manifest with the vendor signature over its exact JSON bytes, as
+/// published in `manifest.json` and returned by the check-update server.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedManifest {
+    /// Manifest JSON, signed as is
+    pub payload: String,
+    /// Hex signature of `payload`
+    pub signature: String,
+}
+
+impl SignedManifest {
+    pub fn sign(manifest: &Manifest, key: &PKey<Private>) -> Result<Self, PackageErr> {
+        manifest.validate()?;
+        let payload = serde_json::to_string_pretty(manifest).map_err(|_| PackageErr::FormatErr)?;
+        let signature = signature::sign(key, payload.as_bytes())?;
+        Ok(SignedManifest { payload, signature: hex::encode(signature) })
+    }
+
+    /// The manifest, once its signature checks out against `key`.
+    pub fn open<T: HasPublic>(&self, key: &PKeyRef<T>) -> Result<Manifest, PackageErr> {
+        let signature = hex::decode(&self.signature).map_err(|_| PackageErr::VerifyNotEqualErr)?;
+        signature::verify(key, self.payload.as_bytes(), &signature)?;
+        let manifest: Manifest = serde_json::from_str(&self.payload).map_err(|_| PackageErr::FormatErr)?;
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    pub fn to_json(&self) -> Result<Vec<u8>, PackageErr> {
+        serde_json::to_vec_pretty(self).map_err(|_| PackageErr::FormatErr)
+    }
+
+    pub fn from_json(data: &[u8]) -> Result<Self, PackageErr> {
+        serde_json::from_slice(data).map_err(|_| PackageErr::FormatErr)
+    }
+}
+
+/// Size and hex SHA-256 of everything in `reader`.
+pub fn sha256<R: Read>(reader: R) -> Result<(u64, String), PackageErr> {
+    digest(reader, MessageDigest::sha256())
+}
+
+/// Size and hex SHA-512 of everything in `reader`.
+pub fn sha512<R: Read>(reader: R) -> Result<(u64, String), PackageErr> {
+    digest(reader, MessageDigest::sha512())
+}
+
+fn digest<R: Read>(mut reader: R, digest: MessageDigest) -> Result<(u64, String), PackageErr> {
+    let mut hasher = Hasher::new(digest).map_err(|_| PackageErr::ChecksumErr)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = reader.read(&mut buf).map_err(|_| PackageErr::IoErr)?;
+        if n == 0 {
+            break;
+        }
+        size += n as u64;
+        hasher.update(&buf[..n]).map_err(|_| PackageErr::ChecksumErr)?;
+    }
+    let digest = hasher.finish().map_err(|_| PackageErr::ChecksumErr)?;
+    Ok((size, hex::encode(digest)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+
+    fn key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn manifest() -> Manifest {
+        Manifest {
+            version_name: "1.2.0".to_string(),
+            version_number: 12,
+            version_min_id: 3,
+            hc_types: vec!["Hc01".to_string()],
+            min_version: "1.0.0".to_string(),
+            allow_downgrade: false,
+            release_notes: "Fixes".to_string(),
+            created_at: 1_700_000_000,
+            expires_at: Some(1_800_000_000),
+            image: "hc-1.2.0.bin".to_string(),
+            size: 3,
+            sha256: sha256(&b"abc"[..]).unwrap().1,
+            sha512: sha512(&b"abc"[..]).unwrap().1,
+            signature: "hc-1.2.0.bin.sig".to_string(),
+            embedded_signature: false,
+            deltas: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_digests() {
+        let manifest = manifest();
+        assert_eq!(manifest.checksum(), "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert!(manifest.sha512.starts_with("ddaf35a193617aba"));
+        assert_eq!(sha256(&b"abc"[..]).unwrap().0, 3);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(manifest().validate(), Ok(()));
+        assert_eq!(Manifest { hc_types: Vec::new(), ..manifest() }.validate(), Err(PackageErr::FormatErr));
+        assert_eq!(Manifest { sha256: "abc".to_string(), ..manifest() }.validate(), Err(PackageErr::FormatErr));
+        assert_eq!(Manifest { expires_at: Some(1), ..manifest() }.validate(), Err(PackageErr::FormatErr));
+        assert_eq!(Manifest { version_min_id: 13, ..manifest() }.validate(), Err(PackageErr::FormatErr));
+        assert_eq!(Manifest { expires_at: None, min_version: String::new(), ..manifest() }.validate(), Ok(()));
+
+        let delta = Delta {
+            base_version: "1.1.0".to_string(),
+            base_size: 3,
+            base_sha256: "ab".repeat(32),
+            patch: "hc-1.1.0-1.2.0.patch".to_string(),
+            size: 1,
+            sha256: "cd".repeat(32),
+        };
+        assert_eq!(Manifest { deltas: vec![delta.clone()], ..manifest() }.validate(), Ok(()));
+        let no_base = Delta { base_version: String::new(), ..delta.clone() };
+        assert_eq!(Manifest { deltas: vec![delta, no_base], ..manifest() }.validate(), Err(PackageErr::FormatErr));
+    }
+
+    #[test]
+    fn test_signed_manifest() {
+        let key = key();
+        let signed = SignedManifest::sign(&manifest(), &key).unwrap();
+        let signed = SignedManifest::from_json(&signed.to_json().unwrap()).unwrap();
+        assert_eq!(signed.open(&key), Ok(manifest()));
+        assert_eq!(signed.open(&self::key()), Err(PackageErr::VerifyNotEqualErr));
+
+        // Any change to the payload breaks the signature
+        let tampered = SignedManifest { payload: signed.payload.replace("\"Hc01\"", "\"Hc02\""), ..signed.clone() };
+        assert_eq!(tampered.open(&key), Err(PackageErr::VerifyNotEqualErr));
+        let garbage = SignedManifest { signature: "zz".to_string(), ..signed };
+        assert_eq!(garbage.open(&key), Err(PackageErr::VerifyNotEqualErr));
+
+        assert_eq!(SignedManifest::sign(&Manifest::default(), &key), Err(PackageErr::FormatErr));
+    }
+}
@@ -0,0 +1,129 @@
+use std::io::{Read, Seek, SeekFrom};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{HasPublic, PKey, PKeyRef, Private, Public};
+use openssl::sign::{Signer, Verifier};
+
+use crate::PackageErr;
+
+/// Marks an image carrying its signature at the end:
+/// `image || signature || signature length (u32 big endian) || TRAILER_MAGIC`.
+pub const TRAILER_MAGIC: &[u8; 8] = b"LUMISIG1";
+pub const TRAILER_LEN: u64 = 4 + TRAILER_MAGIC.len() as u64;
+const MAX_SIGNATURE_LEN: u32 = 4096;
+
+pub fn private_key_from_pem(pem: &[u8]) -> Result<PKey<Private>, PackageErr> {
+    PKey::private_key_from_pem(pem).map_err(|_| PackageErr::KeyErr)
+}
+
+pub fn public_key_from_pem(pem: &[u8]) -> Result<PKey<Public>, PackageErr> {
+    PKey::public_key_from_pem(pem).map_err(|_| PackageErr::KeyErr)
+}
+
+/// Signs the SHA-256 of everything in `reader`. Any key type openssl
+/// supports works (DSA, EC, RSA).
+pub fn sign<R: Read>(key: &PKey<Private>, mut reader: R) -> Result<Vec<u8>, PackageErr> {
+    let mut signer = Signer::new(MessageDigest::sha256(), key).map_err(|_| PackageErr::SignErr)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf).map_err(|_| PackageErr::IoErr)?;
+        if n == 0 {
+            break;
+        }
+        signer.update(&buf[..n]).map_err(|_| PackageErr::SignErr)?;
+    }
+    signer.sign_to_vec().map_err(|_| PackageErr::SignErr)
+}
+
+/// Checks `signature` against everything in `reader`.
+pub fn verify<T: HasPublic, R: Read>(key: &PKeyRef<T>, mut reader: R, signature: &[u8]) -> Result<(), PackageErr> {
+    let mut verifier = Verifier::new(MessageDigest::sha256(), key).map_err(|_| PackageErr::KeyErr)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf).map_err(|_| PackageErr::IoErr)?;
+        if n == 0 {
+            break;
+        }
+        verifier.update(&buf[..n]).map_err(|_| PackageErr::IoErr)?;
+    }
+
+    match verifier.verify(signature) {
+        Ok(true) => Ok(()),
+        // Malformed signatures are reported as errors by some key types
+        Ok(false) | Err(_) => Err(PackageErr::VerifyNotEqualErr),
+    }
+}
+
+/// Bytes to append to an image to carry `signature`.
+pub fn trailer(signature: &[u8]) -> Vec<u8> {
+    let mut trailer = signature.to_vec();
+    trailer.extend_from_slice(&(signature.len() as u32).to_be_bytes());
+    trailer.extend_from_slice(TRAILER_MAGIC);
+    trailer
+}
+
+/// Image length and signature when `file` (of `file_len` bytes) ends with a
+/// signature trailer.
+pub fn read_trailer<F: Read + Seek>(file: &mut F, file_len: u64) -> Result<Option<(u64, Vec<u8>)>, PackageErr> {
+    if file_len < TRAILER_LEN {
+        return Ok(None);
+    }
+    let mut tail = [0u8; TRAILER_LEN as usize];
+    file.seek(SeekFrom::Start(file_len - TRAILER_LEN)).map_err(|_| PackageErr::IoErr)?;
+    file.read_exact(&mut tail).map_err(|_| PackageErr::IoErr)?;
+    if &tail[4..] != TRAILER_MAGIC {
+        return Ok(None);
+    }
+
+    let sig_len = u32::from_be_bytes([tail[0], tail[1], tail[2], tail[3]]);
+    if sig_len == 0 || sig_len > MAX_SIGNATURE_LEN || (sig_len as u64) > file_len - TRAILER_LEN {
+        return Err(PackageErr::FormatErr);
+    }
+    let image_len = file_len - TRAILER_LEN - sig_len as u64;
+    let mut signature = vec![0u8; sig_len as usize];
+    file.seek(SeekFrom::Start(image_len)).map_err(|_| PackageErr::IoErr)?;
+    file.read_exact(&mut signature).map_err(|_| PackageErr::IoErr)?;
+    Ok(Some((image_len, signature)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+
+    fn key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = key();
+        let image = vec![7u8; 100_000];
+        let signature = sign(&key, &image[..]).unwrap();
+        assert_eq!(verify(&key, &image[..], &signature), Ok(()));
+
+        let public = public_key_from_pem(&key.public_key_to_pem().unwrap()).unwrap();
+        assert_eq!(verify(&public, &image[..], &signature), Ok(()));
+        assert_eq!(verify(&public, &image[1..], &signature), Err(PackageErr::VerifyNotEqualErr));
+        assert_eq!(verify(&public, &image[..], b"garbage"), Err(PackageErr::VerifyNotEqualErr));
+        assert_eq!(verify(&self::key(), &image[..], &signature), Err(PackageErr::VerifyNotEqualErr));
+    }
+
+    #[test]
+    fn test_trailer_roundtrip() {
+        let image = b"firmware image".to_vec();
+        let mut data = image.clone();
+        data.extend_from_slice(&trailer(b"signature"));
+        let len = data.len() as u64;
+        assert_eq!(read_trailer(&mut Cursor::new(&data), len), Ok(Some((image.len() as u64, b"signature".to_vec()))));
+
+        assert_eq!(read_trailer(&mut Cursor::new(&image), image.len() as u64), Ok(None));
+        assert_eq!(read_trailer(&mut Cursor::new(b"tiny"), 4), Ok(None));
+
+        let len_at = data.len() - TRAILER_LEN as usize;
+        data[len_at..len_at + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(read_trailer(&mut Cursor::new(&data), len), Err(PackageErr::FormatErr));
+    }
+}
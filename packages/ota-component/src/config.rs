@@ -0,0 +1,261 @@
+use std::path::{Path, PathBuf};
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::error::{OtaErr, OtaError, Phase};
+use crate::health::HealthConfig;
+use crate::identity::IdentityConfig;
+use crate::install::InstallConfig;
+use crate::quiesce::QuiesceConfig;
+use crate::retry::RetryPolicy;
+use crate::schedule::MaintenanceSchedule;
+use crate::transport::download::BandwidthConfig;
+use crate::transport::mqtt::{MqttConfig, StatusConfig};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub maintenance: MaintenanceSchedule,
+    pub retry: RetryPolicy,
+    /// Where the progress of an update is kept across reboots
+    pub state_file: PathBuf,
+    /// Where the firmware image is downloaded to
+    pub download_path: PathBuf,
+    /// Bytes to leave free next to `download_path` once the image is in
+    pub free_space_margin: u64,
+    /// Memory that has to be available to start an install (KiB)
+    pub min_free_memory_kb: u64,
+    /// Version name of the running firmware
+    pub version_file: PathBuf,
+    /// Anti-rollback security counter, only ever raised
+    pub security_counter_file: PathBuf,
+    pub install: InstallConfig,
+    pub health: HealthConfig,
+    pub quiesce: QuiesceConfig,
+    /// Where the controller asks for updates
+    pub check_url: String,
+    /// Where the outcome of installed updates is posted
+    pub report_url: String,
+    /// Per-device credentials for the update server, see `provision`
+    pub credentials_file: PathBuf,
+    /// Identifies the controller to the server instead of the MAC of its
+    /// network interface, `aa:bb:cc:dd:ee:ff`
+    pub mac: Option<String>,
+    pub identity: IdentityConfig,
+    /// Vendor key images and manifests are signed with (PEM)
+    pub public_key: PathBuf,
+    pub status: StatusConfig,
+    pub mqtt: MqttConfig,
+    pub bandwidth: BandwidthConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            maintenance: MaintenanceSchedule::default(),
+            retry: RetryPolicy::default(),
+            state_file: PathBuf::from("ota_state.json"),
+            download_path: PathBuf::from("update_ota.bin"),
+            free_space_margin: 4 * 1024 * 1024,
+            min_free_memory_kb: 55_000,
+            version_file: PathBuf::from("ota_version.txt"),
+            security_counter_file: PathBuf::from("ota_security_counter.json"),
+            install: InstallConfig::default(),
+            health: HealthConfig::default(),
+            quiesce: QuiesceConfig::default(),
+            check_url: "https://api.smarthome.lumi.com.vn/ota/check-update-ota".to_string(),
+            report_url: "https://api.smarthome.lumi.com.vn/ota/report-update-ota".to_string(),
+            credentials_file: PathBuf::from("ota_credentials.json"),
+            mac: None,
+            identity: IdentityConfig::default(),
+            public_key: PathBuf::from("public_key.pem"),
+            status: StatusConfig::default(),
+            mqtt: MqttConfig::default(),
+            bandwidth: BandwidthConfig::default(),
+        }
+    }
+}
+
+/// Settings given on the command line or in the environment, taking
+/// precedence over the config file.
+#[derive(Debug, Default, Clone, clap::Args)]
+pub struct Overrides {
+    /// Update check URL
+    #[arg(long, env = "OTA_CHECK_URL")]
+    pub check_url: Option<String>,
+    /// Update report URL
+    #[arg(long, env = "OTA_REPORT_URL")]
+    pub report_url: Option<String>,
+    /// Per-device credentials for the update server
+    #[arg(long, env = "OTA_CREDENTIALS_FILE")]
+    pub credentials_file: Option<PathBuf>,
+    /// MAC address the controller identifies with
+    #[arg(long, env = "OTA_MAC")]
+    pub mac: Option<String>,
+    /// MQTT broker host
+    #[arg(long, env = "OTA_MQTT_HOST")]
+    pub mqtt_host: Option<String>,
+    /// MQTT broker port
+    #[arg(long, env = "OTA_MQTT_PORT")]
+    pub mqtt_port: Option<u16>,
+    /// Vendor public key (PEM)
+    #[arg(long, env = "OTA_PUBLIC_KEY")]
+    pub public_key: Option<PathBuf>,
+    /// Firmware image download target
+    #[arg(long, env = "OTA_DOWNLOAD_PATH")]
+    pub download_path: Option<PathBuf>,
+    /// Version name of the running firmware
+    #[arg(long, env = "OTA_VERSION_FILE")]
+    pub version_file: Option<PathBuf>,
+    /// Progress of a running update
+    #[arg(long, env = "OTA_STATE_FILE")]
+    pub state_file: Option<PathBuf>,
+}
+
+impl Config {
+    /// Loads the TOML config at `path`, falling back to the defaults when the
+    /// file does not exist, and applies `overrides` on top.
+    pub fn load(path: &Path, overrides: Overrides) -> Result<Self, OtaError> {
+        let mut config = Self::read(path).map_err(|e| e.in_phase(Phase::Startup))?;
+        config.apply(overrides);
+        config.validate().map_err(|e| match e.path {
+            // A file the config points at
+            Some(_) => e.in_phase(Phase::Startup),
+            None => e.in_phase(Phase::Startup).with_path(path),
+        })?;
+        Ok(config)
+    }
+
+    fn read(path: &Path) -> Result<Self, OtaError> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::warn!("Config file {} not found, using defaults", path.display());
+                return Ok(Config::default());
+            }
+            Err(e) => return Err(OtaError::io(OtaErr::ConfigErr, path, e)),
+        };
+        toml::from_str(&content).map_err(|e| OtaError::new(OtaErr::ConfigErr).with_path(path).with_source(e))
+    }
+
+    fn apply(&mut self, overrides: Overrides) {
+        let Overrides {
+            check_url, report_url, credentials_file, mac, mqtt_host, mqtt_port, public_key, download_path, version_file, state_file,
+        } = overrides;
+        fn set<T>(value: &mut T, from: Option<T>) {
+            if let Some(from) = from {
+                *value = from;
+            }
+        }
+        set(&mut self.check_url, check_url);
+        set(&mut self.report_url, report_url);
+        set(&mut self.credentials_file, credentials_file);
+        if mac.is_some() {
+            self.mac = mac;
+        }
+        set(&mut self.mqtt.host, mqtt_host);
+        set(&mut self.mqtt.port, mqtt_port);
+        set(&mut self.public_key, public_key);
+        set(&mut self.download_path, download_path);
+        set(&mut self.version_file, version_file);
+        set(&mut self.state_file, state_file);
+    }
+
+    /// Checks the settings hang together, logging what is wrong.
+    pub fn validate(&self) -> Result<(), OtaError> {
+        fn invalid(what: std::fmt::Arguments) -> Result<(), OtaError> {
+            log::error!("Invalid config: {}", what);
+            Err(OtaErr::ConfigErr.into())
+        }
+        for (name, url) in [("check_url", &self.check_url), ("report_url", &self.report_url)] {
+            match Url::parse(url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+                Ok(_) => return invalid(format_args!("{} {:?} is not an http(s) URL", name, url)),
+                Err(e) => return invalid(format_args!("{} {:?}: {}", name, url, e)),
+            }
+        }
+        if let Some(mac) = self.mac.as_ref().filter(|mac| !is_mac(mac)) {
+            return invalid(format_args!("mac {:?} is not like aa:bb:cc:dd:ee:ff", mac));
+        }
+        if self.mqtt.host.is_empty() || self.mqtt.port == 0 {
+            return invalid(format_args!("mqtt broker {}:{}", self.mqtt.host, self.mqtt.port));
+        }
+        for (name, path) in [
+            ("download_path", &self.download_path),
+            ("version_file", &self.version_file),
+            ("state_file", &self.state_file),
+            ("public_key", &self.public_key),
+            ("credentials_file", &self.credentials_file),
+        ] {
+            if path.file_name().is_none() {
+                return invalid(format_args!("{} {:?} is not a file path", name, path));
+            }
+        }
+        self.maintenance.validate()?;
+        self.retry.validate()?;
+        self.mqtt.reconnect.validate()?;
+        self.mqtt.options(&self.mqtt.client_id)?;
+        Ok(())
+    }
+}
+
+fn is_mac(mac: &str) -> bool {
+    let parts: Vec<&str> = mac.split(':').collect();
+    parts.len() == 6 && parts.iter().all(|part| part.len() == 2 && part.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_example_config() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("ota.example.toml");
+        let config = Config::load(&path, Overrides::default()).unwrap();
+        assert_eq!(config.state_file, PathBuf::from("ota_state.json"));
+        assert_eq!(config.security_counter_file, PathBuf::from("ota_security_counter.json"));
+        assert_eq!(config.retry, RetryPolicy::default());
+        assert_eq!(config.install, InstallConfig::default());
+        assert_eq!((config.mac, config.identity), (None, IdentityConfig::default()));
+        assert_eq!(config.health.probes.len(), 2);
+        assert_eq!(config.quiesce.steps.len(), 2);
+        assert_eq!(config.free_space_margin, Config::default().free_space_margin);
+        assert_eq!(config.min_free_memory_kb, 55_000);
+        assert_eq!(config.status, StatusConfig::default());
+        assert_eq!((config.mqtt.host.as_str(), config.mqtt.port), ("localhost", 1883));
+        assert_eq!(config.mqtt.reconnect.base_delay(10), MqttConfig::default().reconnect.base_delay(10));
+        assert_eq!(config.bandwidth.rate_limit(false), Some(262_144));
+        assert_eq!(config.bandwidth.rate_limit(true), None);
+
+        assert!(Config::load(&path.with_file_name("missing.toml"), Overrides::default()).is_ok());
+    }
+
+    #[test]
+    fn test_overrides() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("ota.example.toml");
+        let overrides = Overrides {
+            check_url: Some("http://updates.local/check".to_string()),
+            mac: Some("AA:bb:cc:00:11:22".to_string()),
+            mqtt_port: Some(8883),
+            download_path: Some(PathBuf::from("/data/update.bin")),
+            ..Overrides::default()
+        };
+        let config = Config::load(&path, overrides).unwrap();
+        assert_eq!(config.check_url, "http://updates.local/check");
+        assert_eq!(config.mac.as_deref(), Some("AA:bb:cc:00:11:22"));
+        assert_eq!((config.mqtt.host.as_str(), config.mqtt.port), ("localhost", 8883));
+        assert_eq!(config.download_path, PathBuf::from("/data/update.bin"));
+        assert_eq!(config.report_url, Config::default().report_url);
+
+        for overrides in [
+            Overrides { check_url: Some("updates.local/check".to_string()), ..Overrides::default() },
+            Overrides { report_url: Some("ftp://updates.local".to_string()), ..Overrides::default() },
+            Overrides { credentials_file: Some(PathBuf::from("")), ..Overrides::default() },
+            Overrides { mac: Some("14:c9:cf:17:af".to_string()), ..Overrides::default() },
+            Overrides { mqtt_port: Some(0), ..Overrides::default() },
+            Overrides { download_path: Some(PathBuf::from("/")), ..Overrides::default() },
+        ] {
+            assert_eq!(Config::load(&path, overrides.clone()).err().map(|e| e.kind), Some(OtaErr::ConfigErr), "{:?}", overrides);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{OtaErr, OtaError};
+
+/// Credentials of this controller for the update server, provisioned once per
+/// device into a file only ota-component may read.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Credentials {
+    /// Sent as `x-lumi-api-key`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_token: Option<String>,
+    /// PEM certificate chain of the device, for mutual TLS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<String>,
+    /// PKCS#8 PEM private key of `client_cert`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<String>,
+}
+
+impl Credentials {
+    /// Reads the credentials at `path`, refusing a file others can read.
+    pub fn load(path: &Path) -> Result<Self, OtaError> {
+        let metadata = fs::metadata(path).map_err(|e| OtaError::io(OtaErr::CredentialsErr, path, e))?;
+        let mode = metadata.permissions().mode();
+        if mode & 0o007 != 0 {
+            log::error!("Credentials {} are accessible to other users (mode {:o}), chmod 600 them", path.display(), mode & 0o777);
+            return Err(OtaError::new(OtaErr::CredentialsErr).with_path(path));
+        }
+        let content = fs::read(path).map_err(|e| OtaError::io(OtaErr::CredentialsErr, path, e))?;
+        let credentials: Credentials = serde_json::from_slice(&content)
+            .map_err(|e| OtaError::new(OtaErr::CredentialsErr).with_path(path).with_source(e))?;
+        credentials.validate().map_err(|e| e.with_path(path))?;
+        Ok(credentials)
+    }
+
+    /// Writes the credentials to `path`, readable by the owner only.
+    pub fn provision(&self, path: &Path) -> Result<(), OtaError> {
+        self.validate()?;
+        let data = serde_json::to_vec_pretty(self).map_err(|e| OtaError::new(OtaErr::CredentialsErr).with_source(e))?;
+        let tmp = path.with_extension("tmp");
+        let _ = fs::remove_file(&tmp);
+        let written = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)
+            .and_then(|mut file| {
+                file.write_all(&data)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, path));
+        written.map_err(|e| {
+            let _ = fs::remove_file(&tmp);
+            OtaError::io(OtaErr::CredentialsErr, path, e)
+        })
+    }
+
+    fn validate(&self) -> Result<(), OtaError> {
+        if self.client_cert.is_some() != self.client_key.is_some() {
+            log::error!("Credentials need both client_cert and client_key");
+            return Err(OtaErr::CredentialsErr.into());
+        }
+        if self.api_token.is_none() && self.client_cert.is_none() {
+            log::error!("Credentials hold neither an api_token nor a client certificate");
+            return Err(OtaErr::CredentialsErr.into());
+        }
+        self.client().map(|_| ())
+    }
+
+    /// HTTP client authenticating with the credentials.
+    pub fn client(&self) -> Result<reqwest::Client, OtaError> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(token) = &self.api_token {
+            let mut value = HeaderValue::from_str(token).map_err(|e| OtaError::new(OtaErr::CredentialsErr).with_source(e))?;
+            value.set_sensitive(true);
+            builder = builder.default_headers(HeaderMap::from_iter([(HeaderName::from_static("x-lumi-api-key"), value)]));
+        }
+        if let (Some(cert), Some(key)) = (&self.client_cert, &self.client_key) {
+            let identity = reqwest::Identity::from_pkcs8_pem(cert.as_bytes(), key.as_bytes())
+                .map_err(|e| OtaError::http(OtaErr::CredentialsErr, e))?;
+            builder = builder.identity(identity);
+        }
+        builder.build().map_err(|e| OtaError::http(OtaErr::CredentialsErr, e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ota-credentials-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_provision_and_load() {
+        let path = temp_dir("token").join("credentials.json");
+        let credentials = Credentials { api_token: Some("device-token".to_string()), ..Credentials::default() };
+        credentials.provision(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(Credentials::load(&path), Ok(credentials));
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(Credentials::load(&path).unwrap_err(), OtaError::new(OtaErr::CredentialsErr).with_path(&path));
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        assert!(Credentials::load(&path).is_ok());
+        let e = Credentials::load(&path.with_file_name("missing.json")).unwrap_err();
+        assert_eq!(e.path, Some(path.with_file_name("missing.json")));
+        assert!(std::error::Error::source(&e).is_some());
+    }
+
+    #[test]
+    fn test_client_certificate() {
+        use openssl::ec::{EcGroup, EcKey};
+        use openssl::nid::Nid;
+        use openssl::pkey::PKey;
+        use openssl::x509::{X509, X509NameBuilder};
+
+        let key = PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "14:c9:cf:17:af:8e").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&openssl::asn1::Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&openssl::asn1::Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(&key, openssl::hash::MessageDigest::sha256()).unwrap();
+        let cert = String::from_utf8(cert.build().to_pem().unwrap()).unwrap();
+        let key = String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+        let path = temp_dir("mtls").join("credentials.json");
+        let credentials = Credentials { client_cert: Some(cert.clone()), client_key: Some(key), ..Credentials::default() };
+        credentials.provision(&path).unwrap();
+        assert_eq!(Credentials::load(&path), Ok(credentials));
+
+        let half = Credentials { client_cert: Some(cert), ..Credentials::default() };
+        assert_eq!(half.provision(&path).unwrap_err(), OtaErr::CredentialsErr);
+        assert_eq!(Credentials::default().provision(&path).unwrap_err(), OtaErr::CredentialsErr);
+        let broken = Credentials { client_cert: Some("junk".to_string()), client_key: Some("junk".to_string()), ..Credentials::default() };
+        assert_eq!(broken.provision(&path).unwrap_err(), OtaErr::CredentialsErr);
+    }
+}
@@ -1,3 +1,7 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
 
 #[derive(Debug, PartialEq,Clone)]
 pub enum OtaErr {
@@ -15,4 +19,177 @@ pub enum OtaErr {
     HttpErr,
     MqttErr,
     TimoutErr,
-}
\ No newline at end of file
+    ConfigErr,
+    StateErr,
+    InstallErr,
+    ChecksumErr,
+    UnsignedErr,
+    ManifestErr,
+    IncompatibleErr,
+    ExpiredErr,
+    RollbackErr,
+    ReportErr,
+    /// Not enough disk space for the image
+    NoSpaceErr,
+    /// A service could not be paused or stopped for the install
+    QuiesceErr,
+    /// The controller's MAC, model or serial could not be read
+    IdentityErr,
+    /// Device credentials missing, unreadable or readable by others
+    CredentialsErr,
+    /// The server answered with a body that is not the expected JSON
+    JsonErr,
+}
+
+impl fmt::Display for OtaErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl std::error::Error for OtaErr {}
+
+/// What ota-component was doing when an error happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Startup,
+    Check,
+    Download,
+    Verify,
+    Quiesce,
+    Install,
+    Commit,
+    Rollback,
+    Report,
+}
+
+/// An error as `OtaLogic` sees it: `kind` decides how it reacts, the rest
+/// tells the log what went wrong where.
+#[derive(Debug, Clone)]
+pub struct OtaError {
+    pub kind: OtaErr,
+    pub phase: Option<Phase>,
+    /// File being read or written
+    pub path: Option<PathBuf>,
+    /// Status of a refused HTTP request
+    pub status: Option<u16>,
+    source: Option<Arc<dyn std::error::Error + Send + Sync>>,
+}
+
+impl OtaError {
+    pub fn new(kind: OtaErr) -> Self {
+        OtaError { kind, phase: None, path: None, status: None, source: None }
+    }
+
+    /// `kind` caused by the IO error `e` on `path`.
+    pub fn io(kind: OtaErr, path: &Path, e: std::io::Error) -> Self {
+        OtaError::new(kind).with_path(path).with_source(e)
+    }
+
+    /// `kind` caused by the failed request `e`, with its status if any.
+    pub fn http(kind: OtaErr, e: reqwest::Error) -> Self {
+        let status = e.status().map(|status| status.as_u16());
+        OtaError { status, ..OtaError::new(kind).with_source(e) }
+    }
+
+    /// Sets the phase, unless a more specific one is already set.
+    pub fn in_phase(self, phase: Phase) -> Self {
+        OtaError { phase: self.phase.or(Some(phase)), ..self }
+    }
+
+    pub fn with_path(self, path: &Path) -> Self {
+        OtaError { path: Some(path.to_path_buf()), ..self }
+    }
+
+    pub fn with_status(self, status: u16) -> Self {
+        OtaError { status: Some(status), ..self }
+    }
+
+    pub fn with_source(self, source: impl std::error::Error + Send + Sync + 'static) -> Self {
+        OtaError { source: Some(Arc::new(source)), ..self }
+    }
+}
+
+impl From<OtaErr> for OtaError {
+    fn from(kind: OtaErr) -> Self {
+        OtaError::new(kind)
+    }
+}
+
+/// Errors compare by what they are and where they happened, not by source.
+impl PartialEq for OtaError {
+    fn eq(&self, other: &Self) -> bool {
+        (&self.kind, self.phase, &self.path, self.status) == (&other.kind, other.phase, &other.path, other.status)
+    }
+}
+
+impl PartialEq<OtaErr> for OtaError {
+    fn eq(&self, kind: &OtaErr) -> bool {
+        self.kind == *kind
+    }
+}
+
+impl fmt::Display for OtaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.kind)?;
+        if let Some(phase) = self.phase {
+            write!(f, " during {:?}", phase)?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, " on {}", path.display())?;
+        }
+        if let Some(status) = self.status {
+            write!(f, " (HTTP {})", status)?;
+        }
+        if let Some(source) = &self.source {
+            write!(f, ": {}", source)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for OtaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.as_deref().map(|source| source as &(dyn std::error::Error + 'static))
+    }
+}
+
+impl From<ota_package::PackageErr> for OtaError {
+    fn from(e: ota_package::PackageErr) -> Self {
+        OtaErr::from(e).into()
+    }
+}
+
+impl From<ota_package::PackageErr> for OtaErr {
+    fn from(e: ota_package::PackageErr) -> Self {
+        use ota_package::PackageErr;
+        match e {
+            PackageErr::FormatErr | PackageErr::VerifyNotEqualErr => OtaErr::VerifyNotEqualErr,
+            PackageErr::UnsignedErr => OtaErr::UnsignedErr,
+            PackageErr::ChecksumErr => OtaErr::ChecksumErr,
+            PackageErr::IoErr | PackageErr::KeyErr | PackageErr::SignErr => OtaErr::VerifyErr,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn test_context() {
+        let io = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "denied");
+        let e = OtaError::io(OtaErr::DownloadErr, Path::new("/data/update.bin"), io)
+            .in_phase(Phase::Download)
+            .in_phase(Phase::Check);
+        assert_eq!(e.phase, Some(Phase::Download));
+        assert_eq!(e.to_string(), "DownloadErr during Download on /data/update.bin: denied");
+        assert_eq!(e.source().map(|source| source.to_string()).as_deref(), Some("denied"));
+        assert_eq!(e, OtaErr::DownloadErr);
+
+        let e = OtaError::from(OtaErr::HttpErr).with_status(503).in_phase(Phase::Check);
+        assert_eq!(e.to_string(), "HttpErr during Check (HTTP 503)");
+        assert!(e.source().is_none());
+    }
+}
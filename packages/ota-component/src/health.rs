@@ -0,0 +1,256 @@
+use std::time::Duration;
+use rumqttc::{AsyncClient, Event, Packet};
+use serde::{Deserialize, Serialize};
+use sysinfo::System;
+
+use crate::transport::mqtt::MqttConfig;
+
+/// Check the new firmware has to pass before it is committed.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Probe {
+    /// A process with this name is running
+    Process { name: String },
+    /// The MQTT broker accepts a connection, with the TLS settings and
+    /// credentials of `[mqtt]`
+    Mqtt { host: String, port: u16 },
+    /// The URL answers with `status`, or any success status
+    Http {
+        url: String,
+        #[serde(default)]
+        status: Option<u16>,
+    },
+    /// The command exits with `exit_code`
+    Script {
+        command: Vec<String>,
+        #[serde(default)]
+        exit_code: i32,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    pub probes: Vec<Probe>,
+    /// Seconds between rounds of probes while the new version is on trial,
+    /// and before the first one
+    pub interval_s: u64,
+    /// Seconds each probe gets to pass
+    pub timeout_s: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            probes: Vec::new(),
+            interval_s: 10,
+            timeout_s: 5,
+        }
+    }
+}
+
+/// Outcome of one probe, as reported to the server.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProbeResult {
+    pub probe: String,
+    pub passed: bool,
+    /// Why it failed
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub detail: String,
+}
+
+impl Probe {
+    pub fn describe(&self) -> String {
+        match self {
+            Probe::Process { name } => format!("process {}", name),
+            Probe::Mqtt { host, port } => format!("mqtt {}:{}", host, port),
+            Probe::Http { url, .. } => format!("http {}", url),
+            Probe::Script { command, .. } => format!("script {}", command.join(" ")),
+        }
+    }
+
+    pub async fn run(&self, timeout: Duration, mqtt: &MqttConfig) -> ProbeResult {
+        let result = match tokio::time::timeout(timeout, self.check(mqtt)).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("no answer within {:?}", timeout)),
+        };
+        if let Err(detail) = &result {
+            log::warn!("Health probe {} failed: {}", self.describe(), detail);
+        }
+        ProbeResult {
+            probe: self.describe(),
+            passed: result.is_ok(),
+            detail: result.err().unwrap_or_default(),
+        }
+    }
+
+    async fn check(&self, mqtt: &MqttConfig) -> Result<(), String> {
+        match self {
+            Probe::Process { name } => {
+                let mut sys = System::new();
+                sys.refresh_processes();
+                if sys.processes_by_exact_name(name).next().is_some() {
+                    Ok(())
+                } else {
+                    Err("not running".to_string())
+                }
+            }
+            Probe::Mqtt { host, port } => {
+                let broker = MqttConfig { host: host.clone(), port: *port, ..mqtt.clone() };
+                let options = broker.options(&format!("{}-health", mqtt.client_id)).map_err(|e| e.to_string())?;
+                let (client, mut eventloop) = AsyncClient::new(options, 1);
+                loop {
+                    match eventloop.poll().await {
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            let _ = client.try_disconnect();
+                            return Ok(());
+                        }
+                        Ok(_) => {}
+                        Err(e) => return Err(e.to_string()),
+                    }
+                }
+            }
+            Probe::Http { url, status } => {
+                let response = reqwest::get(url).await.map_err(|e| e.to_string())?;
+                let passed = match status {
+                    Some(status) => response.status().as_u16() == *status,
+                    None => response.status().is_success(),
+                };
+                if passed {
+                    Ok(())
+                } else {
+                    Err(format!("status {}", response.status()))
+                }
+            }
+            Probe::Script { command, exit_code } => {
+                let (program, args) = command.split_first().ok_or("empty command")?;
+                let status = tokio::process::Command::new(program)
+                    .args(args)
+                    .kill_on_drop(true)
+                    .status()
+                    .await
+                    .map_err(|e| e.to_string())?;
+                match status.code() {
+                    Some(code) if code == *exit_code => Ok(()),
+                    Some(code) => Err(format!("exit code {}", code)),
+                    None => Err("killed by a signal".to_string()),
+                }
+            }
+        }
+    }
+}
+
+/// Runs every probe, one after the other. `mqtt` has the client id of
+/// the controller's session.
+pub async fn check(config: &HealthConfig, mqtt: &MqttConfig) -> Vec<ProbeResult> {
+    let timeout = Duration::from_secs(config.timeout_s);
+    let mut results = Vec::with_capacity(config.probes.len());
+    for probe in &config.probes {
+        results.push(probe.run(timeout, mqtt).await);
+    }
+    results
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::test_broker::{test_pki, TestBroker};
+    use crate::transport::test_server::TestServer;
+    use sysinfo::Pid;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn script(line: &str) -> Probe {
+        Probe::Script { command: vec!["sh".to_string(), "-c".to_string(), line.to_string()], exit_code: 0 }
+    }
+
+    #[tokio::test]
+    async fn test_script_probe() {
+        assert!(script("exit 0").run(TIMEOUT, &MqttConfig::default()).await.passed);
+
+        let failed = script("exit 3").run(TIMEOUT, &MqttConfig::default()).await;
+        assert_eq!((failed.passed, failed.detail.as_str()), (false, "exit code 3"));
+        assert!(Probe::Script { command: vec!["sh".to_string(), "-c".to_string(), "exit 3".to_string()], exit_code: 3 }.run(TIMEOUT, &MqttConfig::default()).await.passed);
+
+        let slow = script("sleep 5").run(Duration::from_millis(100), &MqttConfig::default()).await;
+        assert!(!slow.passed);
+        assert!(!Probe::Script { command: Vec::new(), exit_code: 0 }.run(TIMEOUT, &MqttConfig::default()).await.passed);
+    }
+
+    #[tokio::test]
+    async fn test_process_probe() {
+        let mut sys = System::new();
+        sys.refresh_processes();
+        let name = sys.process(Pid::from_u32(std::process::id())).unwrap().name().to_string();
+        assert!(Probe::Process { name }.run(TIMEOUT, &MqttConfig::default()).await.passed);
+        assert!(!Probe::Process { name: "no-such-process-here".to_string() }.run(TIMEOUT, &MqttConfig::default()).await.passed);
+    }
+
+    #[tokio::test]
+    async fn test_http_probe() {
+        let server = TestServer::start(Vec::new()).await.with_file("/health", b"ok".to_vec());
+        assert!(Probe::Http { url: server.url_of("/health"), status: None }.run(TIMEOUT, &MqttConfig::default()).await.passed);
+        assert!(!Probe::Http { url: server.url_of("/missing"), status: None }.run(TIMEOUT, &MqttConfig::default()).await.passed);
+        assert!(Probe::Http { url: server.url_of("/missing"), status: Some(404) }.run(TIMEOUT, &MqttConfig::default()).await.passed);
+    }
+
+    #[tokio::test]
+    async fn test_mqtt_probe() {
+        // Accepts any connection, like a broker without authentication
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 256];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream.write_all(&[0x20, 0x02, 0x00, 0x00]).await;
+                let _ = stream.read(&mut buf).await;
+            }
+        });
+        let probe = Probe::Mqtt { host: "127.0.0.1".to_string(), port };
+        assert!(probe.run(TIMEOUT, &MqttConfig::default()).await.passed);
+
+        // Nothing listening any more
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        assert!(!Probe::Mqtt { host: "127.0.0.1".to_string(), port: closed }.run(TIMEOUT, &MqttConfig::default()).await.passed);
+
+        // Broker with TLS and a login, which the probe takes from [mqtt]
+        let dir = std::env::temp_dir().join(format!("ota-health-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (acceptor, tls) = test_pki(&dir);
+        let broker = TestBroker::start_with("127.0.0.1:0".parse().unwrap(), Some(acceptor), Some(("hc", "secret"))).await;
+        let probe = Probe::Mqtt { host: "localhost".to_string(), port: broker.addr().port() };
+        let mqtt = MqttConfig {
+            client_id: "ota-aa".to_string(),
+            username: Some("hc".to_string()),
+            password: Some("secret".to_string()),
+            tls: Some(tls),
+            ..MqttConfig::default()
+        };
+        assert!(!probe.run(TIMEOUT, &MqttConfig::default()).await.passed);
+        assert!(probe.run(TIMEOUT, &mqtt).await.passed);
+        assert_eq!(broker.clients(), vec![("ota-aa-health".to_string(), Some("hc".to_string()))]);
+    }
+
+    #[test]
+    fn test_probes_from_toml() {
+        let config: HealthConfig = toml::from_str(r#"
+            interval_s = 30
+            [[probes]]
+            type = "process"
+            name = "master-service"
+            [[probes]]
+            type = "http"
+            url = "http://localhost:8080/health"
+            [[probes]]
+            type = "script"
+            command = ["/usr/bin/check.sh", "--quick"]
+        "#).unwrap();
+        assert_eq!(config.interval_s, 30);
+        assert_eq!(config.timeout_s, 5);
+        assert_eq!(config.probes[1], Probe::Http { url: "http://localhost:8080/health".to_string(), status: None });
+        assert_eq!(config.probes[2].describe(), "script /usr/bin/check.sh --quick");
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+
+use crate::error::OtaErr;
+use crate::logic::HcType;
+
+/// Where the controller's identity is read from.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct IdentityConfig {
+    /// Interface whose MAC identifies the controller, by default the first
+    /// physical one
+    pub interface: Option<String>,
+    /// Where the network interfaces are listed
+    pub net_dir: PathBuf,
+    /// Holds the model name, e.g. `/proc/device-tree/model`. Without it the
+    /// controller is taken to be a `Hc01`
+    pub model_file: Option<PathBuf>,
+    /// Controller type of each model name, names like `Hc01` map to
+    /// themselves
+    pub models: HashMap<String, HcType>,
+    /// Holds the serial number
+    pub serial_file: Option<PathBuf>,
+}
+
+impl Default for IdentityConfig {
+    fn default() -> Self {
+        IdentityConfig {
+            interface: None,
+            net_dir: PathBuf::from("/sys/class/net"),
+            model_file: None,
+            models: HashMap::new(),
+            serial_file: None,
+        }
+    }
+}
+
+/// Who this controller is, as told to the update server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceIdentity {
+    pub mac: String,
+    pub hc_type: HcType,
+    pub model: String,
+    pub serial: String,
+}
+
+/// Reads the identity of this controller. `mac` replaces the one of the
+/// network interface when given.
+pub fn discover(config: &IdentityConfig, mac: Option<&str>) -> Result<DeviceIdentity, OtaErr> {
+    let mac = match mac {
+        Some(mac) => mac.to_string(),
+        None => primary_mac(config)?,
+    };
+    let model = match &config.model_file {
+        Some(path) => read_value(path)?,
+        None => String::new(),
+    };
+    let hc_type = if config.model_file.is_none() {
+        HcType::Hc01
+    } else {
+        hc_type_of(&model, &config.models).ok_or_else(|| {
+            log::error!("Unknown controller model {:?}, add it to identity.models", model);
+            OtaErr::IdentityErr
+        })?
+    };
+    let serial = match &config.serial_file {
+        Some(path) => read_value(path)?,
+        None => String::new(),
+    };
+    log::info!("Controller {} {:?} model {:?} serial {:?}", mac, hc_type, model, serial);
+    Ok(DeviceIdentity { mac, hc_type, model, serial })
+}
+
+fn hc_type_of(model: &str, models: &HashMap<String, HcType>) -> Option<HcType> {
+    if let Some(hc_type) = models.get(model) {
+        return Some(hc_type.clone());
+    }
+    match model.to_ascii_lowercase().as_str() {
+        "hc01" => Some(HcType::Hc01),
+        "hc02" => Some(HcType::Hc02),
+        _ => None,
+    }
+}
+
+/// MAC of the configured interface, or else of the first physical interface
+/// by name, or else of the first one that is not loopback.
+fn primary_mac(config: &IdentityConfig) -> Result<String, OtaErr> {
+    if let Some(interface) = &config.interface {
+        return match mac_of(&config.net_dir.join(interface)) {
+            Some(mac) => Ok(mac),
+            None => {
+                log::error!("No MAC address for interface {}", interface);
+                Err(OtaErr::IdentityErr)
+            }
+        };
+    }
+    let mut interfaces: Vec<PathBuf> = fs::read_dir(&config.net_dir)
+        .map_err(|e| {
+            log::error!("Failed to list {}: {}", config.net_dir.display(), e);
+            OtaErr::IdentityErr
+        })?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    interfaces.sort();
+    let physical = interfaces.iter().filter(|path| path.join("device").exists());
+    physical.chain(interfaces.iter())
+        .find_map(|path| mac_of(path))
+        .ok_or_else(|| {
+            log::error!("No network interface with a MAC address in {}", config.net_dir.display());
+            OtaErr::IdentityErr
+        })
+}
+
+/// MAC of the interface at `path`, unless it is loopback or has none.
+fn mac_of(path: &Path) -> Option<String> {
+    // ARPHRD_LOOPBACK
+    if fs::read_to_string(path.join("type")).is_ok_and(|kind| kind.trim() == "772") {
+        return None;
+    }
+    let mac = fs::read_to_string(path.join("address")).ok()?.trim().to_ascii_lowercase();
+    let valid = mac.len() == 17 && mac != "00:00:00:00:00:00";
+    valid.then_some(mac)
+}
+
+/// Contents of `path` without surrounding whitespace, or the NUL device
+/// tree strings end with.
+fn read_value(path: &Path) -> Result<String, OtaErr> {
+    let value = fs::read_to_string(path).map_err(|e| {
+        log::error!("Failed to read {}: {}", path.display(), e);
+        OtaErr::IdentityErr
+    })?;
+    Ok(value.trim_matches(|c: char| c.is_whitespace() || c == '\0').to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ota-identity-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn interface(net: &Path, name: &str, address: &str, kind: u32, physical: bool) {
+        let dir = net.join(name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("address"), format!("{}\n", address)).unwrap();
+        fs::write(dir.join("type"), format!("{}\n", kind)).unwrap();
+        if physical {
+            fs::create_dir_all(dir.join("device")).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_primary_mac() {
+        let net = temp_dir("net");
+        interface(&net, "lo", "00:00:00:00:00:00", 772, false);
+        interface(&net, "br0", "02:42:AC:11:00:02", 1, false);
+        interface(&net, "wlan0", "14:c9:cf:17:af:8f", 1, true);
+        interface(&net, "eth0", "14:C9:CF:17:AF:8E", 1, true);
+        let config = IdentityConfig { net_dir: net.clone(), ..IdentityConfig::default() };
+        assert_eq!(primary_mac(&config), Ok("14:c9:cf:17:af:8e".to_string()));
+
+        let config = IdentityConfig { interface: Some("wlan0".to_string()), ..config };
+        assert_eq!(primary_mac(&config), Ok("14:c9:cf:17:af:8f".to_string()));
+        let config = IdentityConfig { interface: Some("lo".to_string()), ..config };
+        assert_eq!(primary_mac(&config), Err(OtaErr::IdentityErr));
+
+        // Only virtual interfaces
+        fs::remove_dir_all(net.join("eth0")).unwrap();
+        fs::remove_dir_all(net.join("wlan0")).unwrap();
+        let config = IdentityConfig { net_dir: net, ..IdentityConfig::default() };
+        assert_eq!(primary_mac(&config), Ok("02:42:ac:11:00:02".to_string()));
+    }
+
+    #[test]
+    fn test_discover() {
+        let dir = temp_dir("discover");
+        let net = dir.join("net");
+        interface(&net, "eth0", "14:c9:cf:17:af:8e", 1, true);
+        fs::write(dir.join("model"), "Lumi HC 2\0").unwrap();
+        fs::write(dir.join("serial"), "HC2-000123\n").unwrap();
+        let config = IdentityConfig {
+            net_dir: net,
+            model_file: Some(dir.join("model")),
+            models: HashMap::from([("Lumi HC 2".to_string(), HcType::Hc02)]),
+            serial_file: Some(dir.join("serial")),
+            ..IdentityConfig::default()
+        };
+        let identity = discover(&config, None).unwrap();
+        assert_eq!(identity, DeviceIdentity {
+            mac: "14:c9:cf:17:af:8e".to_string(),
+            hc_type: HcType::Hc02,
+            model: "Lumi HC 2".to_string(),
+            serial: "HC2-000123".to_string(),
+        });
+        assert_eq!(discover(&config, Some("aa:bb:cc:dd:ee:ff")).unwrap().mac, "aa:bb:cc:dd:ee:ff");
+
+        fs::write(dir.join("model"), "hc01\n").unwrap();
+        assert_eq!(discover(&config, None).unwrap().hc_type, HcType::Hc01);
+        fs::write(dir.join("model"), "Lumi HC 3").unwrap();
+        assert_eq!(discover(&config, None), Err(OtaErr::IdentityErr));
+        let config = IdentityConfig { serial_file: Some(dir.join("missing")), model_file: None, ..config };
+        assert_eq!(discover(&config, None), Err(OtaErr::IdentityErr));
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use openssl::hash::{Hasher, MessageDigest};
+use serde::{Deserialize, Serialize};
+
+pub mod image;
+pub mod binary;
+pub mod tarball;
+
+use crate::error::{OtaErr, OtaError};
+use crate::logic::HcType;
+use crate::state::write_atomic;
+use image::ImageInstaller;
+use binary::BinaryInstaller;
+use tarball::TarballInstaller;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct InstallConfig {
+    /// Directory holding the boot marker, and the two slot images unless a
+    /// backend puts them elsewhere
+    pub slot_dir: PathBuf,
+    /// Time a new version gets after restarting to confirm it works
+    pub confirm_timeout_s: u64,
+    /// Restarts into an unconfirmed version before falling back
+    pub max_boot_attempts: u32,
+    /// Command restarting the controller into the new slot, e.g.
+    /// `["systemctl", "reboot"]`. Empty exits and leaves it to the supervisor.
+    pub restart_command: Vec<String>,
+    /// How updates are installed on each controller model, slot images in
+    /// `slot_dir` for models not listed
+    pub backends: HashMap<HcType, Backend>,
+}
+
+/// Installer backend of a controller model, see the `Installer`
+/// implementations.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Backend {
+    /// Raw image written to one of two files or block devices
+    Image {
+        #[serde(default)]
+        slots: Option<[PathBuf; 2]>,
+        #[serde(default)]
+        restart_command: Vec<String>,
+    },
+    /// Single executable replaced in place, e.g. with a service restart as
+    /// `restart_command`
+    Binary {
+        path: PathBuf,
+        #[serde(default)]
+        restart_command: Vec<String>,
+    },
+    /// `.tar.gz` extracted into `<root>/<version>`, `<root>/current` linking
+    /// to the active one
+    Tarball {
+        root: PathBuf,
+        #[serde(default)]
+        restart_command: Vec<String>,
+    },
+}
+
+impl Default for InstallConfig {
+    fn default() -> Self {
+        InstallConfig {
+            slot_dir: PathBuf::from("slots"),
+            confirm_timeout_s: 300,
+            max_boot_attempts: 3,
+            restart_command: Vec::new(),
+            backends: HashMap::new(),
+        }
+    }
+}
+
+/// Puts update images of one controller model in place. Every backend
+/// keeps two slots, so the previous version stays around to roll back to.
+pub trait Installer: Send + Sync {
+    /// Writes `image` into `slot`, which is not the one running.
+    fn write(&self, image: &Path, slot: Slot, version: &str) -> Result<(), OtaError>;
+
+    /// Makes `slot`, holding `version`, the one started next.
+    fn activate(&self, slot: Slot, version: &str) -> Result<(), OtaError>;
+
+    /// Command starting the active slot. Empty exits and leaves it to the
+    /// supervisor of ota-component.
+    fn restart_command(&self) -> &[String];
+
+    /// Image installed in `slot`, as delta patches expect it. `None` when the
+    /// backend does not keep it.
+    fn installed_image(&self, _slot: Slot) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Backend for `hc_type` as configured.
+pub fn backend(config: &InstallConfig, hc_type: &HcType) -> Box<dyn Installer> {
+    let restart = |command: &Vec<String>| {
+        if command.is_empty() { config.restart_command.clone() } else { command.clone() }
+    };
+    match config.backends.get(hc_type) {
+        None => Box::new(ImageInstaller::new(ImageInstaller::default_slots(&config.slot_dir), config.restart_command.clone())),
+        Some(Backend::Image { slots, restart_command }) => {
+            let slots = slots.clone().unwrap_or_else(|| ImageInstaller::default_slots(&config.slot_dir));
+            Box::new(ImageInstaller::new(slots, restart(restart_command)))
+        }
+        Some(Backend::Binary { path, restart_command }) => Box::new(BinaryInstaller::new(path.clone(), restart(restart_command))),
+        Some(Backend::Tarball { root, restart_command }) => Box::new(TarballInstaller::new(root.clone(), restart(restart_command))),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Slot {
+    #[default]
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    pub fn index(self) -> usize {
+        match self {
+            Slot::A => 0,
+            Slot::B => 1,
+        }
+    }
+}
+
+/// Numbers of an installed version, as its manifest gives them. Zero for the
+/// version a controller left the factory with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VersionIds {
+    pub version_number: u16,
+    pub version_min_id: u16,
+}
+
+/// What the boot loader reads to decide which slot to start.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BootMarker {
+    pub active: Slot,
+    pub version: String,
+    pub ids: VersionIds,
+    /// Whether `active` proved to work, a trial boot otherwise
+    pub confirmed: bool,
+    pub boot_attempts: u32,
+    /// Slot to go back to while `active` is on trial
+    pub previous: Option<Slot>,
+    pub previous_version: String,
+    pub previous_ids: VersionIds,
+}
+
+impl Default for BootMarker {
+    fn default() -> Self {
+        BootMarker {
+            active: Slot::A,
+            version: String::new(),
+            ids: VersionIds::default(),
+            confirmed: true,
+            boot_attempts: 0,
+            previous: None,
+            previous_version: String::new(),
+            previous_ids: VersionIds::default(),
+        }
+    }
+}
+
+/// Which version came up, as found at startup.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BootStatus {
+    /// Running a confirmed version
+    Normal { version: String },
+    /// Running a new version that still has to confirm it works
+    Trial { version: String, attempts: u32 },
+    /// The new version failed to come up too often, the previous slot is
+    /// active again
+    RolledBack { version: String },
+}
+
+/// A/B install through an `Installer` backend, with a `boot.json` marker
+/// tracking which slot is active and whether it is still on trial.
+pub struct SlotInstaller {
+    dir: PathBuf,
+    max_boot_attempts: u32,
+    backend: Box<dyn Installer>,
+}
+
+impl SlotInstaller {
+    pub fn new(config: &InstallConfig, backend: Box<dyn Installer>) -> Self {
+        SlotInstaller {
+            dir: config.slot_dir.clone(),
+            max_boot_attempts: config.max_boot_attempts,
+            backend,
+        }
+    }
+
+    pub fn restart_command(&self) -> &[String] {
+        self.backend.restart_command()
+    }
+
+    /// Image of the running version, the base of delta updates.
+    pub fn installed_image(&self) -> Option<PathBuf> {
+        self.backend.installed_image(self.marker().ok()?.active)
+    }
+
+    pub fn marker_path(&self) -> PathBuf {
+        self.dir.join("boot.json")
+    }
+
+    pub fn marker(&self) -> Result<BootMarker, OtaError> {
+        let path = self.marker_path();
+        match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .map_err(|e| OtaError::new(OtaErr::InstallErr).with_path(&path).with_source(e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BootMarker::default()),
+            Err(e) => Err(OtaError::io(OtaErr::InstallErr, &path, e)),
+        }
+    }
+
+    fn save_marker(&self, marker: &BootMarker) -> Result<(), OtaError> {
+        let path = self.marker_path();
+        let data = serde_json::to_vec_pretty(marker)
+            .map_err(|e| OtaError::new(OtaErr::InstallErr).with_path(&path).with_source(e))?;
+        write_atomic(&path, &data).map_err(|e| OtaError::io(OtaErr::InstallErr, &path, e))
+    }
+
+    /// Writes `image` of `version` to the inactive slot and makes it the one
+    /// to start next, on trial until `commit`.
+    pub fn install(&self, image: &Path, version: &str, ids: VersionIds) -> Result<(), OtaError> {
+        let marker = self.marker()?;
+        if !marker.confirmed {
+            log::error!("Slot {:?} is still on trial, not installing over it", marker.active);
+            return Err(OtaError::new(OtaErr::InstallErr).with_path(&self.marker_path()));
+        }
+        fs::create_dir_all(&self.dir).map_err(|e| OtaError::io(OtaErr::InstallErr, &self.dir, e))?;
+        let target = marker.active.other();
+        log::info!("Installing {} into slot {:?}", version, target);
+        self.backend.write(image, target, version)?;
+        self.backend.activate(target, version)?;
+
+        self.save_marker(&BootMarker {
+            active: target,
+            version: version.to_string(),
+            ids,
+            confirmed: false,
+            boot_attempts: 0,
+            previous: Some(marker.active),
+            previous_version: marker.version,
+            previous_ids: marker.ids,
+        })
+    }
+
+    /// Called once per startup, the way a boot loader counts attempts: a
+    /// trial slot failing to confirm too many times is rolled back.
+    pub fn boot(&self) -> Result<BootStatus, OtaError> {
+        let mut marker = self.marker()?;
+        if marker.confirmed {
+            return Ok(BootStatus::Normal { version: marker.version });
+        }
+        marker.boot_attempts += 1;
+        if marker.boot_attempts > self.max_boot_attempts {
+            log::error!("Slot {:?} did not confirm after {} boots", marker.active, self.max_boot_attempts);
+            let version = self.rollback()?;
+            return Ok(BootStatus::RolledBack { version });
+        }
+        self.save_marker(&marker)?;
+        Ok(BootStatus::Trial { version: marker.version, attempts: marker.boot_attempts })
+    }
+
+    /// Keeps the trial slot for good.
+    pub fn commit(&self) -> Result<(), OtaError> {
+        let mut marker = self.marker()?;
+        if marker.confirmed {
+            return Ok(());
+        }
+        marker.confirmed = true;
+        marker.boot_attempts = 0;
+        self.save_marker(&marker)?;
+        log::info!("Slot {:?} with {} confirmed", marker.active, marker.version);
+        Ok(())
+    }
+
+    /// Switches back to the slot that ran before the trial one, returning the
+    /// version it holds.
+    pub fn rollback(&self) -> Result<String, OtaError> {
+        let marker = self.marker()?;
+        let Some(previous) = marker.previous else {
+            log::error!("No previous slot to roll back to");
+            return Err(OtaError::new(OtaErr::InstallErr).with_path(&self.marker_path()));
+        };
+        log::warn!("Rolling back from {} to {}", marker.version, marker.previous_version);
+        self.backend.activate(previous, &marker.previous_version)?;
+        self.save_marker(&BootMarker {
+            active: previous,
+            version: marker.previous_version.clone(),
+            ids: marker.previous_ids,
+            ..BootMarker::default()
+        })?;
+        Ok(marker.previous_version)
+    }
+}
+
+/// Copies `from` to `to` through a synced temporary file, reads it back and
+/// checks it matches.
+fn copy_verified(from: &Path, to: &Path) -> Result<(), OtaError> {
+    // Tells a missing source from a failed write
+    fs::metadata(from).map_err(|e| OtaError::io(OtaErr::InstallErr, from, e))?;
+    let written = copy_synced(from, to).map_err(|e| OtaError::io(OtaErr::InstallErr, to, e))?;
+    let read_back = digest_file(to, None).map_err(|e| OtaError::io(OtaErr::InstallErr, to, e))?;
+    if read_back != written {
+        log::error!("{} does not match {}", to.display(), from.display());
+        return Err(OtaError::new(OtaErr::InstallErr).with_path(to));
+    }
+    Ok(())
+}
+
+/// Copies `from` to `to` through a synced temporary file, returning the
+/// SHA-256 of what was written.
+fn copy_synced(from: &Path, to: &Path) -> std::io::Result<Vec<u8>> {
+    let mut tmp_path = to.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let digest = copy_into(from, &mut File::create(&tmp_path)?)?;
+    fs::set_permissions(&tmp_path, fs::metadata(from)?.permissions())?;
+    fs::rename(&tmp_path, to)?;
+    Ok(digest)
+}
+
+/// Writes `from` to the start of `target` and syncs it, returning the SHA-256
+/// of what was written.
+fn copy_into(from: &Path, target: &mut File) -> std::io::Result<Vec<u8>> {
+    let mut source = File::open(from)?;
+    let mut hasher = Hasher::new(MessageDigest::sha256())?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = source.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        target.write_all(&buf[..n])?;
+        hasher.update(&buf[..n])?;
+    }
+    target.sync_all()?;
+    Ok(hasher.finish()?.to_vec())
+}
+
+/// SHA-256 of `path`, or of its first `len` bytes.
+fn digest_file(path: &Path, len: Option<u64>) -> std::io::Result<Vec<u8>> {
+    let file = File::open(path)?;
+    let mut reader: Box<dyn Read> = match len {
+        Some(len) => Box::new(file.take(len)),
+        None => Box::new(file),
+    };
+    let mut hasher = Hasher::new(MessageDigest::sha256())?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hasher.finish()?.to_vec());
+        }
+        hasher.update(&buf[..n])?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ota-install-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn installer(name: &str) -> (PathBuf, SlotInstaller) {
+        let dir = temp_dir(name);
+        let config = InstallConfig { slot_dir: dir.join("slots"), ..InstallConfig::default() };
+        (dir, SlotInstaller::new(&config, backend(&config, &HcType::Hc01)))
+    }
+
+    fn slot_path(dir: &Path, slot: Slot) -> PathBuf {
+        ImageInstaller::default_slots(&dir.join("slots"))[slot.index()].clone()
+    }
+
+    #[test]
+    fn test_install_and_commit() {
+        let (dir, installer) = installer("commit");
+        fs::write(dir.join("image.bin"), b"version 2").unwrap();
+        assert_eq!(installer.boot(), Ok(BootStatus::Normal { version: String::new() }));
+
+        installer.install(&dir.join("image.bin"), "2.0.0", VersionIds::default()).unwrap();
+        assert_eq!(fs::read(slot_path(&dir, Slot::B)).unwrap(), b"version 2");
+        let marker = installer.marker().unwrap();
+        assert_eq!((marker.active, marker.confirmed, marker.previous), (Slot::B, false, Some(Slot::A)));
+
+        // Nothing is installed over a slot still on trial
+        assert_eq!(installer.install(&dir.join("image.bin"), "2.0.1", VersionIds::default()).unwrap_err(), OtaErr::InstallErr);
+
+        assert_eq!(installer.boot(), Ok(BootStatus::Trial { version: "2.0.0".to_string(), attempts: 1 }));
+        installer.commit().unwrap();
+        assert_eq!(installer.boot(), Ok(BootStatus::Normal { version: "2.0.0".to_string() }));
+
+        // The next update goes to the other slot again
+        fs::write(dir.join("image.bin"), b"version 3").unwrap();
+        installer.install(&dir.join("image.bin"), "3.0.0", VersionIds::default()).unwrap();
+        assert_eq!(fs::read(slot_path(&dir, Slot::A)).unwrap(), b"version 3");
+        assert_eq!(fs::read(slot_path(&dir, Slot::B)).unwrap(), b"version 2");
+    }
+
+    #[test]
+    fn test_rollback_after_failed_boots() {
+        let (dir, installer) = installer("bootloop");
+        fs::write(dir.join("image.bin"), b"old").unwrap();
+        let old = VersionIds { version_number: 1, version_min_id: 0 };
+        installer.install(&dir.join("image.bin"), "1.0.0", old).unwrap();
+        installer.commit().unwrap();
+        fs::write(dir.join("image.bin"), b"broken").unwrap();
+        installer.install(&dir.join("image.bin"), "2.0.0", VersionIds { version_number: 2, version_min_id: 1 }).unwrap();
+
+        for attempts in 1..=3 {
+            assert_eq!(installer.boot(), Ok(BootStatus::Trial { version: "2.0.0".to_string(), attempts }));
+        }
+        assert_eq!(installer.boot(), Ok(BootStatus::RolledBack { version: "1.0.0".to_string() }));
+        let marker = installer.marker().unwrap();
+        assert_eq!((marker.active, marker.confirmed, marker.version.as_str(), marker.ids), (Slot::B, true, "1.0.0", old));
+        assert_eq!(installer.boot(), Ok(BootStatus::Normal { version: "1.0.0".to_string() }));
+
+        // Nothing left to roll back to
+        assert_eq!(installer.rollback().unwrap_err(), OtaErr::InstallErr);
+    }
+
+    #[test]
+    fn test_backend_per_hc_type() {
+        let config: InstallConfig = toml::from_str(r#"
+            restart_command = ["systemctl", "reboot"]
+            [backends.Hc02]
+            type = "binary"
+            path = "/usr/bin/hc-app"
+            restart_command = ["systemctl", "restart", "hc-app"]
+        "#).unwrap();
+        assert_eq!(backend(&config, &HcType::Hc01).restart_command(), ["systemctl", "reboot"]);
+        assert_eq!(backend(&config, &HcType::Hc02).restart_command(), ["systemctl", "restart", "hc-app"]);
+        assert_eq!(config.backends[&HcType::Hc02], Backend::Binary {
+            path: PathBuf::from("/usr/bin/hc-app"),
+            restart_command: vec!["systemctl".to_string(), "restart".to_string(), "hc-app".to_string()],
+        });
+    }
+
+    #[test]
+    fn test_missing_image() {
+        let (dir, installer) = installer("missing");
+        let e = installer.install(&dir.join("missing.bin"), "2.0.0", VersionIds::default()).unwrap_err();
+        assert_eq!(e, OtaErr::InstallErr);
+        assert!(e.path.is_some() && std::error::Error::source(&e).is_some());
+        assert_eq!(installer.marker(), Ok(BootMarker::default()));
+    }
+}
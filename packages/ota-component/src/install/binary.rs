@@ -0,0 +1,93 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use super::{copy_verified, Installer, Slot};
+use crate::error::{OtaErr, OtaError};
+
+/// Single executable, e.g. the firmware of a controller running as a
+/// service. Both versions are kept next to it as `<path>.slot_a` and
+/// `<path>.slot_b`, the active one is copied over `path`.
+pub struct BinaryInstaller {
+    path: PathBuf,
+    restart_command: Vec<String>,
+}
+
+impl BinaryInstaller {
+    pub fn new(path: PathBuf, restart_command: Vec<String>) -> Self {
+        BinaryInstaller { path, restart_command }
+    }
+
+    pub fn slot_path(&self, slot: Slot) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(match slot {
+            Slot::A => ".slot_a",
+            Slot::B => ".slot_b",
+        });
+        PathBuf::from(path)
+    }
+}
+
+fn make_executable(path: &Path) -> Result<(), OtaError> {
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).map_err(|e| OtaError::io(OtaErr::InstallErr, path, e))
+}
+
+impl Installer for BinaryInstaller {
+    fn write(&self, image: &Path, slot: Slot, _version: &str) -> Result<(), OtaError> {
+        // Keep the binary installed before the first update to roll back to
+        let running = self.slot_path(slot.other());
+        if !running.exists() && self.path.exists() {
+            copy_verified(&self.path, &running)?;
+        }
+        let target = self.slot_path(slot);
+        copy_verified(image, &target)?;
+        make_executable(&target)
+    }
+
+    fn activate(&self, slot: Slot, _version: &str) -> Result<(), OtaError> {
+        // Renamed over the old one, so the service never sees half a binary
+        copy_verified(&self.slot_path(slot), &self.path)
+    }
+
+    fn restart_command(&self) -> &[String] {
+        &self.restart_command
+    }
+
+    fn installed_image(&self, slot: Slot) -> Option<PathBuf> {
+        // Before the first update only the binary itself is there
+        let path = self.slot_path(slot);
+        Some(if path.exists() { path } else { self.path.clone() })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::install::test::temp_dir;
+
+    #[test]
+    fn test_replace_binary() {
+        let dir = temp_dir("binary");
+        let path = dir.join("hc-app");
+        fs::write(&path, b"old").unwrap();
+        fs::write(dir.join("update.bin"), b"new").unwrap();
+        let installer = BinaryInstaller::new(path.clone(), Vec::new());
+
+        installer.write(&dir.join("update.bin"), Slot::B, "2.0.0").unwrap();
+        // Nothing changes before activating
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        assert_eq!(fs::read(installer.slot_path(Slot::A)).unwrap(), b"old");
+
+        installer.activate(Slot::B, "2.0.0").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o755);
+
+        installer.activate(Slot::A, "").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+
+        installer.activate(Slot::B, "2.0.0").unwrap();
+        fs::remove_file(installer.slot_path(Slot::A)).unwrap();
+        let e = installer.activate(Slot::A, "").unwrap_err();
+        assert_eq!((e.kind, e.path), (OtaErr::InstallErr, Some(installer.slot_path(Slot::A))));
+    }
+}
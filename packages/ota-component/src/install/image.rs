@@ -0,0 +1,61 @@
+use std::fs::{self, OpenOptions};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+use super::{copy_into, copy_verified, digest_file, Installer, Slot};
+use crate::error::{OtaErr, OtaError};
+
+/// Raw image written to one of two slots. On a device the slots are
+/// partitions, on plain Linux files or loop images. The boot loader picks the
+/// slot from the boot marker, so activating needs nothing more.
+pub struct ImageInstaller {
+    slots: [PathBuf; 2],
+    restart_command: Vec<String>,
+}
+
+impl ImageInstaller {
+    pub fn new(slots: [PathBuf; 2], restart_command: Vec<String>) -> Self {
+        ImageInstaller { slots, restart_command }
+    }
+
+    pub fn default_slots(dir: &Path) -> [PathBuf; 2] {
+        [dir.join("slot_a.img"), dir.join("slot_b.img")]
+    }
+}
+
+impl Installer for ImageInstaller {
+    fn write(&self, image: &Path, slot: Slot, _version: &str) -> Result<(), OtaError> {
+        let path = &self.slots[slot.index()];
+        let is_device = fs::metadata(path).map(|meta| meta.file_type().is_block_device()).unwrap_or(false);
+        if !is_device {
+            return copy_verified(image, path);
+        }
+
+        // Partitions are written in place and larger than the image, only
+        // the part written is read back
+        let written = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|mut device| copy_into(image, &mut device))
+            .map_err(|e| OtaError::io(OtaErr::InstallErr, path, e))?;
+        let len = fs::metadata(image).map_err(|e| OtaError::io(OtaErr::InstallErr, image, e))?.len();
+        let read_back = digest_file(path, Some(len)).map_err(|e| OtaError::io(OtaErr::InstallErr, path, e))?;
+        if read_back != written {
+            log::error!("{} does not match the image", path.display());
+            return Err(OtaError::new(OtaErr::InstallErr).with_path(path));
+        }
+        Ok(())
+    }
+
+    fn activate(&self, _slot: Slot, _version: &str) -> Result<(), OtaError> {
+        Ok(())
+    }
+
+    fn restart_command(&self) -> &[String] {
+        &self.restart_command
+    }
+
+    fn installed_image(&self, slot: Slot) -> Option<PathBuf> {
+        Some(self.slots[slot.index()].clone())
+    }
+}
@@ -0,0 +1,165 @@
+use std::fs::{self, File};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use flate2::read::GzDecoder;
+
+use super::{Installer, Slot};
+use crate::error::{OtaErr, OtaError};
+
+/// `.tar.gz` releases extracted into `<root>/<version>`. The `slot_a` and
+/// `slot_b` links point at the two versions kept, `current` at the active
+/// one, and is what the service runs from.
+pub struct TarballInstaller {
+    root: PathBuf,
+    restart_command: Vec<String>,
+}
+
+impl TarballInstaller {
+    pub fn new(root: PathBuf, restart_command: Vec<String>) -> Self {
+        TarballInstaller { root, restart_command }
+    }
+
+    pub fn current(&self) -> PathBuf {
+        self.root.join("current")
+    }
+
+    fn slot_link(&self, slot: Slot) -> PathBuf {
+        match slot {
+            Slot::A => self.root.join("slot_a"),
+            Slot::B => self.root.join("slot_b"),
+        }
+    }
+
+    /// Removes versions no slot points at any more.
+    fn clean_up(&self) {
+        let kept: Vec<PathBuf> = [Slot::A, Slot::B].iter()
+            .filter_map(|slot| fs::read_link(self.slot_link(*slot)).ok())
+            .collect();
+        let Ok(entries) = fs::read_dir(&self.root) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let is_dir = entry.file_type().map(|file_type| file_type.is_dir()).unwrap_or(false);
+            if is_dir && !kept.iter().any(|kept| kept.as_os_str() == entry.file_name()) {
+                log::info!("Removing old version {}", entry.path().display());
+                let _ = fs::remove_dir_all(entry.path());
+            }
+        }
+    }
+}
+
+/// Points `link` at `target`, replacing it atomically.
+fn switch_link(link: &Path, target: &Path) -> std::io::Result<()> {
+    let mut tmp = link.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let _ = fs::remove_file(&tmp);
+    symlink(target, &tmp)?;
+    fs::rename(&tmp, link)
+}
+
+
+impl Installer for TarballInstaller {
+    fn write(&self, image: &Path, slot: Slot, version: &str) -> Result<(), OtaError> {
+        fs::create_dir_all(&self.root).map_err(|e| OtaError::io(OtaErr::InstallErr, &self.root, e))?;
+        // Keep the release installed before the first update to roll back to
+        let running = self.slot_link(slot.other());
+        if fs::symlink_metadata(&running).is_err() {
+            if let Ok(target) = fs::read_link(self.current()) {
+                switch_link(&running, &target).map_err(|e| OtaError::io(OtaErr::InstallErr, &running, e))?;
+            }
+        }
+        if version.is_empty() || version.contains(['/', '\\']) || version.starts_with('.') {
+            log::error!("Invalid release version {:?}", version);
+            return Err(OtaErr::InstallErr.into());
+        }
+        if fs::read_link(&running).is_ok_and(|target| target.as_os_str() == version) {
+            log::error!("Version {} is the one running", version);
+            return Err(OtaError::new(OtaErr::InstallErr).with_path(&running));
+        }
+
+        let dir = self.root.join(version);
+        let tmp = self.root.join(format!(".{}.tmp", version));
+        let _ = fs::remove_dir_all(&tmp);
+        let _ = fs::remove_dir_all(&dir);
+        let file = File::open(image).map_err(|e| OtaError::io(OtaErr::InstallErr, image, e))?;
+        tar::Archive::new(GzDecoder::new(file)).unpack(&tmp).map_err(|e| {
+            let _ = fs::remove_dir_all(&tmp);
+            OtaError::io(OtaErr::InstallErr, image, e)
+        })?;
+        fs::rename(&tmp, &dir).map_err(|e| OtaError::io(OtaErr::InstallErr, &dir, e))?;
+        let link = self.slot_link(slot);
+        switch_link(&link, Path::new(version)).map_err(|e| OtaError::io(OtaErr::InstallErr, &link, e))?;
+        self.clean_up();
+        Ok(())
+    }
+
+    fn activate(&self, slot: Slot, _version: &str) -> Result<(), OtaError> {
+        let link = self.slot_link(slot);
+        let target = fs::read_link(&link).map_err(|e| OtaError::io(OtaErr::InstallErr, &link, e))?;
+        if !self.root.join(&target).is_dir() {
+            log::error!("Release {} is gone", target.display());
+            return Err(OtaError::new(OtaErr::InstallErr).with_path(&self.root.join(&target)));
+        }
+        switch_link(&self.current(), &target).map_err(|e| OtaError::io(OtaErr::InstallErr, &self.current(), e))
+    }
+
+    fn restart_command(&self) -> &[String] {
+        &self.restart_command
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::install::test::temp_dir;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    fn release(path: &Path, content: &[u8]) {
+        let mut builder = tar::Builder::new(GzEncoder::new(File::create(path).unwrap(), Compression::default()));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o755);
+        builder.append_data(&mut header, "bin/hc-app", content).unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn test_extract_and_switch() {
+        let dir = temp_dir("tarball");
+        let root = dir.join("hc");
+        fs::create_dir_all(root.join("factory/bin")).unwrap();
+        fs::write(root.join("factory/bin/hc-app"), b"factory").unwrap();
+        symlink("factory", root.join("current")).unwrap();
+        let installer = TarballInstaller::new(root.clone(), Vec::new());
+        let app = root.join("current/bin/hc-app");
+
+        release(&dir.join("2.0.0.tar.gz"), b"two");
+        installer.write(&dir.join("2.0.0.tar.gz"), Slot::B, "2.0.0").unwrap();
+        assert_eq!(fs::read(&app).unwrap(), b"factory");
+        installer.activate(Slot::B, "2.0.0").unwrap();
+        assert_eq!(fs::read(&app).unwrap(), b"two");
+        assert_eq!(fs::read_link(installer.current()).unwrap(), PathBuf::from("2.0.0"));
+
+        // Back to what was there before the first update
+        installer.activate(Slot::A, "").unwrap();
+        assert_eq!(fs::read(&app).unwrap(), b"factory");
+        installer.activate(Slot::B, "2.0.0").unwrap();
+
+        // Only the two slots are kept
+        release(&dir.join("3.0.0.tar.gz"), b"three");
+        installer.write(&dir.join("3.0.0.tar.gz"), Slot::A, "3.0.0").unwrap();
+        installer.activate(Slot::A, "3.0.0").unwrap();
+        assert_eq!(fs::read(&app).unwrap(), b"three");
+        assert!(!root.join("factory").exists());
+        assert!(root.join("2.0.0").is_dir());
+
+        // Not a release
+        fs::write(dir.join("broken.tar.gz"), b"garbage").unwrap();
+        let e = installer.write(&dir.join("broken.tar.gz"), Slot::B, "4.0.0").unwrap_err();
+        assert_eq!((e.kind, e.path), (OtaErr::InstallErr, Some(dir.join("broken.tar.gz"))));
+        assert_eq!(installer.write(&dir.join("3.0.0.tar.gz"), Slot::B, "../x").unwrap_err(), OtaErr::InstallErr);
+        assert_eq!(fs::read(&app).unwrap(), b"three");
+    }
+}
@@ -1,106 +1,348 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use rand::Rng;
+use rand::rngs::StdRng;
+use lumi_utils::timer::Timer;
 extern crate chrono;
-use chrono::{DateTime, Utc, Timelike, FixedOffset};
+use chrono::{DateTime, Utc};
 use crate::logic::chrono::TimeZone;
-use crate::error::OtaErr;
-use crate::transport::TransportOut;
+use crate::error::{OtaErr, OtaError};
+use crate::health::ProbeResult;
+use crate::install::BootStatus;
+use crate::retry::{Backoff, RetryPolicy};
+use crate::schedule::MaintenanceSchedule;
+use crate::state::{OtaPhase, OtaState};
+use crate::transport::{Data, TransportOut, UpdateOutcome, UpdateReport};
+use crate::transport::download::Progress;
+use crate::transport::mqtt::OtaStatus;
+use crate::version;
+use ota_package::manifest::{Delta, Manifest};
+use std::str::FromStr;
 use std::time::Duration;
+use serde::{Deserialize, Serialize};
 
 #[derive(PartialEq, Clone)]
 pub enum OtaLogicIn { 
-    Transport(Result<TransportOut, OtaErr>),
-    Push(OtaLogicOut)
+    Transport(Result<TransportOut, OtaError>),
+    Push(OtaLogicOut),
+    Verify(Result<(), OtaError>),
+    Installed(Result<(), OtaError>),
+    /// Manifest of the offered update, once its signature is checked
+    Manifest(Result<Manifest, OtaError>),
+    /// Version name of the running firmware, for `CompareVersionEvent`
+    RunningVersion(Result<String, OtaError>),
+    /// Slot the controller came up in, once per startup
+    Boot(BootStatus),
+    /// Results of a round of health probes
+    Health(Vec<ProbeResult>),
+    Committed(Result<(), OtaError>),
+    /// Version name of the slot rolled back to
+    RolledBack(Result<String, OtaError>),
+    /// Services quiesced for `SuppentEvent`
+    Quiesced(Result<(), OtaError>),
+    /// The MQTT session to the local broker went up or down
+    BrokerConnected(bool),
 }
 
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub enum HcType {
     Hc01,
     Hc02
 }
 
-#[derive(PartialEq, Debug,Clone)]
+impl HcType {
+    /// Name of the controller type in manifests and the config, e.g. `Hc01`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HcType::Hc01 => "Hc01",
+            HcType::Hc02 => "Hc02",
+        }
+    }
+}
+
+impl FromStr for HcType {
+    type Err = OtaErr;
+
+    fn from_str(name: &str) -> Result<Self, OtaErr> {
+        match name {
+            "Hc01" => Ok(HcType::Hc01),
+            "Hc02" => Ok(HcType::Hc02),
+            _ => Err(OtaErr::IncompatibleErr),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Hash, Debug,Clone, Serialize, Deserialize)]
 pub enum OtaLogicOut {
     CheckOtaEvent,
     UpdateOtaEvent(HcType),
     CompareVersionEvent,
+    ManifestEvent,
     VerifyEvent,
     GetLinkEvent,
     KeepAliveEvent,
     SuppentEvent,
+    /// Keep the new version for good
+    CommitEvent,
+    /// Go back to the previous slot
+    RollbackEvent,
+    /// Restart into the slot to boot next
+    RestartEvent,
+    /// Run the health probes on the new version
+    HealthCheckEvent,
+    /// Send `state.report` to the server
+    ReportEvent,
+    /// Publish `status()`
+    ProgressEvent,
 }
 pub struct HcDriver {
     pub hc_type :HcType,
     pub allow_ota: bool,
     pub pid : u32,
     pub version_name :String,
-    pub link: String
 }
 
 
 pub struct OtaLogic {
     pub outputs: VecDeque<OtaLogicOut>,
+    /// Events released into `outputs` once the clock reaches their time (ms)
+    pub scheduled: Vec<(u64, OtaLogicOut)>,
+    timer: Box<dyn Timer + Send>,
+    rng: StdRng,
     pub rnd_check: i64,
-    pub rnd_update_ota: u8,
-    pub last_date_time: DateTime<FixedOffset>,
+    pub rnd_update_ota: u32,
+    pub last_date_time: DateTime<Utc>,
+    pub schedule: MaintenanceSchedule,
+    pub last_window: Option<DateTime<Utc>>,
     pub hc : HcDriver,
-    pub timeout: u64 
+    pub retry_policy: RetryPolicy,
+    /// Backoff state per retried event
+    pub retries: HashMap<OtaLogicOut, Backoff>,
+    /// Progress of the current update, persisted across reboots
+    pub state: OtaState,
+    /// Highest `version_number` installed, images numbered below it are refused
+    pub security_counter: u16,
+    /// Update offered by the server, waiting for its manifest to be checked
+    pub offer: Option<Data>,
+    /// Update rejected for good (bad manifest, unsigned or corrupt image),
+    /// not tried again
+    pub rejected_download: Option<u64>,
+    /// Time the new version has after restarting to confirm it works
+    pub confirm_timeout: Duration,
+    /// When the trial version is rolled back unless confirmed (ms)
+    confirm_deadline: Option<u64>,
+    /// Time between rounds of health probes on trial
+    pub health_interval: Duration,
+    /// Last round of health probes
+    health: Vec<ProbeResult>,
+    /// Last progress of the running download
+    progress: Option<Progress>,
+    /// Periodic checks and keep-alives pause while the local broker is down
+    pub broker_connected: bool,
 }
 
 impl OtaLogic {
-    pub fn new() -> Self {
-        let temp = Utc::now().with_timezone(&FixedOffset::east_opt(7 * 3600).unwrap());
+    pub fn new(schedule: MaintenanceSchedule, retry_policy: RetryPolicy, mut timer: Box<dyn Timer + Send>, mut rng: StdRng) -> Self {
         let hc = HcDriver {
             version_name: "".to_string(),
-            link: "".to_string(),
             hc_type : HcType::Hc01,
             allow_ota: false,
             pid: std::process::id(),
         };
         let outputs = std::iter::once(OtaLogicOut::CheckOtaEvent).collect();
+        let (jitter_min, jitter_max) = schedule.jitter_minutes;
+        let now = timer.now_ms();
         OtaLogic {
-            outputs: outputs,
-            rnd_check: rand::thread_rng().gen_range(30..=50),
-            rnd_update_ota: rand::thread_rng().gen_range(0..=120),
-            last_date_time: temp,
-            hc:hc,
-            timeout: 3
+            outputs,
+            scheduled: Vec::new(),
+            rnd_check: rng.gen_range(30..=50),
+            rnd_update_ota: rng.gen_range(jitter_min..=jitter_max),
+            last_date_time: Utc.timestamp_millis_opt(now as i64).single().unwrap_or_default(),
+            timer,
+            rng,
+            schedule,
+            last_window: None,
+            hc,
+            retry_policy,
+            retries: HashMap::new(),
+            state: OtaState::default(),
+            security_counter: 0,
+            offer: None,
+            rejected_download: None,
+            confirm_timeout: Duration::from_secs(300),
+            confirm_deadline: None,
+            health_interval: Duration::from_secs(10),
+            health: Vec::new(),
+            progress: None,
+            broker_connected: true,
+        }
+    }
+
+    /// Continues from a state saved before a restart: the step that was in
+    /// flight is issued again instead of starting over from the check.
+    pub fn restore(&mut self, state: OtaState) {
+        log::info!("Restore ota state {:?} for version {}", state.phase, state.target_version);
+        self.retries = state.retries.iter()
+            .map(|(event, attempts)| (event.clone(), Backoff { attempts: *attempts }))
+            .collect();
+        self.rejected_download = state.rejected_download;
+        self.state = state;
+
+        let report = self.state.report.as_ref().map(|_| OtaLogicOut::ReportEvent);
+        let resume = match self.state.phase {
+            OtaPhase::Idle => {
+                self.outputs.extend(report);
+                return;
+            }
+            // Waits for `Boot` to tell how the restart went
+            OtaPhase::Verified | OtaPhase::Confirming => None,
+            OtaPhase::Downloading => Some(OtaLogicOut::GetLinkEvent),
+            OtaPhase::Verifying => Some(OtaLogicOut::VerifyEvent),
+            OtaPhase::Suspending => Some(OtaLogicOut::SuppentEvent),
+            OtaPhase::Installing => Some(OtaLogicOut::UpdateOtaEvent(self.hc.hc_type.clone())),
+        };
+        self.outputs.clear();
+        self.outputs.extend(resume);
+        self.outputs.extend(report);
+    }
+
+    /// Current state including retry counters, as it should be persisted.
+    pub fn snapshot(&self) -> OtaState {
+        let mut retries: Vec<(OtaLogicOut, u32)> = self.retries.iter()
+            .filter(|(_, backoff)| backoff.attempts > 0)
+            .map(|(event, backoff)| (event.clone(), backoff.attempts))
+            .collect();
+        retries.sort_by_key(|(event, _)| format!("{:?}", event));
+        OtaState { retries, rejected_download: self.rejected_download, ..self.state.clone() }
+    }
+
+    /// Pushes an output, moving to the phase it starts.
+    fn emit(&mut self, event: OtaLogicOut) {
+        match event {
+            OtaLogicOut::GetLinkEvent => self.state.phase = OtaPhase::Downloading,
+            OtaLogicOut::VerifyEvent => self.state.phase = OtaPhase::Verifying,
+            OtaLogicOut::SuppentEvent => self.state.phase = OtaPhase::Suspending,
+            OtaLogicOut::UpdateOtaEvent(_) => self.state.phase = OtaPhase::Installing,
+            _ => {}
         }
+        self.outputs.push_back(event);
     }
-    fn compare_hour_ota(&mut self, hour: u32, minute: u32, allow: bool) {
-        // Convert rand to hour
-        let hour_ota = 2 + (self.rnd_update_ota / 60);
-        let minute_ota = self.rnd_update_ota % 60;
 
-        if (hour_ota as u32 == hour && minute_ota as u32 == minute) || allow {
-            // call update ota
-            self.outputs.push_back(OtaLogicOut::SuppentEvent);
-            self.hc.allow_ota = false;
+    /// Quiesces services to install the verified update, if there is one.
+    fn start_update(&mut self) -> bool {
+        self.hc.allow_ota = false;
+        if self.state.phase != OtaPhase::Verified {
+            log::info!("No verified update to install");
+            return false;
+        }
+        let now = self.timer.now_ms() / 1000;
+        if let Some(Err(e)) = self.state.manifest.as_ref().map(|manifest| check_expiry(manifest, now)) {
+            self.reject(e.into());
+            return false;
+        }
+        self.emit(OtaLogicOut::SuppentEvent);
+        true
+    }
 
+    fn check_maintenance(&mut self, now: DateTime<Utc>) {
+        if self.hc.allow_ota {
+            log::info!("Update ota requested by master");
+            self.start_update();
+            return;
         }
 
-        log::info!("Time update ota {}:{}", hour_ota, minute_ota);
+        let Some((start, end)) = self.schedule.window_at(now) else {
+            return;
+        };
+        // Only once per window occurrence, at the randomised slot inside it
+        if self.last_window == Some(start) || now < self.schedule.slot(start, end, self.rnd_update_ota) {
+            return;
+        }
 
+        // An update verified later in the window is still installed in it
+        if !self.start_update() {
+            return;
+        }
+        log::info!("Time update ota {}", now.with_timezone(&self.schedule.timezone));
+        self.last_window = Some(start);
+
+        let (jitter_min, jitter_max) = self.schedule.jitter_minutes;
+        self.rnd_update_ota = self.rng.gen_range(jitter_min..=jitter_max);
+    }
+
+    /// Queues `event` to be emitted `delay` from now instead of blocking.
+    pub fn schedule_after(&mut self, delay: Duration, event: OtaLogicOut) {
+        let at = self.timer.now_ms() + delay.as_millis() as u64;
+        log::info!("Schedule {:?} in {:?}", event, delay);
+        self.scheduled.push((at, event));
+    }
+
+    /// Schedules another attempt of `event` according to the retry policy,
+    /// or gives up once it is exhausted.
+    fn retry(&mut self, event: OtaLogicOut) {
+        let backoff = self.retries.entry(event.clone()).or_default();
+        match backoff.next_delay(&self.retry_policy, &mut self.rng) {
+            Some(delay) => {
+                self.schedule_after(delay, event);
+            }
+            None => {
+                log::error!("Giving up {:?} after {} attempts", event, backoff.attempts);
+                backoff.reset();
+            }
+        }
     }
 
-    pub fn on_tick(&mut self, now_ms: u64) {
+    fn succeeded(&mut self, event: OtaLogicOut) {
+        if self.retry_policy.reset_on_success {
+            self.retries.remove(&event);
+        }
+    }
 
-        let timestamp = now_ms; // Example: 1st January 2021, 00:00:00 UTC
-        let datetime_utc: DateTime<Utc> = Utc.timestamp_opt((timestamp / 1000) as i64, 0).unwrap();
-        let datetime_vn = datetime_utc.with_timezone(&FixedOffset::east_opt(7 * 3600).unwrap());
-        let hour = datetime_vn.hour();
-        let minute = datetime_vn.minute();
+    pub fn next_scheduled(&self) -> Option<u64> {
+        self.scheduled.iter().map(|(at, _)| *at).min()
+    }
+
+    fn release_scheduled(&mut self, now_ms: u64) {
+        let mut due: Vec<(u64, OtaLogicOut)> = Vec::new();
+        self.scheduled.retain(|(at, event)| {
+            if *at <= now_ms {
+                due.push((*at, event.clone()));
+                false
+            } else {
+                true
+            }
+        });
+        due.sort_by_key(|(at, _)| *at);
+        for (_, event) in due {
+            match event {
+                // The update may have been dropped or replaced in the meantime
+                OtaLogicOut::SuppentEvent => {
+                    self.start_update();
+                }
+                event => self.emit(event),
+            }
+        }
+    }
+
+    pub fn on_tick(&mut self) {
+        let now_ms = self.timer.now_ms();
+        self.release_scheduled(now_ms);
+
+        if self.confirm_deadline.is_some_and(|deadline| now_ms >= deadline) {
+            log::error!("Update {} did not confirm in time", self.state.target_version);
+            self.confirm_deadline = None;
+            self.scheduled.retain(|(_, event)| *event != OtaLogicOut::HealthCheckEvent);
+            self.outputs.push_back(OtaLogicOut::RollbackEvent);
+        }
+
+        let now = Utc.timestamp_millis_opt(now_ms as i64).single().unwrap_or_default();
         // Check if it has been 30 minutes since the last "Hello"
-        if datetime_vn.signed_duration_since(self.last_date_time).num_minutes() >= 1 {
+        if self.broker_connected && now.signed_duration_since(self.last_date_time).num_minutes() >= 1 {
             self.outputs.push_back(OtaLogicOut::CheckOtaEvent);
             self.outputs.push_back(OtaLogicOut::KeepAliveEvent);
-            self.last_date_time = datetime_vn;
+            self.last_date_time = now;
             
         }
-        if (hour >=2 && hour < 4) || self.hc.allow_ota {
-            self.compare_hour_ota(hour, minute, self.hc.allow_ota);
-        }
-
+        self.check_maintenance(now);
     }
 
     pub fn on_event(&mut self, _event:OtaLogicIn) {
@@ -112,167 +354,634 @@ impl OtaLogic {
                             TransportOut::ResponseRequest(response) => {
                                 // send pack get link 
                                 log::info!("Response successfully with link : {}", response.data.link);
-                                self.hc.link = response.data.link;
-                                self.outputs.push_back(OtaLogicOut::CompareVersionEvent);
+                                self.succeeded(OtaLogicOut::CheckOtaEvent);
+                                if self.state.phase == OtaPhase::Confirming {
+                                    log::info!("Update {} still on trial", self.state.target_version);
+                                    return;
+                                }
+                                if self.rejected_download == Some(response.data.download_id) {
+                                    log::warn!("Ignoring rejected update {}", response.data.version_name);
+                                    return;
+                                }
+                                if self.state.phase != OtaPhase::Idle && self.state.download_id == response.data.download_id {
+                                    log::info!("Update {} already in progress", self.state.target_version);
+                                    return;
+                                }
+                                self.offer = Some(response.data);
+                                self.outputs.push_back(OtaLogicOut::ManifestEvent);
                             } 
                             TransportOut::ResponseLink                             => {
                                 log::info!("Get link successfully");    
-                                self.outputs.push_back(OtaLogicOut::VerifyEvent);
+                                self.succeeded(OtaLogicOut::GetLinkEvent);
+                                self.emit(OtaLogicOut::VerifyEvent);
                             }  
 
                             TransportOut::ResponseKeepAlive                        => {
                                 log::info!("Keep alive to manager service successfully");
                             }  
 
-                            TransportOut::ResponseSuppend                          => {
-                                log::info!("Suppend to manager service successfully");
-                                self.outputs.push_back(OtaLogicOut::UpdateOtaEvent(self.hc.hc_type.clone()));
-                            }  
-                        }
-                    }
-
-                    Err(e) => {
-                        match e {
-                            OtaErr::DownloadErr | OtaErr::LinkErr |  OtaErr::NoLinkResErr | OtaErr::ServerNoReturnErr => {
-                                std::thread::sleep(Duration::from_secs(self.timeout));
-                                self.outputs.push_back(OtaLogicOut::CheckOtaEvent);
-                                self.timeout *= 100;
-                            }
-                            OtaErr::NotEnoughMemoryErr => {
-                                std::thread::sleep(Duration::from_secs(self.timeout));
-                                self.outputs.push_back(OtaLogicOut::SuppentEvent);
-                                self.timeout *= 100;
-                            }
-                            OtaErr::VerifyErr | OtaErr::VerifyNotEqualErr => {
-                                std::thread::sleep(Duration::from_secs(self.timeout));
-                                self.outputs.push_back(OtaLogicOut::GetLinkEvent);
-                                self.timeout *= 100;
-                            }
-
-                            OtaErr::UserCalendarErr => {
-
+                            TransportOut::ResponseReport => {
+                                log::info!("Update report sent");
+                                self.succeeded(OtaLogicOut::ReportEvent);
+                                self.state.report = None;
                             }
-                            _ => {
 
+                            TransportOut::Progress(progress) => {
+                                // Late messages of a download already over
+                                if self.state.phase == OtaPhase::Downloading {
+                                    self.progress = Some(progress);
+                                    self.outputs.push_back(OtaLogicOut::ProgressEvent);
+                                }
                             }
                         }
                     }
+
+                    Err(e) => self.on_error(e),
                 }  
             }
             OtaLogicIn::Push(event) => {
-                match event {
-                    OtaLogicOut::GetLinkEvent => {
-                        self.outputs.push_back(event);
+                // Nothing is downloaded without an accepted manifest
+                if event == OtaLogicOut::GetLinkEvent && self.state.manifest.is_some() {
+                    self.emit(event);
+                }
+            }
+            OtaLogicIn::Manifest(result) => {
+                let Some(offer) = self.offer.take() else {
+                    return;
+                };
+                let now = self.timer.now_ms() / 1000;
+                match result.and_then(|manifest| self.check_manifest(&offer, &manifest, now).map(|_| manifest).map_err(OtaError::from)) {
+                    Ok(manifest) => {
+                        log::info!("Manifest of {} accepted", manifest.version_name);
+                        let report = self.state.report.take();
+                        self.state = OtaState {
+                            target_version: offer.version_name,
+                            download_id: offer.download_id,
+                            link: offer.link,
+                            // The signed digest is the one to trust
+                            checksum: manifest.checksum(),
+                            signature_link: offer.signature_link,
+                            manifest: Some(manifest),
+                            report,
+                            ..OtaState::default()
+                        };
+                        self.outputs.push_back(OtaLogicOut::CompareVersionEvent);
                     }
-                    _ => {
-
+                    Err(e) => {
+                        log::error!("Update {} rejected: {}", offer.version_name, e);
+                        self.rejected_download = Some(offer.download_id);
+                    }
+                }
+            }
+            OtaLogicIn::RunningVersion(result) => {
+                let Some(manifest) = self.state.manifest.clone() else {
+                    return;
+                };
+                if self.state.phase != OtaPhase::Idle {
+                    return;
+                }
+                if let Ok(name) = result {
+                    self.hc.version_name = name;
+                }
+                match self.check_version(&manifest) {
+                    Ok(true) => {
+                        log::info!("Updating {:?} to {}", self.hc.version_name, manifest.version_name);
+                        self.emit(OtaLogicOut::GetLinkEvent);
+                    }
+                    Ok(false) => {
+                        log::info!("Already running {}", manifest.version_name);
+                        self.reset_state();
+                    }
+                    Err(e) => self.reject(e.into()),
+                }
+            }
+            OtaLogicIn::BrokerConnected(connected) => {
+                if connected && !self.broker_connected {
+                    log::info!("Broker back, resuming checks");
+                    self.outputs.push_back(OtaLogicOut::CheckOtaEvent);
+                    self.outputs.push_back(OtaLogicOut::KeepAliveEvent);
+                    self.last_date_time = Utc.timestamp_millis_opt(self.timer.now_ms() as i64).single().unwrap_or_default();
+                } else if !connected && self.broker_connected {
+                    log::warn!("Broker lost, pausing checks");
+                }
+                self.broker_connected = connected;
+            }
+            OtaLogicIn::Quiesced(result) => {
+                match result {
+                    Ok(()) if self.state.phase == OtaPhase::Suspending && self.state.manifest.is_some() => {
+                        log::info!("Services quiesced, installing");
+                        self.succeeded(OtaLogicOut::SuppentEvent);
+                        self.emit(OtaLogicOut::UpdateOtaEvent(self.hc.hc_type.clone()));
+                    }
+                    Ok(()) => log::error!("Services quiesced without a verified update, not installing"),
+                    Err(e) => self.on_error(e),
+                }
+            }
+            OtaLogicIn::Verify(result) => {
+                match result {
+                    Ok(()) => {
+                        log::info!("Update {} verified, waiting for maintenance window", self.state.target_version);
+                        self.state.phase = OtaPhase::Verified;
+                    }
+                    Err(e) => self.on_error(e),
+                }
+            }
+            OtaLogicIn::Installed(result) => {
+                match result {
+                    Ok(()) => {
+                        log::info!("Update {} installed, restarting into it", self.state.target_version);
+                        self.state.phase = OtaPhase::Confirming;
+                        self.retries.clear();
+                        self.outputs.push_back(OtaLogicOut::RestartEvent);
+                    }
+                    // Not retried, another attempt would install the same image
+                    Err(e) => self.reject(e),
+                }
+            }
+            OtaLogicIn::Boot(status) => self.on_boot(status),
+            OtaLogicIn::Health(results) => {
+                if self.confirm_deadline.is_none() {
+                    return;
+                }
+                let passed = results.iter().all(|result| result.passed);
+                self.health = results;
+                if passed {
+                    log::info!("Update {} is healthy", self.state.target_version);
+                    self.confirm_deadline = None;
+                    self.outputs.push_back(OtaLogicOut::CommitEvent);
+                } else {
+                    self.schedule_after(self.health_interval, OtaLogicOut::HealthCheckEvent);
+                }
+            }
+            OtaLogicIn::Committed(result) => {
+                match result {
+                    Ok(()) => {
+                        self.succeeded(OtaLogicOut::CommitEvent);
+                        self.committed();
+                    }
+                    Err(e) => {
+                        log::error!("Commit {} failed: {}", self.state.target_version, e);
+                        self.retry(OtaLogicOut::CommitEvent);
+                    }
+                }
+            }
+            OtaLogicIn::RolledBack(result) => {
+                match result {
+                    Ok(version) => {
+                        self.succeeded(OtaLogicOut::RollbackEvent);
+                        self.hc.version_name = version;
+                        self.end_trial(UpdateOutcome::RolledBack);
+                        self.outputs.push_back(OtaLogicOut::RestartEvent);
                     }
+                    Err(e) => {
+                        log::error!("Rollback of {} failed: {}", self.state.target_version, e);
+                        self.retry(OtaLogicOut::RollbackEvent);
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_boot(&mut self, status: BootStatus) {
+        log::info!("Booted {:?}", status);
+        match status {
+            BootStatus::Normal { version } => {
+                if !version.is_empty() {
+                    self.hc.version_name = version;
+                }
+                // Committed before the state was saved
+                if self.state.phase == OtaPhase::Confirming {
+                    self.committed();
+                }
+            }
+            BootStatus::Trial { version, .. } => {
+                if self.state.phase != OtaPhase::Confirming {
+                    log::warn!("Trial of {} without a saved update", version);
+                    self.reset_state();
+                    self.state.phase = OtaPhase::Confirming;
+                    self.state.target_version = version.clone();
+                }
+                self.hc.version_name = version;
+                self.confirm_deadline = Some(self.timer.now_ms() + self.confirm_timeout.as_millis() as u64);
+                self.health.clear();
+                // Give the new version time to start before probing it
+                self.schedule_after(self.health_interval, OtaLogicOut::HealthCheckEvent);
+            }
+            BootStatus::RolledBack { version } => {
+                self.hc.version_name = version;
+                if self.state.phase == OtaPhase::Confirming {
+                    self.end_trial(UpdateOutcome::RolledBack);
                 }
             }
         }
     }
+
+    /// The new version proved to work.
+    fn committed(&mut self) {
+        log::info!("Update {} committed", self.state.target_version);
+        self.hc.version_name = self.state.target_version.clone();
+        if let Some(manifest) = &self.state.manifest {
+            self.security_counter = self.security_counter.max(manifest.version_number);
+        }
+        self.end_trial(UpdateOutcome::Committed);
+    }
+
+    /// Leaves the trial of the new version, queueing its outcome for the
+    /// server. A rolled back update is not tried again.
+    fn end_trial(&mut self, outcome: UpdateOutcome) {
+        let report = UpdateReport {
+            download_id: self.state.download_id,
+            version_name: self.state.target_version.clone(),
+            running_version: self.hc.version_name.clone(),
+            outcome,
+            probes: std::mem::take(&mut self.health),
+        };
+        if outcome == UpdateOutcome::RolledBack {
+            log::error!("Update {} rolled back to {}", report.version_name, report.running_version);
+            self.rejected_download = Some(self.state.download_id);
+        }
+        self.state = OtaState { report: Some(report), ..OtaState::default() };
+        self.confirm_deadline = None;
+        self.retries.clear();
+        self.outputs.push_back(OtaLogicOut::ReportEvent);
+    }
+
+    /// Starts over without an update, keeping a report not sent yet.
+    fn reset_state(&mut self) {
+        self.progress = None;
+        self.state = OtaState { report: self.state.report.take(), ..OtaState::default() };
+    }
+
+    /// Whether the update described by `manifest` may be installed on this
+    /// controller, and matches what the server offered.
+    fn check_manifest(&self, offer: &Data, manifest: &Manifest, now: u64) -> Result<(), OtaErr> {
+        if manifest.version_name != offer.version_name
+            || manifest.version_number != offer.version_number
+            || manifest.version_min_id != offer.version_min_id
+        {
+            log::error!("Manifest is for {}, server offered {}", manifest.version_name, offer.version_name);
+            return Err(OtaErr::ManifestErr);
+        }
+        if !manifest.hc_types.iter().any(|hc_type| hc_type.parse().as_ref() == Ok(&self.hc.hc_type)) {
+            log::error!("Update is for {:?}, not {}", manifest.hc_types, self.hc.hc_type.as_str());
+            return Err(OtaErr::IncompatibleErr);
+        }
+        if manifest.version_number < self.security_counter {
+            log::error!("Update {} is below security counter {}", manifest.version_number, self.security_counter);
+            return Err(OtaErr::RollbackErr);
+        }
+        check_expiry(manifest, now)
+    }
+
+    /// Whether the update is worth installing over the running version:
+    /// `Ok(false)` when already running it, an error when it may not be.
+    fn check_version(&self, manifest: &Manifest) -> Result<bool, OtaErr> {
+        let target = version::parse(&manifest.version_name)?;
+        let current = match version::parse(&self.hc.version_name) {
+            Ok(current) => current,
+            Err(_) if manifest.min_version.is_empty() => {
+                log::warn!("Running version {:?} unknown, installing {}", self.hc.version_name, target);
+                return Ok(true);
+            }
+            Err(e) => return Err(e),
+        };
+        if !manifest.min_version.is_empty() && current < version::parse(&manifest.min_version)? {
+            log::error!("Update needs at least {}, running {}", manifest.min_version, current);
+            return Err(OtaErr::IncompatibleErr);
+        }
+        match target.cmp(&current) {
+            std::cmp::Ordering::Greater => Ok(true),
+            std::cmp::Ordering::Equal => Ok(false),
+            std::cmp::Ordering::Less if manifest.allow_downgrade => {
+                log::warn!("Downgrading from {} to {}", current, target);
+                Ok(true)
+            }
+            std::cmp::Ordering::Less => {
+                log::error!("Refusing downgrade from {} to {}", current, target);
+                Err(OtaErr::RollbackErr)
+            }
+        }
+    }
+
+    /// Status of the running download, for the status topic.
+    pub fn status(&self) -> Option<OtaStatus> {
+        Some(OtaStatus {
+            download_id: self.state.download_id,
+            version_name: self.state.target_version.clone(),
+            phase: self.state.phase,
+            progress: self.progress.clone()?,
+        })
+    }
+
+    /// Whether a maintenance window is open now, by the logic's clock.
+    pub fn in_window(&mut self) -> bool {
+        let now = Utc.timestamp_millis_opt(self.timer.now_ms() as i64).single().unwrap_or_default();
+        self.schedule.is_open(now)
+    }
+
+    /// Patch from the running version, when the update offers one.
+    pub fn delta(&self) -> Option<&Delta> {
+        let manifest = self.state.manifest.as_ref()?;
+        manifest.deltas.iter().find(|delta| delta.base_version == self.hc.version_name)
+    }
+
+    /// Drops the current update for good.
+    fn reject(&mut self, e: OtaError) {
+        log::error!("Rejecting update {}: {}", self.state.target_version, e);
+        self.rejected_download = Some(self.state.download_id);
+        self.reset_state();
+    }
+
+    /// Reacts to a failed step according to the kind of error.
+    fn on_error(&mut self, e: OtaError) {
+        log::warn!("Update step failed: {}", e);
+        match e.kind {
+            OtaErr::DownloadErr | OtaErr::LinkErr |  OtaErr::NoLinkResErr | OtaErr::ServerNoReturnErr => {
+                // The link may be stale, ask the server again from scratch
+                self.state.phase = OtaPhase::Idle;
+                self.retry(OtaLogicOut::CheckOtaEvent);
+            }
+            OtaErr::NotEnoughMemoryErr | OtaErr::QuiesceErr if self.state.phase == OtaPhase::Suspending => {
+                // Still verified, the retry goes through `start_update` again
+                self.state.phase = OtaPhase::Verified;
+                self.retry(OtaLogicOut::SuppentEvent);
+            }
+            OtaErr::NoSpaceErr => {
+                // Space may be freed in the meantime, nothing was downloaded
+                log::error!("No space for update {}", self.state.target_version);
+                self.retry(OtaLogicOut::GetLinkEvent);
+            }
+            OtaErr::ChecksumErr => {
+                if self.state.checksum_failures < self.retry_policy.checksum_redownloads {
+                    self.state.checksum_failures += 1;
+                    log::warn!("Checksum mismatch, download again ({}/{})", self.state.checksum_failures, self.retry_policy.checksum_redownloads);
+                    self.emit(OtaLogicOut::GetLinkEvent);
+                } else {
+                    log::error!("Image for {} keeps failing its checksum", self.state.target_version);
+                    self.reject(e);
+                }
+            }
+            // Downloading it again would not add a signature
+            OtaErr::UnsignedErr => self.reject(e),
+            OtaErr::ReportErr => self.retry(OtaLogicOut::ReportEvent),
+            OtaErr::VerifyErr | OtaErr::VerifyNotEqualErr => {
+                // The image is unusable, resume with a fresh download
+                self.state.phase = OtaPhase::Downloading;
+                self.retry(OtaLogicOut::GetLinkEvent);
+            }
+
+            OtaErr::UserCalendarErr => {
+
+            }
+            _ => {
+
+            }
+        }
+    }
     pub fn pop_action(&mut self) -> Option<OtaLogicOut> {
         self.outputs.pop_front()
     }
 
 }
 
+/// Whether the update may still be installed at unix time `now`.
+fn check_expiry(manifest: &Manifest, now: u64) -> Result<(), OtaErr> {
+    match manifest.expires_at {
+        Some(expires_at) if now >= expires_at => {
+            log::error!("Update {} expired", manifest.version_name);
+            Err(OtaErr::ExpiredErr)
+        }
+        _ => Ok(()),
+    }
+}
+
 #[cfg(test)]
 mod test {
 
     use super::*;
-    use crate::transport::ResponseOtaHc;
+    use crate::transport::{Data, ResponseOtaHc};
+    use lumi_utils::timer::MockTimer;
+    use rand::SeedableRng;
+
+    fn logic_with(timer: &MockTimer, seed: u64) -> OtaLogic {
+        OtaLogic::new(
+            MaintenanceSchedule::default(),
+            RetryPolicy::default(),
+            Box::new(timer.clone()),
+            StdRng::seed_from_u64(seed),
+        )
+    }
 
     #[test]
 
     fn test_random_values() {
-        let mut unique_values = std::collections::HashSet::new();
+        let timer = MockTimer::new(1705301096152);
 
-        for _ in 0..10 {
-            let ota_logic = OtaLogic::new();
+        for seed in 0..10 {
+            let ota_logic = logic_with(&timer, seed);
 
-            // Check the number of elements in outputs
-            assert_eq!(ota_logic.outputs.len(), 0);
+            // The first check is queued right away
+            assert_eq!(ota_logic.outputs, VecDeque::from([OtaLogicOut::CheckOtaEvent]));
 
             // Check the range for rnd_check
             assert!(ota_logic.rnd_check >= 30 && ota_logic.rnd_check <= 50);
 
             // Check the range for rnd_update_ota
-            assert!(ota_logic.rnd_update_ota <= 120 && ota_logic.rnd_update_ota >= 1);
+            assert!(ota_logic.rnd_update_ota <= 120);
 
-            // Insert the random values into the HashSet
-            unique_values.insert((ota_logic.rnd_check, ota_logic.rnd_update_ota));
+            // The same seed always gives the same values
+            let again = logic_with(&timer, seed);
+            assert_eq!((again.rnd_check, again.rnd_update_ota), (ota_logic.rnd_check, ota_logic.rnd_update_ota));
         }
+    }
+
+    // 2024-01-14 19:00:00 UTC, 02:00 in the default UTC+7 window
+    const WINDOW_START_MS: u64 = 1705258800000;
+    const MINUTE_MS: u64 = 60 * 1000;
 
-        // If the size of the HashSet is equal to the number of iterations, it means all values were unique
-        assert_eq!(unique_values.len(), 5);
+    fn logic_at(timer: &MockTimer) -> OtaLogic {
+        let mut ota_logic = logic_with(timer, 7);
+        ota_logic.outputs.clear();
+        ota_logic
     }
-    
+
+    fn updates(ota_logic: &mut OtaLogic) -> usize {
+        ota_logic.outputs.drain(..).filter(|out| *out == OtaLogicOut::SuppentEvent).count()
+    }
+
     #[test]
-    fn test_compare_hour_ota() {
-        let mut ota_logic = OtaLogic::new();
+    fn test_maintenance_window() {
+        let timer = MockTimer::new(WINDOW_START_MS - 30 * MINUTE_MS);
+        let mut ota_logic = logic_at(&timer);
+        ota_logic.rnd_update_ota = 30;
+        ota_logic.state.phase = OtaPhase::Verified;
 
-        // Test case when update_ota is allowed
-        ota_logic.compare_hour_ota(2, 30, false);
-        assert_eq!(ota_logic.outputs.len(), 1);
-        assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::VerifyEvent));
+        // Before the window opens
+        ota_logic.on_tick();
+        assert_eq!(updates(&mut ota_logic), 0);
+        assert!(!ota_logic.in_window());
 
-        // Test case when update_ota is not allowed, should not push VerifyEvent
-        ota_logic.compare_hour_ota(4, 30, false);
-        assert_eq!(ota_logic.outputs.len(), 0);
+        // Window open, but the random slot is not reached yet
+        timer.set(WINDOW_START_MS + 29 * MINUTE_MS);
+        ota_logic.on_tick();
+        assert_eq!(updates(&mut ota_logic), 0);
+        assert!(ota_logic.in_window());
 
-        ota_logic.compare_hour_ota(1, 59, false);
-        assert_eq!(ota_logic.outputs.len(), 0);
+        timer.advance(MINUTE_MS);
+        ota_logic.on_tick();
+        assert_eq!(updates(&mut ota_logic), 1);
 
-        ota_logic.compare_hour_ota(4, 01, false);
-        assert_eq!(ota_logic.outputs.len(), 0);
+        // Only once per window
+        ota_logic.state.phase = OtaPhase::Verified;
+        timer.advance(100);
+        ota_logic.on_tick();
+        timer.advance(60 * MINUTE_MS);
+        ota_logic.on_tick();
+        assert_eq!(updates(&mut ota_logic), 0);
 
+        // Next night, nothing to install until an update is verified, and a
+        // slot past the window end is clamped into it
+        ota_logic.state.phase = OtaPhase::Idle;
+        ota_logic.rnd_update_ota = 500;
+        timer.set(WINDOW_START_MS + 24 * 60 * MINUTE_MS + 118 * MINUTE_MS);
+        ota_logic.on_tick();
+        assert_eq!(updates(&mut ota_logic), 0);
+        timer.advance(MINUTE_MS);
+        ota_logic.on_tick();
+        assert_eq!(updates(&mut ota_logic), 0);
+        assert_eq!(ota_logic.state.phase, OtaPhase::Idle);
+        ota_logic.state.phase = OtaPhase::Verified;
+        timer.advance(100);
+        ota_logic.on_tick();
+        assert_eq!(updates(&mut ota_logic), 1);
+    }
 
-        ota_logic.compare_hour_ota(1, 30, true);
-        assert_eq!(ota_logic.outputs.len(), 1);
-        assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::VerifyEvent));
+    #[test]
+    fn test_maintenance_window_from_config() {
+        let schedule: MaintenanceSchedule = toml::from_str(r#"
+            timezone = "UTC"
+            jitter_minutes = [0, 0]
+
+            [[windows]]
+            days = ["Sat"]
+            start = "10:00"
+            end = "11:00"
+        "#).unwrap();
+        // Saturday 2024-01-13 10:00 UTC
+        let timer = MockTimer::new(1705140000000);
+        let mut ota_logic = logic_at(&timer);
+        ota_logic.schedule = schedule;
+        ota_logic.rnd_update_ota = 0;
+        ota_logic.state.phase = OtaPhase::Verified;
+
+        // Sunday
+        timer.advance(24 * 60 * MINUTE_MS);
+        ota_logic.on_tick();
+        assert_eq!(updates(&mut ota_logic), 0);
+
+        // Next Saturday
+        timer.advance(6 * 24 * 60 * MINUTE_MS);
+        ota_logic.on_tick();
+        assert_eq!(updates(&mut ota_logic), 1);
     }
 
-    
     #[test]
     fn test_on_tick() {
-        let mut ota_logic = OtaLogic::new();
-        ota_logic.on_tick(1705301096152);
-        ota_logic.on_tick(1705301197152);
+        // 2024-01-15 13:44 UTC+7, outside the maintenance window
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+
+        timer.advance(59_000);
+        ota_logic.on_tick();
         assert_eq!(ota_logic.outputs.len(), 0);
 
-        ota_logic.on_tick(1705301096152);
-        ota_logic.on_tick(1705301196152);
+        timer.advance(1_000);
+        ota_logic.on_tick();
         assert_eq!(ota_logic.outputs.len(), 2);
         assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::CheckOtaEvent));
         assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::KeepAliveEvent));
 
+        // Nothing to install yet
+        ota_logic.hc.allow_ota = true;
+        timer.advance(100);
+        ota_logic.on_tick();
+        assert_eq!(ota_logic.outputs.len(), 0);
+        assert!(!ota_logic.hc.allow_ota);
+
+        ota_logic.state.phase = OtaPhase::Verified;
         ota_logic.hc.allow_ota = true;
-        ota_logic.on_tick(1705301196152);
+        timer.advance(100);
+        ota_logic.on_tick();
         assert_eq!(ota_logic.outputs.len(), 1);
         assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::SuppentEvent));
+        assert!(!ota_logic.hc.allow_ota);
+    }
 
+    #[test]
+    fn test_checks_pause_while_broker_down() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+        ota_logic.outputs.clear();
 
+        ota_logic.on_event(OtaLogicIn::BrokerConnected(false));
+        timer.advance(5 * 60_000);
+        ota_logic.on_tick();
+        assert_eq!(ota_logic.outputs.len(), 0);
+
+        // Resumes right away, the next periodic check a minute later
+        ota_logic.on_event(OtaLogicIn::BrokerConnected(true));
+        assert_eq!(ota_logic.outputs.drain(..).collect::<Vec<_>>(), vec![OtaLogicOut::CheckOtaEvent, OtaLogicOut::KeepAliveEvent]);
+        ota_logic.on_event(OtaLogicIn::BrokerConnected(true));
+        timer.advance(59_000);
+        ota_logic.on_tick();
+        assert_eq!(ota_logic.outputs.len(), 0);
+        timer.advance(1_000);
+        ota_logic.on_tick();
+        assert_eq!(ota_logic.outputs.len(), 2);
+    }
+
+    /// Feeds `err` and returns the retry it schedules, firing it with the clock.
+    fn retry(ota_logic: &mut OtaLogic, timer: &MockTimer, err: OtaErr) -> Option<OtaLogicOut> {
+        ota_logic.on_event(OtaLogicIn::Transport(Err(err.into())));
+        // Nothing happens until the delay has passed
+        assert_eq!(ota_logic.outputs.len(), 0);
+        let at = ota_logic.next_scheduled()?;
+        timer.set(at - 1);
+        ota_logic.on_tick();
+        ota_logic.outputs.retain(|out| *out != OtaLogicOut::CheckOtaEvent && *out != OtaLogicOut::KeepAliveEvent);
+        assert_eq!(ota_logic.outputs.len(), 0);
+
+        timer.set(at);
+        ota_logic.on_tick();
+        let out = ota_logic.outputs.pop_front();
+        ota_logic.outputs.clear();
+        assert!(ota_logic.scheduled.is_empty());
+        out
     }
     
     #[test]
     fn test_on_event() {
-        let mut ota_logic = OtaLogic::new();
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_with(&timer, 1);
 
         //check ota
-        ota_logic.outputs.push_back(OtaLogicOut::CheckOtaEvent);
         assert_eq!(ota_logic.outputs.len(), 1);
         assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::CheckOtaEvent));
 
         //check response
-        let res = ResponseOtaHc::default();
-        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseRequest(res))));
+        let res = response(1);
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseRequest(res.clone()))));
         assert_eq!(ota_logic.outputs.len(), 1);
-        assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::CompareVersionEvent));
+        assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::ManifestEvent));
 
-        // check keep alive
-        ota_logic.outputs.push_back(OtaLogicOut::KeepAliveEvent);
+        // manifest checked, compare version
+        ota_logic.on_event(OtaLogicIn::Manifest(Ok(manifest_for(&res.data))));
         assert_eq!(ota_logic.outputs.len(), 1);
+        assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::CompareVersionEvent));
+
+        // version differs, get link
+        ota_logic.on_event(OtaLogicIn::RunningVersion(Ok("1.0.1".to_string())));
+        assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::GetLinkEvent));
 
         // check reponsselink và veriy
         ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseLink)));
@@ -280,35 +989,668 @@ mod test {
         assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::VerifyEvent));
 
         // check err 
-        ota_logic.on_event(OtaLogicIn::Transport(Err(OtaErr::DownloadErr)));
-        assert_eq!(ota_logic.outputs.len(), 1);
-        assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::CheckOtaEvent));
+        assert_eq!(retry(&mut ota_logic, &timer, OtaErr::DownloadErr), Some(OtaLogicOut::CheckOtaEvent));
+        assert_eq!(retry(&mut ota_logic, &timer, OtaErr::LinkErr), Some(OtaLogicOut::CheckOtaEvent));
+        assert_eq!(retry(&mut ota_logic, &timer, OtaErr::NoLinkResErr), Some(OtaLogicOut::CheckOtaEvent));
+        assert_eq!(retry(&mut ota_logic, &timer, OtaErr::ServerNoReturnErr), Some(OtaLogicOut::CheckOtaEvent));
+        assert_eq!(retry(&mut ota_logic, &timer, OtaErr::VerifyErr), Some(OtaLogicOut::GetLinkEvent));
+        assert_eq!(retry(&mut ota_logic, &timer, OtaErr::NoSpaceErr), Some(OtaLogicOut::GetLinkEvent));
+        assert_eq!(retry(&mut ota_logic, &timer, OtaErr::UserCalendarErr), None);
+        // Quiescing is only retried while a verified update waits for it
+        assert_eq!(retry(&mut ota_logic, &timer, OtaErr::NotEnoughMemoryErr), None);
+        ota_logic.state.phase = OtaPhase::Suspending;
+        assert_eq!(retry(&mut ota_logic, &timer, OtaErr::NotEnoughMemoryErr), Some(OtaLogicOut::SuppentEvent));
+        assert_eq!(retry(&mut ota_logic, &timer, OtaErr::QuiesceErr), Some(OtaLogicOut::SuppentEvent));
 
-        ota_logic.on_event(OtaLogicIn::Transport(Err(OtaErr::LinkErr)));
+        // check suppend and update
+        ota_logic.on_event(OtaLogicIn::Quiesced(Ok(())));
         assert_eq!(ota_logic.outputs.len(), 1);
-        assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::CheckOtaEvent));
+        assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::UpdateOtaEvent(ota_logic.hc.hc_type.clone())));
+        
+    }
 
-        ota_logic.on_event(OtaLogicIn::Transport(Err(OtaErr::NoLinkResErr)));
-        assert_eq!(ota_logic.outputs.len(), 1);
-        assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::CheckOtaEvent));
+    fn no_jitter() -> RetryPolicy {
+        RetryPolicy {
+            initial_delay_ms: 1_000,
+            multiplier: 2.0,
+            max_delay_ms: 5_000,
+            jitter: 0.0,
+            max_attempts: 5,
+            reset_on_success: true,
+            checksum_redownloads: 2,
+        }
+    }
 
-        ota_logic.on_event(OtaLogicIn::Transport(Err(OtaErr::ServerNoReturnErr)));
-        assert_eq!(ota_logic.outputs.len(), 1);
-        assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::CheckOtaEvent));
+    #[test]
+    fn test_retry_does_not_block() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+        ota_logic.retry_policy = no_jitter();
 
-        ota_logic.on_event(OtaLogicIn::Transport(Err(OtaErr::NotEnoughMemoryErr)));
-        assert_eq!(ota_logic.outputs.len(), 1);
-        assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::SuppentEvent));
+        let started = std::time::Instant::now();
+        ota_logic.on_event(OtaLogicIn::Transport(Err(OtaErr::DownloadErr.into())));
+        ota_logic.on_event(OtaLogicIn::Transport(Err(OtaErr::VerifyErr.into())));
+        ota_logic.on_event(OtaLogicIn::Transport(Err(OtaErr::LinkErr.into())));
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
 
-        ota_logic.on_event(OtaLogicIn::Transport(Err(OtaErr::VerifyErr)));
-        assert_eq!(ota_logic.outputs.len(), 1);
-        assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::GetLinkEvent));
+        // Each step backs off on its own
+        assert_eq!(ota_logic.scheduled, vec![
+            (1705301096152 + 1_000, OtaLogicOut::CheckOtaEvent),
+            (1705301096152 + 1_000, OtaLogicOut::GetLinkEvent),
+            (1705301096152 + 2_000, OtaLogicOut::CheckOtaEvent),
+        ]);
+    }
 
+    #[test]
+    fn test_write_failure_while_downloading() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+        ota_logic.retry_policy = no_jitter();
 
-        // check suppend and update
-        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseSuppend)));
-        assert_eq!(ota_logic.outputs.len(), 1);
-        assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::UpdateOtaEvent(ota_logic.hc.hc_type)));
-        
+        for kind in [OtaErr::DownloadErr, OtaErr::NoSpaceErr] {
+            ota_logic.state.phase = OtaPhase::Downloading;
+            let io = std::io::Error::other("write failed");
+            let e = OtaError::io(kind, std::path::Path::new("/data/update_ota.bin.part"), io);
+            ota_logic.on_event(OtaLogicIn::Transport(Err(e)));
+            let at = ota_logic.next_scheduled().unwrap();
+            timer.set(at);
+            ota_logic.on_tick();
+            // Never a partial image installed
+            assert!(!ota_logic.outputs.contains(&OtaLogicOut::SuppentEvent));
+            assert_ne!(ota_logic.state.phase, OtaPhase::Suspending);
+            ota_logic.outputs.clear();
+        }
+    }
+
+    #[test]
+    fn test_retry_backoff_is_capped_and_gives_up() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+        ota_logic.retry_policy = no_jitter();
+
+        let mut delays = Vec::new();
+        ota_logic.state.phase = OtaPhase::Suspending;
+        for _ in 0..5 {
+            let before = ota_logic.timer.now_ms();
+            assert_eq!(retry(&mut ota_logic, &timer, OtaErr::NotEnoughMemoryErr), Some(OtaLogicOut::SuppentEvent));
+            delays.push(ota_logic.timer.now_ms() - before);
+        }
+        assert_eq!(delays, vec![1_000, 2_000, 4_000, 5_000, 5_000]);
+
+        // Exhausted, nothing more is scheduled and the next failure starts over
+        assert_eq!(retry(&mut ota_logic, &timer, OtaErr::NotEnoughMemoryErr), None);
+        assert_eq!(ota_logic.state.phase, OtaPhase::Verified);
+        ota_logic.state.phase = OtaPhase::Suspending;
+        ota_logic.on_event(OtaLogicIn::Transport(Err(OtaErr::NotEnoughMemoryErr.into())));
+        assert_eq!(ota_logic.next_scheduled(), Some(ota_logic.timer.now_ms() + 1_000));
+    }
+
+    #[test]
+    fn test_quiesce_retry_needs_verified_update() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+        ota_logic.retry_policy = no_jitter();
+        ota_logic.state.phase = OtaPhase::Suspending;
+        ota_logic.state.manifest = Some(manifest_for(&response(1).data));
+
+        // The update is dropped while the retry waits
+        ota_logic.on_event(OtaLogicIn::Quiesced(Err(OtaErr::QuiesceErr.into())));
+        ota_logic.reset_state();
+        timer.set(ota_logic.next_scheduled().unwrap());
+        ota_logic.on_tick();
+        assert!(!ota_logic.outputs.contains(&OtaLogicOut::SuppentEvent));
+        assert_eq!(ota_logic.state.phase, OtaPhase::Idle);
+
+        // Quiesced by a stale request, nothing to install
+        ota_logic.outputs.clear();
+        ota_logic.on_event(OtaLogicIn::Quiesced(Ok(())));
+        assert!(ota_logic.outputs.is_empty());
+        ota_logic.state.phase = OtaPhase::Suspending;
+        ota_logic.on_event(OtaLogicIn::Quiesced(Ok(())));
+        assert!(ota_logic.outputs.is_empty());
+    }
+
+    #[test]
+    fn test_retry_resets_on_success() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+        ota_logic.retry_policy = no_jitter();
+
+        retry(&mut ota_logic, &timer, OtaErr::VerifyErr);
+        retry(&mut ota_logic, &timer, OtaErr::VerifyErr);
+        assert_eq!(ota_logic.retries[&OtaLogicOut::GetLinkEvent].attempts, 2);
+
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseLink)));
+        assert!(!ota_logic.retries.contains_key(&OtaLogicOut::GetLinkEvent));
+
+        ota_logic.outputs.clear();
+        ota_logic.retry_policy.reset_on_success = false;
+        retry(&mut ota_logic, &timer, OtaErr::VerifyErr);
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseLink)));
+        assert_eq!(ota_logic.retries[&OtaLogicOut::GetLinkEvent].attempts, 1);
+    }
+
+    fn response(download_id: u64) -> ResponseOtaHc {
+        ResponseOtaHc {
+            success: true,
+            data: Data {
+                download_id,
+                version_name: "1.0.2".to_string(),
+                link: "http://localhost/update_ota.bin".to_string(),
+                ..Data::default()
+            },
+            ..ResponseOtaHc::default()
+        }
+    }
+
+    fn manifest_for(data: &Data) -> Manifest {
+        Manifest {
+            version_name: data.version_name.clone(),
+            version_number: data.version_number,
+            version_min_id: data.version_min_id,
+            hc_types: vec!["Hc01".to_string()],
+            size: 1024,
+            sha256: "ab".repeat(32),
+            sha512: "cd".repeat(64),
+            ..Manifest::default()
+        }
+    }
+
+    /// Offers update `download_id` with a valid manifest.
+    fn accept(ota_logic: &mut OtaLogic, download_id: u64) {
+        let response = response(download_id);
+        let manifest = manifest_for(&response.data);
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseRequest(response))));
+        ota_logic.on_event(OtaLogicIn::Manifest(Ok(manifest)));
+    }
+
+    #[test]
+    fn test_phase_follows_flow() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+        assert_eq!(ota_logic.state.phase, OtaPhase::Idle);
+
+        accept(&mut ota_logic, 7);
+        assert_eq!(ota_logic.state.target_version, "1.0.2");
+        assert_eq!(ota_logic.state.download_id, 7);
+        assert_eq!(ota_logic.state.checksum, format!("sha256:{}", "ab".repeat(32)));
+
+        ota_logic.on_event(OtaLogicIn::RunningVersion(Ok("1.0.1".to_string())));
+        assert_eq!(ota_logic.state.phase, OtaPhase::Downloading);
+
+        // A periodic check for the same update does not restart the download
+        ota_logic.outputs.clear();
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseRequest(response(7)))));
+        assert_eq!(ota_logic.outputs.len(), 0);
+        assert_eq!(ota_logic.state.phase, OtaPhase::Downloading);
+
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseLink)));
+        assert_eq!(ota_logic.state.phase, OtaPhase::Verifying);
+        ota_logic.on_event(OtaLogicIn::Verify(Ok(())));
+        assert_eq!(ota_logic.state.phase, OtaPhase::Verified);
+
+        ota_logic.hc.allow_ota = true;
+        ota_logic.on_tick();
+        assert_eq!(ota_logic.state.phase, OtaPhase::Suspending);
+        ota_logic.on_event(OtaLogicIn::Quiesced(Ok(())));
+        assert_eq!(ota_logic.state.phase, OtaPhase::Installing);
+
+        ota_logic.on_event(OtaLogicIn::Installed(Ok(())));
+        assert_eq!(ota_logic.state.phase, OtaPhase::Confirming);
+        assert_eq!(ota_logic.outputs.back(), Some(&OtaLogicOut::RestartEvent));
+
+        let saved = ota_logic.snapshot();
+        let mut ota_logic = logic_at(&timer);
+        ota_logic.restore(saved);
+        ota_logic.on_event(OtaLogicIn::Boot(BootStatus::Trial { version: "1.0.2".to_string(), attempts: 1 }));
+        ota_logic.on_event(OtaLogicIn::Health(vec![healthy()]));
+        assert_eq!(ota_logic.outputs, VecDeque::from([OtaLogicOut::CommitEvent]));
+        ota_logic.on_event(OtaLogicIn::Committed(Ok(())));
+        assert_eq!(ota_logic.state.phase, OtaPhase::Idle);
+        assert_eq!(ota_logic.hc.version_name, "1.0.2");
+
+        let report = ota_logic.state.report.clone().unwrap();
+        assert_eq!(report.outcome, UpdateOutcome::Committed);
+        assert_eq!((report.download_id, report.running_version.as_str()), (7, "1.0.2"));
+        assert_eq!(report.probes, vec![healthy()]);
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseReport)));
+        assert_eq!(ota_logic.state, OtaState::default());
+    }
+
+    fn healthy() -> ProbeResult {
+        ProbeResult { probe: "process master-service".to_string(), passed: true, detail: String::new() }
+    }
+
+    #[test]
+    fn test_restore_resumes_phase() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+        ota_logic.retry_policy = no_jitter();
+        accept(&mut ota_logic, 7);
+        ota_logic.on_event(OtaLogicIn::RunningVersion(Ok("1.0.1".to_string())));
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseLink)));
+        ota_logic.on_event(OtaLogicIn::Verify(Err(OtaErr::VerifyNotEqualErr.into())));
+        let saved = ota_logic.snapshot();
+        assert_eq!(saved.retries, vec![(OtaLogicOut::GetLinkEvent, 1)]);
+
+        // Rebooted while the download was being retried
+        let mut restored = logic_with(&timer, 3);
+        restored.retry_policy = no_jitter();
+        restored.restore(saved.clone());
+        assert_eq!(restored.outputs, VecDeque::from([OtaLogicOut::GetLinkEvent]));
+        assert_eq!(restored.state.link, "http://localhost/update_ota.bin");
+        assert_eq!(restored.snapshot(), saved);
+
+        // Retry counters carry on where they were
+        restored.outputs.clear();
+        restored.on_event(OtaLogicIn::Verify(Err(OtaErr::VerifyErr.into())));
+        assert_eq!(restored.next_scheduled(), Some(1705301096152 + 2_000));
+
+        // A verified image waits for the maintenance window instead
+        let mut restored = logic_with(&timer, 3);
+        restored.restore(OtaState { phase: OtaPhase::Verified, ..saved.clone() });
+        assert_eq!(restored.outputs.len(), 0);
+
+        let mut restored = logic_with(&timer, 3);
+        restored.restore(OtaState { phase: OtaPhase::Installing, ..saved });
+        assert_eq!(restored.outputs, VecDeque::from([OtaLogicOut::UpdateOtaEvent(HcType::Hc01)]));
+
+        let mut restored = logic_with(&timer, 3);
+        restored.restore(OtaState::default());
+        assert_eq!(restored.outputs, VecDeque::from([OtaLogicOut::CheckOtaEvent]));
+    }
+
+    #[test]
+    fn test_checksum_mismatch_redownloads_then_rejects() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+        accept(&mut ota_logic, 7);
+        ota_logic.on_event(OtaLogicIn::RunningVersion(Ok("1.0.1".to_string())));
+        ota_logic.outputs.clear();
+
+        for attempt in 1..=2 {
+            ota_logic.on_event(OtaLogicIn::Transport(Err(OtaErr::ChecksumErr.into())));
+            assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::GetLinkEvent));
+            assert_eq!(ota_logic.state.checksum_failures, attempt);
+            assert_eq!(ota_logic.state.phase, OtaPhase::Downloading);
+        }
+
+        ota_logic.on_event(OtaLogicIn::Transport(Err(OtaErr::ChecksumErr.into())));
+        assert_eq!(ota_logic.outputs.len(), 0);
+        assert!(ota_logic.scheduled.is_empty());
+        assert_eq!(ota_logic.state, OtaState::default());
+
+        // The same update is not downloaded again, a newer one is
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseRequest(response(7)))));
+        assert_eq!(ota_logic.outputs.len(), 0);
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseRequest(response(8)))));
+        assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::ManifestEvent));
+    }
+
+    #[test]
+    fn test_unsigned_image_is_rejected() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+        accept(&mut ota_logic, 7);
+        ota_logic.on_event(OtaLogicIn::RunningVersion(Ok("1.0.1".to_string())));
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseLink)));
+        assert_eq!(ota_logic.state.phase, OtaPhase::Verifying);
+        ota_logic.outputs.clear();
+
+        ota_logic.on_event(OtaLogicIn::Verify(Err(OtaErr::UnsignedErr.into())));
+        assert_eq!(ota_logic.outputs.len(), 0);
+        assert!(ota_logic.scheduled.is_empty());
+        assert_eq!(ota_logic.state, OtaState::default());
+
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseRequest(response(7)))));
+        assert_eq!(ota_logic.outputs.len(), 0);
+    }
+
+    #[test]
+    fn test_hc_type_names() {
+        for hc_type in [HcType::Hc01, HcType::Hc02] {
+            assert_eq!(hc_type.as_str().parse(), Ok(hc_type.clone()));
+            // Manifests and the config spell them the same way
+            assert_eq!(serde_json::to_string(&hc_type).unwrap(), format!("\"{}\"", hc_type.as_str()));
+        }
+        assert_eq!("hc01".parse::<HcType>(), Err(OtaErr::IncompatibleErr));
+    }
+
+    #[test]
+    fn test_manifest_rules() {
+        let timer = MockTimer::new(1705301096152);
+        let now = 1705301096;
+        let mut ota_logic = logic_at(&timer);
+        ota_logic.hc.version_name = "1.0.1".to_string();
+        ota_logic.outputs.clear();
+
+        let offer = |ota_logic: &mut OtaLogic, download_id: u64, change: &dyn Fn(&mut Manifest)| {
+            let response = response(download_id);
+            let mut manifest = manifest_for(&response.data);
+            change(&mut manifest);
+            ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseRequest(response))));
+            assert_eq!(ota_logic.outputs.pop_front(), Some(OtaLogicOut::ManifestEvent));
+            ota_logic.on_event(OtaLogicIn::Manifest(Ok(manifest)));
+            let accepted = ota_logic.outputs.pop_front() == Some(OtaLogicOut::CompareVersionEvent);
+            assert_eq!(accepted, ota_logic.state.manifest.is_some());
+            assert_eq!(accepted, ota_logic.rejected_download != Some(download_id));
+            ota_logic.state = OtaState::default();
+            accepted
+        };
+
+        assert!(offer(&mut ota_logic, 1, &|_| {}));
+        assert!(!offer(&mut ota_logic, 2, &|m| m.hc_types = vec!["Hc02".to_string()]));
+        assert!(offer(&mut ota_logic, 8, &|m| m.hc_types = vec!["Hc02".to_string(), "Hc01".to_string()]));
+        assert!(!offer(&mut ota_logic, 3, &|m| m.version_name = "9.9.9".to_string()));
+        // Below the security counter, a signed downgrade flag does not help
+        ota_logic.security_counter = 2;
+        assert!(!offer(&mut ota_logic, 4, &|m| { m.version_number = 1; m.allow_downgrade = true; }));
+        ota_logic.security_counter = 0;
+        assert!(!offer(&mut ota_logic, 5, &|m| m.version_number = 1));
+        assert!(offer(&mut ota_logic, 6, &|m| m.expires_at = Some(now + 1)));
+        assert!(!offer(&mut ota_logic, 7, &|m| m.expires_at = Some(now)));
+
+        // Bad signature, or no manifest at all
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseRequest(response(8)))));
+        ota_logic.on_event(OtaLogicIn::Manifest(Err(OtaErr::ManifestErr.into())));
+        assert_eq!(ota_logic.outputs, VecDeque::from([OtaLogicOut::ManifestEvent]));
+        assert_eq!(ota_logic.rejected_download, Some(8));
+
+        // Nothing is downloaded before a manifest is accepted
+        ota_logic.outputs.clear();
+        ota_logic.on_event(OtaLogicIn::Push(OtaLogicOut::GetLinkEvent));
+        ota_logic.on_event(OtaLogicIn::RunningVersion(Ok("1.0.1".to_string())));
+        assert_eq!(ota_logic.outputs.len(), 0);
+    }
+
+    #[test]
+    fn test_expired_update_is_not_installed() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+        let response = response(7);
+        let manifest = Manifest { expires_at: Some(1705301096 + 60), ..manifest_for(&response.data) };
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseRequest(response))));
+        ota_logic.on_event(OtaLogicIn::Manifest(Ok(manifest)));
+        ota_logic.on_event(OtaLogicIn::RunningVersion(Ok("1.0.1".to_string())));
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseLink)));
+        ota_logic.on_event(OtaLogicIn::Verify(Ok(())));
+        ota_logic.outputs.clear();
+
+        timer.advance(60_000);
+        ota_logic.hc.allow_ota = true;
+        ota_logic.on_tick();
+        assert!(!ota_logic.outputs.contains(&OtaLogicOut::SuppentEvent));
+        assert_eq!(ota_logic.state, OtaState::default());
+        assert_eq!(ota_logic.rejected_download, Some(7));
+    }
+
+    #[test]
+    fn test_version_rules() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+
+        // Outputs after offering `version` while running `running`
+        let compare = |ota_logic: &mut OtaLogic, download_id: u64, running: &str, change: &dyn Fn(&mut Manifest)| {
+            let mut response = response(download_id);
+            response.data.version_name = "1.10.0".to_string();
+            let mut manifest = manifest_for(&response.data);
+            change(&mut manifest);
+            ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseRequest(response))));
+            ota_logic.on_event(OtaLogicIn::Manifest(Ok(manifest)));
+            ota_logic.outputs.clear();
+            ota_logic.on_event(OtaLogicIn::RunningVersion(Ok(running.to_string())));
+            let out = ota_logic.outputs.pop_front();
+            let phase = ota_logic.state.phase;
+            ota_logic.state = OtaState::default();
+            (out, phase)
+        };
+        let download = (Some(OtaLogicOut::GetLinkEvent), OtaPhase::Downloading);
+        let nothing = (None, OtaPhase::Idle);
+
+        // Ordered as versions, not as strings, whitespace from the file ignored
+        assert_eq!(compare(&mut ota_logic, 1, "1.9.3\n", &|_| {}), download);
+        assert_eq!(compare(&mut ota_logic, 2, "1.10.0\n", &|_| {}), nothing);
+        assert_ne!(ota_logic.rejected_download, Some(2));
+
+        // Downgrades only with the signed flag
+        assert_eq!(compare(&mut ota_logic, 3, "2.0.0", &|_| {}), nothing);
+        assert_eq!(ota_logic.rejected_download, Some(3));
+        assert_eq!(compare(&mut ota_logic, 4, "2.0.0", &|m| m.allow_downgrade = true), download);
+
+        assert_eq!(compare(&mut ota_logic, 5, "1.9.0", &|m| m.min_version = "1.9.0".to_string()), download);
+        assert_eq!(compare(&mut ota_logic, 6, "1.8.9", &|m| m.min_version = "1.9.0".to_string()), nothing);
+        assert_eq!(ota_logic.rejected_download, Some(6));
+
+        // Unknown running version, fine unless a minimum is required
+        assert_eq!(compare(&mut ota_logic, 7, "", &|_| {}), download);
+        assert_eq!(compare(&mut ota_logic, 8, "", &|m| m.min_version = "1.0.0".to_string()), nothing);
+    }
+
+    #[test]
+    fn test_download_progress_status() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+        let progress = Progress { bytes: 512, total: Some(1024), rate: 256, eta_s: Some(2), ..Progress::default() };
+        ota_logic.outputs.clear();
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::Progress(progress.clone()))));
+        assert_eq!(ota_logic.outputs.len(), 0);
+        assert_eq!(ota_logic.status(), None);
+
+        accept(&mut ota_logic, 7);
+        ota_logic.on_event(OtaLogicIn::RunningVersion(Ok("1.0.1".to_string())));
+        ota_logic.outputs.clear();
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::Progress(progress.clone()))));
+        assert_eq!(ota_logic.pop_action(), Some(OtaLogicOut::ProgressEvent));
+        let status = ota_logic.status().unwrap();
+        assert_eq!((status.download_id, status.version_name.as_str()), (7, "1.0.2"));
+        assert_eq!((status.phase, status.progress), (OtaPhase::Downloading, progress));
+    }
+
+    #[test]
+    fn test_delta_from_running_version() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+        let response = response(7);
+        let delta = Delta { base_version: "1.0.1".to_string(), patch: "firmware-1.0.1-1.0.2.patch".to_string(), ..Delta::default() };
+        let manifest = Manifest { deltas: vec![Delta { base_version: "1.0.0".to_string(), ..delta.clone() }, delta.clone()], ..manifest_for(&response.data) };
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseRequest(response))));
+        ota_logic.on_event(OtaLogicIn::Manifest(Ok(manifest)));
+
+        ota_logic.hc.version_name = "1.0.1".to_string();
+        assert_eq!(ota_logic.delta(), Some(&delta));
+        ota_logic.hc.version_name = "0.9.0".to_string();
+        assert_eq!(ota_logic.delta(), None);
+    }
+
+    #[test]
+    fn test_install_raises_security_counter() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+        let mut response = response(7);
+        response.data.version_number = 5;
+        response.data.version_min_id = 4;
+        let manifest = manifest_for(&response.data);
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseRequest(response))));
+        ota_logic.on_event(OtaLogicIn::Manifest(Ok(manifest)));
+        ota_logic.on_event(OtaLogicIn::RunningVersion(Ok("1.0.1".to_string())));
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseLink)));
+        ota_logic.on_event(OtaLogicIn::Verify(Ok(())));
+        ota_logic.hc.allow_ota = true;
+        ota_logic.on_tick();
+        ota_logic.on_event(OtaLogicIn::Quiesced(Ok(())));
+        ota_logic.on_event(OtaLogicIn::Installed(Ok(())));
+        ota_logic.on_event(OtaLogicIn::Boot(BootStatus::Trial { version: "1.0.2".to_string(), attempts: 1 }));
+        // Not before the new version is kept
+        assert_eq!(ota_logic.security_counter, 0);
+
+        ota_logic.on_event(OtaLogicIn::Committed(Ok(())));
+        assert_eq!(ota_logic.security_counter, 5);
+        assert_eq!(ota_logic.hc.version_name, "1.0.2");
+
+        // Compared with what was installed, whatever floor the offer claims
+        ota_logic.state = OtaState::default();
+        ota_logic.outputs.clear();
+        let mut older = self::response(8);
+        older.data.version_number = 4;
+        older.data.version_min_id = 0;
+        let manifest = manifest_for(&older.data);
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseRequest(older))));
+        ota_logic.on_event(OtaLogicIn::Manifest(Ok(manifest)));
+        assert_eq!(ota_logic.rejected_download, Some(8));
+        assert!(!ota_logic.outputs.contains(&OtaLogicOut::CompareVersionEvent));
+    }
+
+    #[test]
+    fn test_health_probes_decide_commit() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+        ota_logic.confirm_timeout = Duration::from_secs(60);
+        install(&mut ota_logic);
+        ota_logic.on_event(OtaLogicIn::Boot(BootStatus::Trial { version: "1.0.2".to_string(), attempts: 1 }));
+
+        // First round once the new version had time to start
+        timer.advance(9_000);
+        ota_logic.on_tick();
+        assert!(!ota_logic.outputs.contains(&OtaLogicOut::HealthCheckEvent));
+        timer.advance(1_000);
+        ota_logic.on_tick();
+        assert!(ota_logic.outputs.contains(&OtaLogicOut::HealthCheckEvent));
+        ota_logic.outputs.clear();
+
+        // A failing probe is tried again, nothing is committed
+        let failed = ProbeResult { passed: false, detail: "not running".to_string(), ..healthy() };
+        ota_logic.on_event(OtaLogicIn::Health(vec![healthy(), failed.clone()]));
+        assert_eq!(ota_logic.outputs.len(), 0);
+        assert_eq!(ota_logic.next_scheduled(), Some(1705301096152 + 20_000));
+
+        // Still failing when the time is up
+        timer.advance(10_000);
+        ota_logic.on_tick();
+        ota_logic.on_event(OtaLogicIn::Health(vec![failed.clone()]));
+        timer.advance(40_000);
+        ota_logic.on_tick();
+        assert!(ota_logic.outputs.contains(&OtaLogicOut::RollbackEvent));
+        assert_eq!(ota_logic.next_scheduled(), None);
+
+        // Passing too late changes nothing
+        ota_logic.outputs.clear();
+        ota_logic.on_event(OtaLogicIn::Health(vec![healthy()]));
+        assert_eq!(ota_logic.outputs.len(), 0);
+
+        ota_logic.on_event(OtaLogicIn::RolledBack(Ok("1.0.1".to_string())));
+        assert_eq!(ota_logic.state.report.as_ref().unwrap().probes, vec![failed]);
+
+        // Reports are retried until the server has them
+        ota_logic.retry_policy = no_jitter();
+        ota_logic.outputs.clear();
+        ota_logic.on_event(OtaLogicIn::Transport(Err(OtaErr::ReportErr.into())));
+        assert_eq!(ota_logic.scheduled, vec![(1705301096152 + 61_000, OtaLogicOut::ReportEvent)]);
+        assert!(ota_logic.state.report.is_some());
+    }
+
+    /// Takes update 7 to the restart into the new version.
+    fn install(ota_logic: &mut OtaLogic) {
+        accept(ota_logic, 7);
+        ota_logic.on_event(OtaLogicIn::RunningVersion(Ok("1.0.1".to_string())));
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseLink)));
+        ota_logic.on_event(OtaLogicIn::Verify(Ok(())));
+        ota_logic.hc.allow_ota = true;
+        ota_logic.on_tick();
+        ota_logic.on_event(OtaLogicIn::Quiesced(Ok(())));
+        ota_logic.on_event(OtaLogicIn::Installed(Ok(())));
+        ota_logic.outputs.clear();
+    }
+
+    #[test]
+    fn test_failed_install_is_rejected() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+        accept(&mut ota_logic, 7);
+        ota_logic.on_event(OtaLogicIn::RunningVersion(Ok("1.0.1".to_string())));
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseLink)));
+        ota_logic.on_event(OtaLogicIn::Verify(Ok(())));
+        ota_logic.hc.allow_ota = true;
+        ota_logic.on_tick();
+        ota_logic.on_event(OtaLogicIn::Quiesced(Ok(())));
+        ota_logic.outputs.clear();
+
+        ota_logic.on_event(OtaLogicIn::Installed(Err(OtaErr::InstallErr.into())));
+        assert_eq!(ota_logic.state.phase, OtaPhase::Idle);
+        assert_eq!(ota_logic.rejected_download, Some(7));
+
+        // Nothing left for the next window to install
+        ota_logic.hc.allow_ota = true;
+        ota_logic.on_tick();
+        assert!(!ota_logic.outputs.contains(&OtaLogicOut::SuppentEvent));
+    }
+
+    #[test]
+    fn test_unconfirmed_update_rolls_back() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+        ota_logic.confirm_timeout = Duration::from_secs(60);
+        install(&mut ota_logic);
+        ota_logic.on_event(OtaLogicIn::Boot(BootStatus::Trial { version: "1.0.2".to_string(), attempts: 1 }));
+
+        timer.advance(59_000);
+        ota_logic.on_tick();
+        assert!(!ota_logic.outputs.contains(&OtaLogicOut::RollbackEvent));
+        timer.advance(1_000);
+        ota_logic.on_tick();
+        assert!(ota_logic.outputs.contains(&OtaLogicOut::RollbackEvent));
+        ota_logic.outputs.clear();
+
+        // Too late to confirm now
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseRequest(response(7)))));
+        assert_eq!(ota_logic.outputs.len(), 0);
+
+        ota_logic.on_event(OtaLogicIn::RolledBack(Ok("1.0.1".to_string())));
+        assert_eq!(ota_logic.outputs, VecDeque::from([OtaLogicOut::ReportEvent, OtaLogicOut::RestartEvent]));
+        assert_eq!(ota_logic.state.phase, OtaPhase::Idle);
+        assert_eq!(ota_logic.rejected_download, Some(7));
+        assert_eq!(ota_logic.hc.version_name, "1.0.1");
+        let report = ota_logic.state.report.clone().unwrap();
+        assert_eq!((report.outcome, report.running_version.as_str()), (UpdateOutcome::RolledBack, "1.0.1"));
+
+        // The report is sent again after the restart
+        let mut ota_logic = {
+            let mut restored = logic_at(&timer);
+            restored.restore(ota_logic.snapshot());
+            restored
+        };
+        assert_eq!(ota_logic.outputs, VecDeque::from([OtaLogicOut::ReportEvent]));
+        ota_logic.on_event(OtaLogicIn::Boot(BootStatus::Normal { version: "1.0.1".to_string() }));
+        assert_eq!(ota_logic.hc.version_name, "1.0.1");
+        assert!(ota_logic.state.report.is_some());
+
+        // The update is not offered again
+        ota_logic.outputs.clear();
+        ota_logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseRequest(response(7)))));
+        assert_eq!(ota_logic.outputs.len(), 0);
+    }
+
+    #[test]
+    fn test_boot_loop_rolls_back() {
+        let timer = MockTimer::new(1705301096152);
+        let mut ota_logic = logic_at(&timer);
+        install(&mut ota_logic);
+
+        // The installer gave up on the new slot before anything could confirm
+        let mut restored = logic_at(&timer);
+        restored.restore(ota_logic.snapshot());
+        assert_eq!(restored.outputs.len(), 0);
+        restored.on_event(OtaLogicIn::Boot(BootStatus::RolledBack { version: "1.0.1".to_string() }));
+        assert_eq!(restored.state.report.as_ref().unwrap().outcome, UpdateOutcome::RolledBack);
+        assert_eq!(restored.rejected_download, Some(7));
+        assert_eq!(restored.hc.version_name, "1.0.1");
+        restored.on_tick();
+        assert!(!restored.outputs.contains(&OtaLogicOut::RollbackEvent));
+
+        // Confirmed, but restarted before the state was saved
+        let mut restored = logic_at(&timer);
+        restored.restore(ota_logic.snapshot());
+        restored.on_event(OtaLogicIn::Boot(BootStatus::Normal { version: "1.0.2".to_string() }));
+        assert_eq!(restored.state.report.as_ref().unwrap().outcome, UpdateOutcome::Committed);
+        assert_eq!(restored.hc.version_name, "1.0.2");
     }
 }
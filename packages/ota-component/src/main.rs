@@ -1,4 +1,7 @@
-use clap::Parser;
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use config::{Config, Overrides};
+use credentials::Credentials;
 use system_intergration::SystemIntergration;
 
 pub mod system_intergration;
@@ -6,12 +9,55 @@ pub mod logic;
 pub mod transport;
 pub mod security;
 pub mod error;
+pub mod config;
+pub mod schedule;
+pub mod retry;
+pub mod state;
+pub mod version;
+pub mod install;
+pub mod health;
+pub mod quiesce;
+pub mod identity;
+pub mod credentials;
 // Import các thành phần từ modules transport::http_client_json
 
+/// Over-the-air updates for the home controller
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// Path to the TOML configuration file
+    #[arg(short, long, env = "OTA_CONFIG", default_value = "ota.toml")]
+    config: PathBuf,
+    #[command(flatten)]
+    overrides: Overrides,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Write the device credentials to `credentials_file`, readable by the
+    /// owner only
+    Provision {
+        /// API token for the update server
+        #[arg(long, env = "OTA_API_TOKEN", hide_env_values = true)]
+        api_token: Option<String>,
+        /// PEM certificate of the device, for mutual TLS
+        #[arg(long, requires = "client_key")]
+        client_cert: Option<PathBuf>,
+        /// PKCS#8 PEM private key of the certificate
+        #[arg(long, requires = "client_cert")]
+        client_key: Option<PathBuf>,
+    },
+}
 
+fn provision(config: &Config, api_token: Option<String>, client_cert: Option<PathBuf>, client_key: Option<PathBuf>) -> Result<(), error::OtaError> {
+    let read = |path: Option<PathBuf>| path.map(|path| std::fs::read_to_string(&path)
+        .map_err(|e| error::OtaError::io(error::OtaErr::CredentialsErr, &path, e))).transpose();
+    let credentials = Credentials { api_token, client_cert: read(client_cert)?, client_key: read(client_key)? };
+    credentials.provision(&config.credentials_file)?;
+    log::info!("Credentials written to {}", config.credentials_file.display());
+    Ok(())
 }
 
 #[tokio::main]
@@ -19,19 +65,42 @@ async fn main() {
     env_logger::builder().format_timestamp_millis().init();
 
     let args = Args::parse();
-    log::info!("args: {:?}", args);
+    log::info!("config: {}", args.config.display());
+    let config = match Config::load(&args.config, args.overrides) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("{}", e);
+            return;
+        }
+    };
+    if let Some(Command::Provision { api_token, client_cert, client_key }) = args.command {
+        if let Err(e) = provision(&config, api_token, client_cert, client_key) {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
     //TestHttpJsonResponse!();
-    let mut system_intergration = SystemIntergration::new().await;
+    let mut system_intergration = match SystemIntergration::new(config).await {
+        Ok(system_intergration) => system_intergration,
+        Err(e) => {
+            log::error!("{}", e);
+            return;
+        }
+    };
     loop {
         match system_intergration.recv().await {
             Ok(_) => {
-                
+                if system_intergration.restart {
+                    log::info!("Restarting");
+                    break;
+                }
             },
             Err(e) => {
-                log::error!("{:?}", e);
+                log::error!("{}", e);
                 break;
             }
         }
 
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,341 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use sysinfo::System;
+
+use crate::error::{OtaErr, OtaError};
+use crate::state::write_atomic;
+
+/// What to pause or stop on the controller while an update is installed.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct QuiesceConfig {
+    /// Carried out in order, undone in reverse order
+    pub steps: Vec<QuiesceStep>,
+    /// Stops a `service` step, `{name}` is replaced with the service name
+    pub stop_command: Vec<String>,
+    /// Starts a `service` step again
+    pub start_command: Vec<String>,
+    /// What is paused or stopped right now, resumed at the next startup if
+    /// ota-component dies in between
+    pub state_file: PathBuf,
+}
+
+impl Default for QuiesceConfig {
+    fn default() -> Self {
+        QuiesceConfig {
+            steps: Vec::new(),
+            stop_command: ["systemctl", "stop", "{name}"].map(String::from).to_vec(),
+            start_command: ["systemctl", "start", "{name}"].map(String::from).to_vec(),
+            state_file: PathBuf::from("ota_quiesce.json"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum QuiesceStep {
+    /// SIGSTOP the processes named `process`, where `*` matches anything
+    Pause { process: String },
+    /// Stop a service with `stop_command`
+    Service { name: String },
+}
+
+/// One thing paused or stopped by the plan.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Held {
+    Process { pid: u32, name: String },
+    Service { name: String },
+}
+
+/// Services quiesced for an install. They are resumed by `resume`, or when
+/// dropped on any other way out.
+pub struct Quiesced {
+    held: Vec<Held>,
+    start_command: Vec<String>,
+    state_file: PathBuf,
+}
+
+/// Fails with `NotEnoughMemoryErr` unless `min_free_kb` of memory is
+/// available.
+pub fn check_memory(min_free_kb: u64) -> Result<(), OtaError> {
+    let mut sys = System::new();
+    sys.refresh_memory();
+    let available_kb = sys.available_memory() / 1024;
+    if available_kb < min_free_kb {
+        log::error!("{} KiB of memory available, {} KiB needed to install", available_kb, min_free_kb);
+        return Err(OtaErr::NotEnoughMemoryErr.into());
+    }
+    Ok(())
+}
+
+/// Carries out the plan. On failure, whatever was already paused or stopped
+/// is resumed before returning.
+pub fn quiesce(config: &QuiesceConfig) -> Result<Quiesced, OtaError> {
+    let mut quiesced = Quiesced {
+        held: Vec::new(),
+        start_command: config.start_command.clone(),
+        state_file: config.state_file.clone(),
+    };
+    for step in &config.steps {
+        let held = match step {
+            QuiesceStep::Pause { process } => matching_processes(process)
+                .into_iter()
+                .map(|(pid, name)| Held::Process { pid, name })
+                .collect(),
+            QuiesceStep::Service { name } => vec![Held::Service { name: name.clone() }],
+        };
+        if held.is_empty() {
+            log::info!("Nothing to quiesce for {:?}", step);
+        }
+        for held in held {
+            // Recorded first, resuming something not paused is harmless
+            quiesced.held.push(held.clone());
+            quiesced.save();
+            let result = match &held {
+                Held::Process { pid, .. } => signal(*pid, Signal::SIGSTOP),
+                Held::Service { name } => run(&config.stop_command, name),
+            };
+            if let Err(e) = result {
+                quiesced.held.pop();
+                return Err(e);
+            }
+            log::info!("Quiesced {:?}", held);
+        }
+    }
+    Ok(quiesced)
+}
+
+/// Resumes what an earlier run left paused or stopped, if anything. A state
+/// file that cannot be read is removed, there is nothing to resume from it.
+pub fn recover(config: &QuiesceConfig) -> Result<(), OtaError> {
+    let path = &config.state_file;
+    let content = match std::fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(OtaError::io(OtaErr::QuiesceErr, path, e)),
+    };
+    let held: Vec<Held> = match serde_json::from_slice(&content) {
+        Ok(held) => held,
+        Err(e) => {
+            let _ = std::fs::remove_file(path);
+            return Err(OtaError::new(OtaErr::QuiesceErr).with_path(path).with_source(e));
+        }
+    };
+    log::warn!("Resuming {} services left quiesced", held.len());
+    Quiesced { held, start_command: config.start_command.clone(), state_file: path.clone() }.resume();
+    Ok(())
+}
+
+impl Quiesced {
+    /// Resumes everything in reverse order. Failures are logged, the rest is
+    /// resumed anyway.
+    pub fn resume(mut self) {
+        self.resume_all();
+    }
+
+    fn resume_all(&mut self) {
+        while let Some(held) = self.held.pop() {
+            let result = match &held {
+                Held::Process { pid, .. } => signal(*pid, Signal::SIGCONT),
+                Held::Service { name } => run(&self.start_command, name),
+            };
+            match result {
+                Ok(()) => log::info!("Resumed {:?}", held),
+                Err(e) => log::error!("Failed to resume {:?}: {}", held, e),
+            }
+        }
+        match std::fs::remove_file(&self.state_file) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                log::error!("Failed to remove {}: {}", self.state_file.display(), e);
+            }
+            _ => {}
+        }
+    }
+
+    fn save(&self) {
+        let saved = serde_json::to_vec(&self.held).map_err(std::io::Error::other)
+            .and_then(|data| write_atomic(&self.state_file, &data));
+        if let Err(e) = saved {
+            log::warn!("Failed to save quiesce state {}: {}", self.state_file.display(), e);
+        }
+    }
+}
+
+impl Drop for Quiesced {
+    fn drop(&mut self) {
+        if !self.held.is_empty() {
+            self.resume_all();
+        }
+    }
+}
+
+/// Processes whose name matches `pattern`, never ota-component itself or init.
+fn matching_processes(pattern: &str) -> Vec<(u32, String)> {
+    let mut sys = System::new();
+    sys.refresh_processes();
+    let own = std::process::id();
+    let mut processes: Vec<_> = sys.processes().iter()
+        .map(|(pid, process)| (pid.as_u32(), process.name().to_string()))
+        .filter(|(pid, name)| *pid != own && *pid != 1 && matches(pattern, name))
+        .collect();
+    processes.sort();
+    processes
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any run of characters.
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=name.len()).filter(|i| name.is_char_boundary(*i)).any(|i| matches(rest, &name[i..]))
+        }
+    }
+}
+
+fn signal(pid: u32, signal: Signal) -> Result<(), OtaError> {
+    kill(Pid::from_raw(pid as i32), signal).map_err(|e| {
+        let path = PathBuf::from(format!("/proc/{}", pid));
+        OtaError::new(OtaErr::QuiesceErr).with_path(&path).with_source(e)
+    })
+}
+
+fn run(command: &[String], name: &str) -> Result<(), OtaError> {
+    let command: Vec<String> = command.iter().map(|arg| arg.replace("{name}", name)).collect();
+    let Some((program, args)) = command.split_first() else {
+        log::error!("No command configured for service {}", name);
+        return Err(OtaErr::QuiesceErr.into());
+    };
+    let program = Path::new(program);
+    match Command::new(program).args(args).status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => {
+            let failed = std::io::Error::other(format!("{:?} failed: {}", command, status));
+            Err(OtaError::io(OtaErr::QuiesceErr, program, failed))
+        }
+        Err(e) => Err(OtaError::io(OtaErr::QuiesceErr, program, e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ota-quiesce-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// `sleep` running under `name`, which no other process has.
+    fn spawn_sleeper(dir: &Path, name: &str) -> std::process::Child {
+        let program = dir.join(name);
+        std::os::unix::fs::symlink("/bin/sleep", &program).unwrap();
+        Command::new(program).arg("30").spawn().unwrap()
+    }
+
+    fn state(child: &std::process::Child) -> char {
+        // Signals are delivered asynchronously
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", child.id())).unwrap();
+        stat.rsplit_once(") ").unwrap().1.chars().next().unwrap()
+    }
+
+    fn config(dir: &Path, log: &Path, process: &str) -> QuiesceConfig {
+        let log = log.display();
+        QuiesceConfig {
+            steps: vec![
+                QuiesceStep::Pause { process: format!("{}*", process) },
+                QuiesceStep::Service { name: "camera".to_string() },
+            ],
+            stop_command: vec!["sh".to_string(), "-c".to_string(), format!("echo stop {{name}} >> {}", log)],
+            start_command: vec!["sh".to_string(), "-c".to_string(), format!("echo start {{name}} >> {}", log)],
+            state_file: dir.join("ota_quiesce.json"),
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("hc-app", "hc-app"));
+        assert!(!matches("hc-app", "hc-app2"));
+        assert!(matches("hc-*", "hc-app"));
+        assert!(matches("*cam*", "ipcamera"));
+        assert!(!matches("*cam*d", "ipcamera"));
+    }
+
+    #[test]
+    fn test_check_memory() {
+        assert_eq!(check_memory(0), Ok(()));
+        assert_eq!(check_memory(u64::MAX).unwrap_err(), OtaErr::NotEnoughMemoryErr);
+    }
+
+    #[test]
+    fn test_quiesce_and_resume() {
+        let dir = temp_dir("resume");
+        let log = dir.join("services.log");
+        let config = config(&dir, &log, "otaq-resume");
+        let mut child = spawn_sleeper(&dir, "otaq-resume");
+        assert_eq!(state(&child), 'S');
+
+        let quiesced = quiesce(&config).unwrap();
+        assert_eq!(state(&child), 'T');
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "stop camera\n");
+        assert!(config.state_file.exists());
+
+        quiesced.resume();
+        assert_eq!(state(&child), 'S');
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "stop camera\nstart camera\n");
+        assert!(!config.state_file.exists());
+
+        // Dropped without resuming, e.g. on an error path
+        drop(quiesce(&config).unwrap());
+        assert_eq!(state(&child), 'S');
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_failed_step_resumes_the_rest() {
+        let dir = temp_dir("failed");
+        let log = dir.join("services.log");
+        let mut config = config(&dir, &log, "otaq-none");
+        config.steps.push(QuiesceStep::Service { name: "zigbee".to_string() });
+        config.stop_command = vec!["sh".to_string(), "-c".to_string(), format!("echo stop {{name}} >> {}; [ {{name}} != zigbee ]", log.display())];
+        let e = quiesce(&config).err().unwrap();
+        assert_eq!((&e.kind, e.path.as_deref()), (&OtaErr::QuiesceErr, Some(Path::new("sh"))));
+        assert!(std::error::Error::source(&e).is_some());
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "stop camera\nstop zigbee\nstart camera\n");
+        assert!(!config.state_file.exists());
+    }
+
+    #[test]
+    fn test_recover_after_crash() {
+        let dir = temp_dir("recover");
+        let log = dir.join("services.log");
+        let config = config(&dir, &log, "otaq-recover");
+        let mut child = spawn_sleeper(&dir, "otaq-recover");
+
+        std::mem::forget(quiesce(&config).unwrap());
+        assert_eq!(state(&child), 'T');
+        recover(&config).unwrap();
+        assert_eq!(state(&child), 'S');
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "stop camera\nstart camera\n");
+        assert!(!config.state_file.exists());
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        assert_eq!(recover(&config), Ok(()));
+        std::fs::write(&config.state_file, b"{").unwrap();
+        let e = recover(&config).unwrap_err();
+        assert_eq!((e.kind, e.path), (OtaErr::QuiesceErr, Some(config.state_file.clone())));
+        assert!(!config.state_file.exists());
+    }
+}
@@ -0,0 +1,157 @@
+use std::time::Duration;
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::error::OtaErr;
+
+/// How failed steps of the OTA flow are retried: capped exponential backoff
+/// with random jitter, giving up after `max_attempts` (0 retries forever).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub initial_delay_ms: u64,
+    pub multiplier: f64,
+    pub max_delay_ms: u64,
+    /// Fraction of the delay randomly added or removed, between 0.0 and 1.0
+    pub jitter: f64,
+    pub max_attempts: u32,
+    /// Start again from `initial_delay_ms` once the step succeeds
+    pub reset_on_success: bool,
+    /// Downloads of an image not matching its checksum before it is rejected
+    pub checksum_redownloads: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial_delay_ms: 3_000,
+            multiplier: 2.0,
+            max_delay_ms: 30 * 60 * 1000,
+            jitter: 0.2,
+            max_attempts: 10,
+            reset_on_success: true,
+            checksum_redownloads: 2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn validate(&self) -> Result<(), OtaErr> {
+        if self.multiplier < 1.0 || !(0.0..=1.0).contains(&self.jitter) || self.max_delay_ms < self.initial_delay_ms {
+            log::error!("Invalid retry policy {:?}", self);
+            return Err(OtaErr::ConfigErr);
+        }
+        Ok(())
+    }
+
+    /// Delay before retry number `attempt` (starting at 0), without jitter.
+    pub fn base_delay(&self, attempt: u32) -> Duration {
+        let delay = self.initial_delay_ms as f64 * self.multiplier.powi(attempt.min(i32::MAX as u32) as i32);
+        Duration::from_millis(delay.min(self.max_delay_ms as f64) as u64)
+    }
+
+    pub fn delay<R: Rng>(&self, attempt: u32, rng: &mut R) -> Duration {
+        let base = self.base_delay(attempt).as_millis() as f64;
+        if self.jitter <= 0.0 {
+            return Duration::from_millis(base as u64);
+        }
+        let factor = rng.gen_range(-self.jitter..=self.jitter);
+        let delay = (base * (1.0 + factor)).min(self.max_delay_ms as f64);
+        Duration::from_millis(delay.max(0.0) as u64)
+    }
+}
+
+/// Attempt counter for one retried step.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Backoff {
+    pub attempts: u32,
+}
+
+impl Backoff {
+    /// Delay before the next attempt, or `None` once the policy gives up.
+    pub fn next_delay<R: Rng>(&mut self, policy: &RetryPolicy, rng: &mut R) -> Option<Duration> {
+        if policy.max_attempts != 0 && self.attempts >= policy.max_attempts {
+            return None;
+        }
+        let delay = policy.delay(self.attempts, rng);
+        self.attempts += 1;
+        Some(delay)
+    }
+
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            initial_delay_ms: 1_000,
+            multiplier: 3.0,
+            max_delay_ms: 20_000,
+            jitter: 0.0,
+            max_attempts: 5,
+            reset_on_success: true,
+            checksum_redownloads: 2,
+        }
+    }
+
+    #[test]
+    fn test_capped_exponential_delay() {
+        let policy = policy();
+        let delays: Vec<u64> = (0..5).map(|n| policy.base_delay(n).as_millis() as u64).collect();
+        assert_eq!(delays, vec![1_000, 3_000, 9_000, 20_000, 20_000]);
+        assert_eq!(policy.base_delay(u32::MAX), Duration::from_millis(20_000));
+    }
+
+    #[test]
+    fn test_jitter_stays_in_bounds() {
+        let policy = RetryPolicy { jitter: 0.5, ..policy() };
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut seen = std::collections::HashSet::new();
+
+        for _ in 0..100 {
+            let delay = policy.delay(1, &mut rng).as_millis() as u64;
+            assert!((1_500..=4_500).contains(&delay));
+            seen.insert(delay);
+        }
+        assert!(seen.len() > 1);
+
+        // Never above the cap
+        for _ in 0..100 {
+            assert!(policy.delay(10, &mut rng) <= Duration::from_millis(20_000));
+        }
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        let policy = policy();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut backoff = Backoff::default();
+
+        for _ in 0..5 {
+            assert!(backoff.next_delay(&policy, &mut rng).is_some());
+        }
+        assert_eq!(backoff.next_delay(&policy, &mut rng), None);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(&policy, &mut rng), Some(Duration::from_millis(1_000)));
+
+        let forever = RetryPolicy { max_attempts: 0, ..policy };
+        let mut backoff = Backoff { attempts: 1_000 };
+        assert_eq!(backoff.next_delay(&forever, &mut rng), Some(Duration::from_millis(20_000)));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(RetryPolicy::default().validate().is_ok());
+        assert_eq!(RetryPolicy { multiplier: 0.5, ..policy() }.validate(), Err(OtaErr::ConfigErr));
+        assert_eq!(RetryPolicy { jitter: 1.5, ..policy() }.validate(), Err(OtaErr::ConfigErr));
+        assert_eq!(RetryPolicy { max_delay_ms: 10, ..policy() }.validate(), Err(OtaErr::ConfigErr));
+    }
+}
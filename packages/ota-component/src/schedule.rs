@@ -0,0 +1,218 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer};
+
+use crate::error::OtaErr;
+
+/// One recurring maintenance window in the schedule's local time. A window
+/// whose `end` is not after `start` wraps past midnight into the next day;
+/// `days` refers to the day the window opens.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MaintenanceWindow {
+    #[serde(default = "all_days")]
+    pub days: Vec<Weekday>,
+    #[serde(deserialize_with = "deserialize_time")]
+    pub start: NaiveTime,
+    #[serde(deserialize_with = "deserialize_time")]
+    pub end: NaiveTime,
+}
+
+/// When OTA updates are allowed to start. The update is triggered once per
+/// window occurrence, a random number of minutes (within `jitter_minutes`)
+/// after the window opens, so a fleet does not hit the server all at once.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct MaintenanceSchedule {
+    pub timezone: Tz,
+    pub windows: Vec<MaintenanceWindow>,
+    pub jitter_minutes: (u32, u32),
+}
+
+fn all_days() -> Vec<Weekday> {
+    vec![
+        Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu,
+        Weekday::Fri, Weekday::Sat, Weekday::Sun,
+    ]
+}
+
+fn deserialize_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&s, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(&s, "%H:%M:%S"))
+        .map_err(serde::de::Error::custom)
+}
+
+impl Default for MaintenanceSchedule {
+    fn default() -> Self {
+        MaintenanceSchedule {
+            timezone: chrono_tz::Asia::Ho_Chi_Minh,
+            windows: vec![MaintenanceWindow {
+                days: all_days(),
+                start: NaiveTime::from_hms_opt(2, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(4, 0, 0).unwrap(),
+            }],
+            jitter_minutes: (0, 120),
+        }
+    }
+}
+
+impl MaintenanceWindow {
+    /// Start and end in UTC of the occurrence opening on local date `day`.
+    fn occurrence(&self, tz: &Tz, day: NaiveDate) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        if !self.days.contains(&day.weekday()) {
+            return None;
+        }
+        let end_day = if self.end <= self.start { day.succ_opt()? } else { day };
+        let start = tz.from_local_datetime(&day.and_time(self.start)).earliest()?;
+        let end = tz.from_local_datetime(&end_day.and_time(self.end)).latest()?;
+        Some((start.with_timezone(&Utc), end.with_timezone(&Utc)))
+    }
+}
+
+impl MaintenanceSchedule {
+    pub fn validate(&self) -> Result<(), OtaErr> {
+        if self.jitter_minutes.0 > self.jitter_minutes.1 {
+            log::error!("Maintenance jitter range {:?} is empty", self.jitter_minutes);
+            return Err(OtaErr::ConfigErr);
+        }
+        Ok(())
+    }
+
+    /// The window occurrence containing `now`, as `[start, end)` in UTC.
+    pub fn window_at(&self, now: DateTime<Utc>) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let today = now.with_timezone(&self.timezone).date_naive();
+        // A window opened yesterday may still be running past midnight.
+        let days = [today.pred_opt(), Some(today)];
+        days.iter().flatten().find_map(|day| {
+            self.windows
+                .iter()
+                .filter_map(|window| window.occurrence(&self.timezone, *day))
+                .find(|(start, end)| *start <= now && now < *end)
+        })
+    }
+
+    pub fn is_open(&self, now: DateTime<Utc>) -> bool {
+        self.window_at(now).is_some()
+    }
+
+    /// Moment inside the occurrence `[start, end)` at which the update starts,
+    /// `offset_minutes` after it opens but never later than its last minute.
+    pub fn slot(&self, start: DateTime<Utc>, end: DateTime<Utc>, offset_minutes: u32) -> DateTime<Utc> {
+        let latest = (end - Duration::minutes(1)).max(start);
+        (start + Duration::minutes(offset_minutes as i64)).min(latest)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn window(days: Vec<Weekday>, start: (u32, u32), end: (u32, u32)) -> MaintenanceWindow {
+        MaintenanceWindow {
+            days,
+            start: NaiveTime::from_hms_opt(start.0, start.1, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(end.0, end.1, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_default_window() {
+        let schedule = MaintenanceSchedule::default();
+
+        // 02:00 - 04:00 in UTC+7
+        assert!(!schedule.is_open(utc("2024-01-14T18:59:00Z")));
+        assert!(schedule.is_open(utc("2024-01-14T19:00:00Z")));
+        assert!(schedule.is_open(utc("2024-01-14T20:59:59Z")));
+        assert!(!schedule.is_open(utc("2024-01-14T21:00:00Z")));
+
+        let (start, end) = schedule.window_at(utc("2024-01-14T20:00:00Z")).unwrap();
+        assert_eq!(start, utc("2024-01-14T19:00:00Z"));
+        assert_eq!(end, utc("2024-01-14T21:00:00Z"));
+    }
+
+    #[test]
+    fn test_weekend_only_in_other_timezone() {
+        let schedule = MaintenanceSchedule {
+            timezone: chrono_tz::Europe::Berlin,
+            windows: vec![window(vec![Weekday::Sat, Weekday::Sun], (3, 0), (5, 0))],
+            jitter_minutes: (0, 0),
+        };
+
+        // Saturday 2024-01-13 03:30 CET
+        assert!(schedule.is_open(utc("2024-01-13T02:30:00Z")));
+        // Monday 2024-01-15 03:30 CET
+        assert!(!schedule.is_open(utc("2024-01-15T02:30:00Z")));
+        // Summer time shifts the window by an hour in UTC
+        assert!(schedule.is_open(utc("2024-07-13T01:30:00Z")));
+        assert!(!schedule.is_open(utc("2024-07-13T03:30:00Z")));
+    }
+
+    #[test]
+    fn test_window_wraps_midnight() {
+        let schedule = MaintenanceSchedule {
+            timezone: chrono_tz::UTC,
+            windows: vec![window(vec![Weekday::Fri], (23, 0), (1, 0))],
+            jitter_minutes: (0, 0),
+        };
+
+        // Opens Friday 2024-01-12, closes Saturday
+        assert!(schedule.is_open(utc("2024-01-12T23:30:00Z")));
+        assert!(schedule.is_open(utc("2024-01-13T00:30:00Z")));
+        assert!(!schedule.is_open(utc("2024-01-13T01:00:00Z")));
+        // Thursday night is not in the schedule
+        assert!(!schedule.is_open(utc("2024-01-11T23:30:00Z")));
+    }
+
+    #[test]
+    fn test_multiple_windows() {
+        let schedule = MaintenanceSchedule {
+            timezone: chrono_tz::UTC,
+            windows: vec![
+                window(all_days(), (2, 0), (3, 0)),
+                window(vec![Weekday::Sun], (13, 0), (14, 0)),
+            ],
+            jitter_minutes: (0, 0),
+        };
+
+        assert!(schedule.is_open(utc("2024-01-15T02:10:00Z")));
+        assert!(!schedule.is_open(utc("2024-01-15T13:10:00Z")));
+        assert!(schedule.is_open(utc("2024-01-14T13:10:00Z")));
+    }
+
+    #[test]
+    fn test_slot_is_clamped_to_window() {
+        let schedule = MaintenanceSchedule::default();
+        let start = utc("2024-01-14T19:00:00Z");
+        let end = utc("2024-01-14T21:00:00Z");
+
+        assert_eq!(schedule.slot(start, end, 0), start);
+        assert_eq!(schedule.slot(start, end, 45), utc("2024-01-14T19:45:00Z"));
+        assert_eq!(schedule.slot(start, end, 120), utc("2024-01-14T20:59:00Z"));
+        assert_eq!(schedule.slot(start, end, 500), utc("2024-01-14T20:59:00Z"));
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let schedule: MaintenanceSchedule = toml::from_str(r#"
+            timezone = "America/New_York"
+            jitter_minutes = [5, 30]
+
+            [[windows]]
+            days = ["Sat", "Sun"]
+            start = "01:30"
+            end = "05:00"
+        "#).unwrap();
+
+        assert_eq!(schedule.timezone, chrono_tz::America::New_York);
+        assert_eq!(schedule.jitter_minutes, (5, 30));
+        assert_eq!(schedule.windows, vec![window(vec![Weekday::Sat, Weekday::Sun], (1, 30), (5, 0))]);
+        assert!(schedule.validate().is_ok());
+
+        let empty: MaintenanceSchedule = toml::from_str("jitter_minutes = [30, 5]").unwrap();
+        assert_eq!(empty.validate(), Err(OtaErr::ConfigErr));
+    }
+}
@@ -1,92 +1,315 @@
 use openssl::pkey::PKey;
 use std::fs::File;
-use std::io::{Read, Write};
-use openssl::hash::MessageDigest;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use openssl::hash::{Hasher, MessageDigest};
 use openssl::pkey::Public;
 use std::fs::OpenOptions;
-use std::io::Seek;
-use openssl::sign::{Signer, Verifier};
+use ota_package::manifest::{Manifest, SignedManifest};
+use ota_package::signature;
 
-use crate::error::OtaErr;
+use crate::error::{OtaErr, OtaError};
 
+/// Verifies vendor signatures (SHA-256 over the whole image) of downloaded
+/// images. Only the vendor public key lives on the controller.
 pub struct DsaType {
     f_path: String,
     public_key: PKey<Public>
 }
 
 impl DsaType {
-    pub fn new(f_path: String, kpublic_path: String) -> Self {
+    /// Verifier for the image at `f_path`, with the vendor key read from
+    /// `kpublic_path`.
+    pub fn new(f_path: String, kpublic_path: &Path) -> Result<Self, OtaError> {
+        let public_key_pem = std::fs::read(kpublic_path)
+            .map_err(|e| OtaError::io(OtaErr::ConfigErr, kpublic_path, e))?;
+        let public_key = PKey::public_key_from_pem(&public_key_pem)
+            .map_err(|e| OtaError::new(OtaErr::ConfigErr).with_path(kpublic_path).with_source(e))?;
 
-        let mut public_key_pem = Vec::new();
-        File::open(kpublic_path.clone())
-            .unwrap()
-            .read_to_end(&mut public_key_pem)
-            .unwrap();
-
-        let public_key = PKey::public_key_from_pem(&public_key_pem).unwrap();
+        Ok(DsaType {
+            f_path,
+            public_key
+        })
+    }
 
-        DsaType {
-            f_path: f_path,
-            public_key: public_key
-        }
+    /// Detached signature of the image, fetched from `Data.signature_link`
+    /// or extracted from the image trailer.
+    pub fn signature_path(&self) -> PathBuf {
+        PathBuf::from(format!("{}.sig", self.f_path))
     }
-    pub async fn verify(&mut self,len:usize) -> Result<(), OtaErr> {        
-        let mut file_content = Vec::new();
-        let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(&self.f_path)
-        .expect("Failed to open data file");
 
-        file.read_to_end(&mut file_content)
-        .expect("Failed to read data file");
+    /// Checks the downloaded image against its detached signature, or the
+    /// signature trailer appended to it. A verified trailer is moved out into
+    /// the `.sig` file so the image left behind is exactly what was signed.
+    /// Images without any signature are rejected.
+    pub async fn verify(&mut self) -> Result<(), OtaError> {
+        let image = Path::new(&self.f_path);
+        let failed = |e| OtaError::io(OtaErr::VerifyErr, image, e);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(image)
+            .map_err(|e| {
+                log::error!("Failed to open {}: {}", self.f_path, e);
+                failed(e)
+            })?;
+        let file_len = file.metadata().map_err(failed)?.len();
 
-        let file_len = file_content.len();
+        if let Ok(signature) = std::fs::read(self.signature_path()) {
+            return self.verify_range(&mut file, file_len, &signature).map_err(|e| e.with_path(image));
+        }
 
-        let mut temp = &file_content[0..(file_len - len)];
+        let (image_len, signature) = signature::read_trailer(&mut file, file_len)
+            .map_err(|e| OtaError::from(e).with_path(image))?
+            .ok_or_else(|| {
+                log::error!("{} is not signed", self.f_path);
+                OtaError::new(OtaErr::UnsignedErr).with_path(image)
+            })?;
+        self.verify_range(&mut file, image_len, &signature).map_err(|e| e.with_path(image))?;
 
-        let last_256_bytes = &file_content[file_len.saturating_sub(len)..];
+        // Keep the signature before stripping it, so a restart in between
+        // still finds the image verifiable.
+        let sig_path = self.signature_path();
+        let sig_failed = |e| OtaError::io(OtaErr::VerifyErr, &sig_path, e);
+        let mut sig_file = File::create(&sig_path).map_err(sig_failed)?;
+        sig_file.write_all(&signature).map_err(sig_failed)?;
+        sig_file.sync_all().map_err(sig_failed)?;
+        file.set_len(image_len).map_err(failed)?;
+        file.sync_all().map_err(failed)?;
+        Ok(())
+    }
 
-        file.seek(std::io::SeekFrom::Start(0))
-        .expect("Failed to seek back to the beginning of the file");
+    /// Opens the signed manifest of an update. Updates without one are not
+    /// trusted.
+    pub fn open_manifest(&self, signed: Option<&SignedManifest>) -> Result<Manifest, OtaErr> {
+        let signed = signed.ok_or_else(|| {
+            log::error!("Update has no manifest");
+            OtaErr::ManifestErr
+        })?;
+        signed.open(&self.public_key).map_err(|e| {
+            log::error!("Invalid update manifest: {:?}", e);
+            OtaErr::ManifestErr
+        })
+    }
 
-        let _ =  file.write_all(&mut temp);
+    /// Verifies `signature` over the first `len` bytes of `file`.
+    fn verify_range(&self, file: &mut File, len: u64, signature: &[u8]) -> Result<(), OtaError> {
+        file.seek(SeekFrom::Start(0)).map_err(|e| OtaError::new(OtaErr::VerifyErr).with_source(e))?;
+        signature::verify(&self.public_key, file.take(len), signature).map_err(OtaError::from)
+    }
+}
 
-        // verify
-        let mut verifier = Verifier::new(MessageDigest::sha256(), &self.public_key)
-        .expect("Failed to create verifier");
+/// Expected digest of a downloaded image, written as `<algorithm>:<hex>` or
+/// plain hex for SHA-256.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checksum {
+    pub algorithm: String,
+    pub digest: Vec<u8>,
+}
 
-        match verifier.verify_oneshot(&last_256_bytes, &temp) {
-            Ok(check) => {
-                if check {
-                    return Ok(());
-                } else {
-                    return Err(OtaErr::VerifyNotEqualErr);
-                }
-            }
-            Err(err) => {
-                println!("Error verifying: {}", err);
-                return Err(OtaErr::VerifyErr);
+impl Checksum {
+    pub fn parse(value: &str) -> Result<Self, OtaErr> {
+        let value = value.trim();
+        let (algorithm, digest) = value.split_once(':').unwrap_or(("sha256", value));
+        let algorithm = algorithm.to_ascii_lowercase();
+        let checksum = Checksum {
+            digest: hex::decode(digest).map_err(|_| OtaErr::ChecksumErr)?,
+            algorithm,
+        };
+        match checksum.message_digest() {
+            Some(md) if md.size() == checksum.digest.len() => Ok(checksum),
+            _ => {
+                log::error!("Unsupported checksum {}", value);
+                Err(OtaErr::ChecksumErr)
             }
         }
     }
 
-    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, OtaErr> {
-        let mut private_key_pem = Vec::new();
-        File::open("private_key.pem") // Update the path to your private key
-            .expect("Failed to open private key file")
-            .read_to_end(&mut private_key_pem)
-            .expect("Failed to read private key file");
+    fn message_digest(&self) -> Option<MessageDigest> {
+        match self.algorithm.as_str() {
+            "sha256" => Some(MessageDigest::sha256()),
+            "sha384" => Some(MessageDigest::sha384()),
+            "sha512" => Some(MessageDigest::sha512()),
+            _ => None,
+        }
+    }
 
-        let private_key = PKey::private_key_from_pem(&private_key_pem)
-            .expect("Failed to create private key from PEM");
+    pub fn hasher(&self) -> Result<Hasher, OtaErr> {
+        let md = self.message_digest().ok_or(OtaErr::ChecksumErr)?;
+        Hasher::new(md).map_err(|_| OtaErr::ChecksumErr)
+    }
+
+    pub fn matches(&self, digest: &[u8]) -> bool {
+        self.digest.len() == digest.len() && openssl::memcmp::eq(&self.digest, digest)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::Private;
+    use ota_package::signature::TRAILER_LEN;
+
+    fn key_pair(dir: &std::path::Path) -> (PKey<Private>, PathBuf) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let public_path = dir.join("public_key.pem");
+        std::fs::write(&public_path, key.public_key_to_pem().unwrap()).unwrap();
+        (key, public_path)
+    }
+
+    fn sign(key: &PKey<Private>, data: &[u8]) -> Vec<u8> {
+        signature::sign(key, data).unwrap()
+    }
+
+    fn with_trailer(image: &[u8], signature: &[u8]) -> Vec<u8> {
+        let mut data = image.to_vec();
+        data.extend_from_slice(&signature::trailer(signature));
+        data
+    }
+
+    fn setup(name: &str) -> (std::path::PathBuf, PKey<Private>, DsaType) {
+        let dir = std::env::temp_dir().join(format!("ota-security-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (key, public_path) = key_pair(&dir);
+        let image_path = dir.join("update_ota.bin");
+        let dsa = DsaType::new(image_path.to_string_lossy().to_string(), &public_path).unwrap();
+        (image_path, key, dsa)
+    }
 
-        let mut signer = Signer::new(MessageDigest::sha256(), &private_key)
-            .expect("Failed to create signer");
+    fn image() -> Vec<u8> {
+        (0..200_000).map(|i| (i % 253) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn test_verify_trailer() {
+        let (path, key, mut dsa) = setup("trailer");
+        let image = image();
+        let signature = sign(&key, &image);
+        std::fs::write(&path, with_trailer(&image, &signature)).unwrap();
+
+        dsa.verify().await.unwrap();
+        // Trailer moved out, the signed image is left
+        assert_eq!(std::fs::read(&path).unwrap(), image);
+        assert_eq!(std::fs::read(dsa.signature_path()).unwrap(), signature);
+
+        // Verifying again, e.g. after a restart, uses the extracted signature
+        dsa.verify().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_verify_detached() {
+        let (path, key, mut dsa) = setup("detached");
+        let image = image();
+        std::fs::write(&path, &image).unwrap();
+        std::fs::write(dsa.signature_path(), sign(&key, &image)).unwrap();
+
+        dsa.verify().await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), image);
+    }
+
+    #[tokio::test]
+    async fn test_reject_tampered_image() {
+        let (path, key, mut dsa) = setup("tampered");
+        let mut image = image();
+        let signature = sign(&key, &image);
+        image[1000] ^= 1;
+        std::fs::write(&path, with_trailer(&image, &signature)).unwrap();
+        assert_eq!(dsa.verify().await.map_err(|e| e.kind), Err(OtaErr::VerifyNotEqualErr));
+        assert!(!dsa.signature_path().exists());
+
+        // Signed by someone else
+        let other = setup("tampered-other").1;
+        let image = self::image();
+        std::fs::write(&path, with_trailer(&image, &sign(&other, &image))).unwrap();
+        assert_eq!(dsa.verify().await.map_err(|e| e.kind), Err(OtaErr::VerifyNotEqualErr));
+
+        // Garbage where the signature should be
+        std::fs::write(&path, with_trailer(&image, b"not a signature")).unwrap();
+        assert_eq!(dsa.verify().await.map_err(|e| e.kind), Err(OtaErr::VerifyNotEqualErr));
+
+        let mut bad_len = with_trailer(&image, &sign(&key, &image));
+        let len_at = bad_len.len() - TRAILER_LEN as usize;
+        bad_len[len_at..len_at + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        std::fs::write(&path, bad_len).unwrap();
+        assert_eq!(dsa.verify().await.map_err(|e| e.kind), Err(OtaErr::VerifyNotEqualErr));
+    }
+
+    #[test]
+    fn test_open_manifest() {
+        let (_, key, dsa) = setup("manifest");
+        let manifest = Manifest {
+            version_name: "1.2.0".to_string(),
+            hc_types: vec!["Hc01".to_string()],
+            image: "hc-1.2.0.bin".to_string(),
+            size: 1,
+            sha256: "00".repeat(32),
+            sha512: "00".repeat(64),
+            ..Manifest::default()
+        };
+        let signed = SignedManifest::sign(&manifest, &key).unwrap();
+        assert_eq!(dsa.open_manifest(Some(&signed)), Ok(manifest));
+        assert_eq!(dsa.open_manifest(None), Err(OtaErr::ManifestErr));
+
+        let other = setup("manifest-other").1;
+        let forged = SignedManifest::sign(&Manifest { version_name: "9.0.0".to_string(), ..dsa.open_manifest(Some(&signed)).unwrap() }, &other).unwrap();
+        assert_eq!(dsa.open_manifest(Some(&forged)), Err(OtaErr::ManifestErr));
+    }
+
+    #[tokio::test]
+    async fn test_reject_unsigned_image() {
+        let (path, _, mut dsa) = setup("unsigned");
+        std::fs::write(&path, image()).unwrap();
+        assert_eq!(dsa.verify().await.map_err(|e| e.kind), Err(OtaErr::UnsignedErr));
+
+        std::fs::write(&path, b"tiny").unwrap();
+        assert_eq!(dsa.verify().await.map_err(|e| e.kind), Err(OtaErr::UnsignedErr));
+
+        std::fs::remove_file(&path).unwrap();
+        let missing = dsa.verify().await.unwrap_err();
+        assert_eq!((&missing.kind, missing.path.as_deref()), (&OtaErr::VerifyErr, Some(path.as_path())));
+        assert!(std::error::Error::source(&missing).is_some());
+    }
+
+    #[test]
+    fn test_unreadable_public_key() {
+        let dir = setup("public-key").0.parent().unwrap().to_path_buf();
+        let missing = DsaType::new("update_ota.bin".to_string(), &dir.join("missing.pem")).err().unwrap();
+        assert_eq!((missing.kind, missing.path), (OtaErr::ConfigErr, Some(dir.join("missing.pem"))));
+        std::fs::write(dir.join("junk.pem"), b"junk").unwrap();
+        let junk = DsaType::new("update_ota.bin".to_string(), &dir.join("junk.pem")).err().unwrap();
+        assert_eq!(junk.kind, OtaErr::ConfigErr);
+    }
+
+    // sha256("abc")
+    const ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn test_parse_checksum() {
+        let plain = Checksum::parse(ABC).unwrap();
+        assert_eq!(plain.algorithm, "sha256");
+        assert_eq!(Checksum::parse(&format!("sha256:{}", ABC)).unwrap(), plain);
+        assert_eq!(Checksum::parse(&format!("SHA256:{}", ABC.to_uppercase())).unwrap(), plain);
+
+        assert_eq!(Checksum::parse("md5:900150983cd24fb0d6963f7d28e17f72"), Err(OtaErr::ChecksumErr));
+        assert_eq!(Checksum::parse("sha256:zz"), Err(OtaErr::ChecksumErr));
+        assert_eq!(Checksum::parse(&format!("sha512:{}", ABC)), Err(OtaErr::ChecksumErr));
+        assert_eq!(Checksum::parse(""), Err(OtaErr::ChecksumErr));
+    }
 
-        signer.update(&message).expect("Failed to update signer with message");
-        let signature = signer.sign_to_vec().expect("Failed to sign message");
+    #[test]
+    fn test_checksum_matches() {
+        let checksum = Checksum::parse(ABC).unwrap();
+        let mut hasher = checksum.hasher().unwrap();
+        hasher.update(b"ab").unwrap();
+        hasher.update(b"c").unwrap();
+        assert!(checksum.matches(&hasher.finish().unwrap()));
 
-        Ok(signature)
+        let mut hasher = checksum.hasher().unwrap();
+        hasher.update(b"abd").unwrap();
+        assert!(!checksum.matches(&hasher.finish().unwrap()));
     }
 }
@@ -0,0 +1,162 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::error::OtaErr;
+use crate::logic::OtaLogicOut;
+use crate::transport::UpdateReport;
+use ota_package::manifest::Manifest;
+
+/// Where the OTA flow stands, so it can resume after a reboot.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OtaPhase {
+    #[default]
+    Idle,
+    Downloading,
+    Verifying,
+    Verified,
+    Suspending,
+    Installing,
+    /// Restarted into the new version, which has to prove it works
+    Confirming,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OtaState {
+    pub phase: OtaPhase,
+    pub target_version: String,
+    pub download_id: u64,
+    pub link: String,
+    pub checksum: String,
+    pub signature_link: String,
+    /// Verified manifest of the update
+    pub manifest: Option<Manifest>,
+    /// Downloads so far that did not match `checksum`
+    pub checksum_failures: u32,
+    /// Attempts made so far for each retried event
+    pub retries: Vec<(OtaLogicOut, u32)>,
+    /// Outcome of the last update, until the server has it
+    pub report: Option<UpdateReport>,
+    /// Update rejected for good, not downloaded again when offered
+    pub rejected_download: Option<u64>,
+}
+
+/// JSON state file, replaced atomically on every save.
+pub struct StateStore {
+    path: PathBuf,
+}
+
+impl StateStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        StateStore { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the saved state. A missing or unreadable file means starting
+    /// over, which is always safe.
+    pub fn load(&self) -> Option<OtaState> {
+        let content = match fs::read(&self.path) {
+            Ok(content) => content,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("Failed to read ota state {}: {}", self.path.display(), e);
+                }
+                return None;
+            }
+        };
+        match serde_json::from_slice(&content) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                log::warn!("Discarding corrupted ota state {}: {}", self.path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Writes to a temporary file next to the state file, syncs it and renames
+    /// it over the old one.
+    pub fn save(&self, state: &OtaState) -> Result<(), OtaErr> {
+        let data = serde_json::to_vec_pretty(state).map_err(|_| OtaErr::StateErr)?;
+        write_atomic(&self.path, &data).map_err(|e| {
+            log::error!("Failed to save ota state {}: {}", self.path.display(), e);
+            OtaErr::StateErr
+        })
+    }
+}
+
+/// Replaces `path` with `data` so that a power cut leaves either the old or
+/// the new content, never a mix.
+pub fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn store(name: &str) -> StateStore {
+        let dir = std::env::temp_dir().join(format!("ota-state-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        StateStore::new(dir.join("ota_state.json"))
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let store = store("roundtrip");
+        assert_eq!(store.load(), None);
+
+        let state = OtaState {
+            phase: OtaPhase::Verified,
+            target_version: "1.2.0".to_string(),
+            download_id: 42,
+            link: "http://localhost/update.bin".to_string(),
+            checksum: "sha256:00".to_string(),
+            signature_link: String::new(),
+            manifest: None,
+            checksum_failures: 1,
+            retries: vec![(OtaLogicOut::GetLinkEvent, 2)],
+            report: None,
+            rejected_download: Some(41),
+        };
+        store.save(&state).unwrap();
+        assert_eq!(store.load(), Some(state.clone()));
+
+        // Saving again replaces the file and leaves no temporary behind
+        let state = OtaState { phase: OtaPhase::Installing, ..state };
+        store.save(&state).unwrap();
+        assert_eq!(store.load(), Some(state));
+        let files: Vec<_> = fs::read_dir(store.path().parent().unwrap()).unwrap().collect();
+        assert_eq!(files.len(), 1);
+
+        fs::remove_file(store.path()).unwrap();
+        assert_eq!(store.load(), None);
+    }
+
+    #[test]
+    fn test_corrupted_state_is_discarded() {
+        let store = store("corrupted");
+        fs::write(store.path(), b"{\"phase\": \"Downl").unwrap();
+        assert_eq!(store.load(), None);
+
+        // Unknown fields from a newer version and missing ones are fine
+        fs::write(store.path(), br#"{"phase": "Downloading", "future": 1}"#).unwrap();
+        assert_eq!(store.load().unwrap().phase, OtaPhase::Downloading);
+    }
+}
@@ -1,15 +1,23 @@
-use psutil::process::processes;
-use lumi_utils::timer::{SystemTimer, Timer};
+use lumi_utils::timer::SystemTimer;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
 use tokio::{time::{interval, Interval, Duration}, select};
-use crate::{transport::{http_client::HttpClient, Transport,TransportIn, HttpClientJson, TransportOut ,mqtt::MqttDriver}, logic::OtaLogic,};
-use crate::logic::{OtaLogicOut,OtaLogicIn,HcType};
+use crate::{transport::{http_client::HttpClient, Transport,TransportIn, HttpClientJson, TransportOut ,mqtt::{MqttConfig, MqttDriver, MqttEvent, MqttRequest}}, logic::OtaLogic,};
+use crate::logic::{OtaLogicOut,OtaLogicIn};
 use tokio::sync::mpsc;
 use crate::security::DsaType;
-use crate::error::OtaErr;
-use tokio::fs::File;
-use sysinfo::System;
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::error::{OtaErr, OtaError, Phase};
+use crate::config::Config;
+use crate::credentials::Credentials;
+use crate::identity::{self, DeviceIdentity};
+use crate::state::{OtaPhase, OtaState, StateStore};
+use crate::transport::download::{self, BandwidthConfig, DeltaRequest, DownloadRequest, Downloader};
+use crate::version::{self, CounterStore};
+use crate::install::{self, BootStatus, SlotInstaller, VersionIds};
+use crate::health::{self, HealthConfig};
+use crate::quiesce::{self, QuiesceConfig, Quiesced};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 #[derive(Debug)]
 pub enum SystemIntergrationErr {
     TranSportErr,
@@ -17,66 +25,142 @@ pub enum SystemIntergrationErr {
 
 pub struct SystemIntergration {
     interval: Interval,
-    timer: SystemTimer,
     transport: HttpClient,
     pub logic: OtaLogic,
     dsa: DsaType,
     mqtt: MqttDriver,
+    store: StateStore,
+    persisted: OtaState,
+    counter_store: CounterStore,
+    persisted_counter: u16,
+    version_file: PathBuf,
+    download_path: PathBuf,
+    /// Shared with the blocking tasks installing, committing or rolling back
+    installer: Arc<SlotInstaller>,
+    health: HealthConfig,
+    /// `[mqtt]` with the client id of this controller
+    mqtt_config: MqttConfig,
+    bandwidth: BandwidthConfig,
+    quiesce: QuiesceConfig,
+    /// Memory that has to be available to start an install (KiB)
+    min_free_memory_kb: u64,
+    /// Services paused or stopped until the install is over
+    quiesced: Option<Quiesced>,
+    /// MQTT topic the download progress is published to
+    status_topic: String,
+    check_url: String,
+    /// Authenticates with the device credentials
+    client: reqwest::Client,
+    identity: DeviceIdentity,
+    /// Set once ota-component has to exit for its supervisor to restart it
+    pub restart: bool,
 }
 
 impl SystemIntergration {
-    pub async fn new() -> Self {
-        let ota_logic = OtaLogic::new();
-        let (tx, rx) = mpsc::channel::<Result<TransportOut, OtaErr>>(5);
-        let public_key_path = "public_key.pem";
-        let dsa =  DsaType::new("update_ota.bin".to_string(), public_key_path.to_string());
+    pub async fn new(config: Config) -> Result<Self, OtaError> {
+        let mut ota_logic = OtaLogic::new(
+            config.maintenance,
+            config.retry,
+            Box::new(SystemTimer::default()),
+            StdRng::from_entropy(),
+        );
+        let identity = identity::discover(&config.identity, config.mac.as_deref()).map_err(|e| OtaError::from(e).in_phase(Phase::Startup))?;
+        let client = Credentials::load(&config.credentials_file)
+            .and_then(|credentials| credentials.client())
+            .map_err(|e| e.in_phase(Phase::Startup))?;
+        ota_logic.hc.hc_type = identity.hc_type.clone();
+        let store = StateStore::new(config.state_file);
+        if let Some(state) = store.load() {
+            ota_logic.restore(state);
+        }
+        let persisted = ota_logic.snapshot();
+        let counter_store = CounterStore::new(config.security_counter_file);
+        ota_logic.security_counter = counter_store.load();
+        let persisted_counter = ota_logic.security_counter;
+        if let Ok(name) = version::read_running(&config.version_file) {
+            ota_logic.hc.version_name = name;
+        }
+        ota_logic.confirm_timeout = Duration::from_secs(config.install.confirm_timeout_s);
+        ota_logic.health_interval = Duration::from_secs(config.health.interval_s);
+        let recover = config.quiesce.clone();
+        if let Ok(Err(e)) = tokio::task::spawn_blocking(move || quiesce::recover(&recover)).await {
+            log::error!("Failed to resume services left quiesced: {}", e.in_phase(Phase::Startup));
+        }
+        let installer = SlotInstaller::new(&config.install, install::backend(&config.install, &ota_logic.hc.hc_type));
+        match installer.boot() {
+            Ok(status) => {
+                let (BootStatus::Normal { version } | BootStatus::Trial { version, .. } | BootStatus::RolledBack { version }) = &status;
+                if !version.is_empty() {
+                    record_running(&config.version_file, version);
+                }
+                ota_logic.on_event(OtaLogicIn::Boot(status));
+            }
+            Err(e) => log::error!("Failed to read boot slot: {}", e.in_phase(Phase::Startup)),
+        }
+        let (tx, rx) = mpsc::channel::<Result<TransportOut, OtaError>>(5);
+        let dsa = DsaType::new(config.download_path.to_string_lossy().to_string(), &config.public_key)
+            .map_err(|e| e.in_phase(Phase::Startup))?;
+        let mqtt = MqttConfig { client_id: config.mqtt.client_id_for(&identity.mac), ..config.mqtt };
 
-        SystemIntergration {
+        Ok(SystemIntergration {
             interval: interval(Duration::from_millis(100)),
-            timer: SystemTimer::default(),
             transport: HttpClient {
-               tx: tx,
-               rx: rx,
+               tx,
+               rx,
+               download_path: config.download_path.clone(),
+               report_url: config.report_url,
+               client: client.clone(),
+               progress_interval: Duration::from_millis(config.status.interval_ms),
+               space_margin: config.free_space_margin,
+               memory_margin_kb: config.min_free_memory_kb,
             },
             logic: ota_logic,
-            dsa: dsa,
-            mqtt: MqttDriver::new(
-                "ota".to_string(),
-                "localhost".to_string(),
-                1883,
-                5,  // Thêm tham số keep_alive
-            ).await,
-        }
+            dsa,
+            mqtt: MqttDriver::new(&mqtt).await.map_err(|e| e.in_phase(Phase::Startup))?,
+            mqtt_config: mqtt,
+            store,
+            persisted,
+            persisted_counter,
+            counter_store,
+            version_file: config.version_file,
+            download_path: config.download_path,
+            installer: Arc::new(installer),
+            health: config.health,
+            bandwidth: config.bandwidth,
+            quiesce: config.quiesce,
+            min_free_memory_kb: config.min_free_memory_kb,
+            quiesced: None,
+            status_topic: config.status.topic_for(&identity.mac),
+            check_url: config.check_url,
+            client,
+            identity,
+            restart: false,
+        })
     }
 
-    pub async fn recv(&mut self) -> Result<(),OtaErr> {
+    pub async fn recv(&mut self) -> Result<(), OtaError> {
         select! {
             _ = self.interval.tick() => {
-                self.logic.on_tick(self.timer.now_ms());
+                self.logic.on_tick();
             },
 
             event = self.transport.recv() =>{
                 self.logic.on_event(OtaLogicIn::Transport(event));
             },
 
-            request_update = self.mqtt.recv() => {
-                match request_update
-                {
-                    Ok(response) => {
+            event = self.mqtt.recv() => {
+                match event {
+                    MqttEvent::Message(response) => {
                         if response.topic == "master/ota" {
-                            if response.message == "true" {
-                                self.logic.hc.allow_ota = true;
-                            }
-                            else {
-                                self.logic.hc.allow_ota = false;
-                            }
+                            self.logic.hc.allow_ota = response.message == "true";
                         }
                     }
-                    Err(_) => {
-
+                    MqttEvent::Acked(MqttRequest::KeepAlive) => {
+                        self.logic.on_event(OtaLogicIn::Transport(Ok(TransportOut::ResponseKeepAlive)));
                     }
+                    MqttEvent::Connected => self.logic.on_event(OtaLogicIn::BrokerConnected(true)),
+                    MqttEvent::Disconnected => self.logic.on_event(OtaLogicIn::BrokerConnected(false)),
                 }
-                
             }
         }
     
@@ -84,161 +168,191 @@ impl SystemIntergration {
         while let Some(out) = self.logic.pop_action() {
             match out {
                 OtaLogicOut::CheckOtaEvent => {
-                    let client = HttpClientJson::new_template();
+                    let installed = self.installer.marker().map(|marker| marker.ids).unwrap_or_else(|e| {
+                        log::warn!("Installed version numbers unknown: {}", e);
+                        VersionIds::default()
+                    });
+                    let client = HttpClientJson::check_update(&self.check_url, &self.client, &self.identity, &self.logic.hc.version_name, installed);
                     log::info!("Check ota event");
                     let _ = self.transport.send(TransportIn::CheckOtaHc(client)).await;
                 }
 
                 OtaLogicOut::UpdateOtaEvent(hc) => {
-                    let f_path = "/home/bhien/update_ota.bin".to_string();
-                    log::info!("Updating ota for hc");
-                    match hc {
-                        HcType:: Hc01 => {
-                            match File::open(f_path).await {
-                                Ok(_) => {
-                                    log::info!("Update ota successfully");
-                                }
-                                Err(e) => {
-                                    log::error!("Update ota failed: {}", e);
-                                }
-                            }
-                        }
+                    log::info!("Updating ota for {:?}", hc);
+                    let (image, version) = (self.download_path.clone(), self.logic.state.target_version.clone());
+                    let ids = self.logic.state.manifest.as_ref()
+                        .map(|manifest| VersionIds { version_number: manifest.version_number, version_min_id: manifest.version_min_id })
+                        .unwrap_or_default();
+                    let result = self.run_installer(move |installer| installer.install(&image, &version, ids)).await;
+                    self.resume_services().await;
+                    if result.is_ok() {
+                        Downloader::new(&self.download_path).discard().await;
+                    }
+                    self.logic.on_event(OtaLogicIn::Installed(result.map_err(|e| e.in_phase(Phase::Install))));
+                }
 
-                        HcType:: Hc02 => {
+                OtaLogicOut::CommitEvent => {
+                    let result = self.run_installer(|installer| installer.commit()).await;
+                    if result.is_ok() {
+                        record_running(&self.version_file, &self.logic.state.target_version);
+                    }
+                    self.logic.on_event(OtaLogicIn::Committed(result.map_err(|e| e.in_phase(Phase::Commit))));
+                }
 
-                        }
+                OtaLogicOut::RollbackEvent => {
+                    let result = self.run_installer(|installer| installer.rollback()).await;
+                    if let Ok(version) = &result {
+                        record_running(&self.version_file, version);
                     }
+                    self.logic.on_event(OtaLogicIn::RolledBack(result.map_err(|e| e.in_phase(Phase::Rollback))));
                 }
 
-                OtaLogicOut::VerifyEvent => {
-                    // Đọc dữ liệu từ file
-                    let fpath_ota = "update_ota.bin".to_string();
-                    let mut data_file = OpenOptions::new()
-                        .read(true)
-                        .open(&fpath_ota)
-                        .await.unwrap();
-                
-                    let mut data = Vec::new();
-                    let _ = data_file.read_to_end(&mut data).await;
-                    
-                    // Thực hiện quá trình ký
-                    let signature = match self.dsa.sign(&data) {
-                        Ok(signature) => signature,
-                        Err(_) => {
-                            log::error!("Error signing data");
-                            return Ok(());
-                        }
-                    };
+                OtaLogicOut::HealthCheckEvent => {
+                    let results = health::check(&self.health, &self.mqtt_config).await;
+                    self.logic.on_event(OtaLogicIn::Health(results));
+                }
 
-                    // Ghi dữ liệu đã ký vào cuối file
-                    let mut data_file = OpenOptions::new()
-                        .write(true)
-                        .append(true)
-                        .open(&fpath_ota)
-                        .await.unwrap();
-                    
-                    let _ = data_file.write_all(&signature).await;
-
-                    match self.dsa.verify(signature.len()).await{
-                        Ok(()) => {
-                            log::info!("Verify successfully");
-                        }
-                        Err(e) => {
-                            if e == OtaErr::VerifyErr {
-                                log::error!("Verify processing error");
-                                
-                            }
-                            else if e == OtaErr::VerifyNotEqualErr {
-                                log::error!("Verify not equal");
-                            }
-                        }
+                OtaLogicOut::ReportEvent => {
+                    if let Some(report) = self.logic.state.report.clone() {
+                        let _ = self.transport.send(TransportIn::Report(report)).await;
                     }
                 }
 
-                OtaLogicOut::CompareVersionEvent => {
-                    let mut version = Vec::new();
-                    let _ = File::open("ota_version.txt") // Update the path to your private key
-                        .await.expect("Failed to open version file")
-                        .read_to_end(&mut version).await;
-
-                    match String::from_utf8(version) {
-                        Ok(s) => {
-                            log::info!("Compare versioon successfully");
-                            if self.logic.hc.version_name == s {
-                                log::info!(
-                                    "Version name equal"
-                                ); 
-                            }else {
-                                log::info!(
-                                    "Version name not equal"
-                                );
-                                self.logic.on_event(OtaLogicIn::Push(OtaLogicOut::GetLinkEvent));
-                            }
-                        }
-                        Err(e) => {
-                            log::error!("Error compare version: {}", e);
-                        }
+                OtaLogicOut::ProgressEvent => {
+                    if let Some(status) = self.logic.status() {
+                        let _ = self.mqtt.publish_status(&self.status_topic, &status);
                     }
                 }
+
+                OtaLogicOut::RestartEvent => {
+                    self.persist();
+                    self.restart_into_slot().await;
+                }
+
+                OtaLogicOut::VerifyEvent => {
+                    let result = self.dsa.verify().await.map_err(|e| e.in_phase(Phase::Verify));
+                    match &result {
+                        Ok(()) => log::info!("Verify successfully"),
+                        Err(e) => log::error!("{}", e),
+                    }
+                    self.logic.on_event(OtaLogicIn::Verify(result));
+                }
+
+                OtaLogicOut::ManifestEvent => {
+                    let signed = self.logic.offer.as_ref().and_then(|offer| offer.manifest.as_ref());
+                    let result = self.dsa.open_manifest(signed).map_err(|e| OtaError::from(e).in_phase(Phase::Check));
+                    self.logic.on_event(OtaLogicIn::Manifest(result));
+                }
+
+                OtaLogicOut::CompareVersionEvent => {
+                    let running = version::read_running(&self.version_file).map_err(|e| e.in_phase(Phase::Check));
+                    self.logic.on_event(OtaLogicIn::RunningVersion(running));
+                }
                 
                 OtaLogicOut::GetLinkEvent => {
-                    let _ =  self.transport.send(TransportIn::GetLink(self.logic.hc.link.clone())).await;
+                    let request = DownloadRequest {
+                        link: self.logic.state.link.clone(),
+                        checksum: self.logic.state.checksum.clone(),
+                        signature_link: self.logic.state.signature_link.clone(),
+                        size: self.logic.state.manifest.as_ref().map(|manifest| manifest.size),
+                        delta: self.logic.delta().and_then(|delta| Some(DeltaRequest {
+                            link: download::sibling_link(&self.logic.state.link, &delta.patch)?,
+                            base: self.installer.installed_image()?,
+                            delta: delta.clone(),
+                        })),
+                        rate_limit: self.bandwidth.rate_limit(self.logic.in_window()),
+                    };
+                    let _ =  self.transport.send(TransportIn::GetLink(request)).await;
                 }
 
                 OtaLogicOut::KeepAliveEvent => {
-                    let _ =  self.transport.send(TransportIn::KeepAlive).await;
+                    let _ = self.mqtt.keep_alive().await;
                 }
 
                 OtaLogicOut::SuppentEvent => {
-                    let processes = processes().unwrap();
-                    tokio::time::sleep(Duration::from_secs(1)).await;
-
-                    log::info!("{:>6} {:>4}", "PID", "%CPU");
-                    let mut cpu_percentages: Vec<(f32, i32)> = Vec::new();
-
-                    for p in processes {
-                        if let Ok(mut process) = p {
-                            let cpu_percent = process.cpu_percent().unwrap();
-                            let pid = process.pid();
-
-                            cpu_percentages.push((cpu_percent, pid as i32));
-                        }
+                    // Anything still held from an earlier attempt is resumed first
+                    self.resume_services().await;
+                    let config = self.quiesce.clone();
+                    let min_free_kb = self.min_free_memory_kb;
+                    // Stopping services can take a while, keep the runtime free for MQTT
+                    let result = match tokio::task::spawn_blocking(move || {
+                        quiesce::check_memory(min_free_kb)?;
+                        quiesce::quiesce(&config)
+                    }).await {
+                        Ok(result) => result.map(|quiesced| self.quiesced = Some(quiesced)),
+                        Err(e) => Err(OtaError::new(OtaErr::QuiesceErr).with_source(e)),
+                    };
+                    self.logic.on_event(OtaLogicIn::Quiesced(result.map_err(|e| e.in_phase(Phase::Quiesce))));
+                    if self.logic.state.phase != OtaPhase::Installing {
+                        self.resume_services().await;
                     }
-                    
-                    cpu_percentages.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
-
-                    //kiểm tra dung lượngg
-                    let mut sys = System::new_all();
-                    sys.refresh_all();
-                    let total_memory = sys.total_memory();
-                    let used_memory = sys.used_memory();
-
-                    if total_memory as i64 - used_memory as i64 >= (55000) {
-                        //lấy các pid tốn nhất và gửi đi 
-                        let mut proccess_suppend: Vec<i32> = Vec::new();
-                        if let Some((max_cpu_percent, max_pid)) = cpu_percentages.first() {
-                            log::info!("Processe uses the most memory:");
-                            log::info!("PID: {}, CPU Percent: {}%", max_pid, max_cpu_percent);
-                            proccess_suppend.push(*max_pid);
-                        } else {
-                            log::error!("No process found.");
-                        }
+                }
+            }
 
-                        if let Some((second_cpu_percent, second_pid)) = cpu_percentages.get(1) {
-                            log::info!("Processe uses the second memory:");
-                            log::info!("PID: {}, CPU Percent: {}%", second_pid, second_cpu_percent);
-                            proccess_suppend.push(*second_pid);
+        }
+        self.persist();
+        Ok(())
+    }
 
-                        } else {
-                            log::error!("No process found.");
-                        }
+    /// Runs an installer step off the runtime: copying and syncing an image
+    /// takes long enough to stall the MQTT session.
+    async fn run_installer<T, E>(&self, step: impl FnOnce(&SlotInstaller) -> Result<T, E> + Send + 'static) -> Result<T, OtaError>
+    where
+        T: Send + 'static,
+        E: Into<OtaError> + Send + 'static,
+    {
+        let installer = self.installer.clone();
+        match tokio::task::spawn_blocking(move || step(&installer)).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(e) => Err(OtaError::new(OtaErr::InstallErr).with_source(e)),
+        }
+    }
 
-                        let _ =  self.transport.send(TransportIn::Suppend(proccess_suppend)).await;
-                    }
-                }
+    /// Resumes the services quiesced for an install, if any.
+    async fn resume_services(&mut self) {
+        if let Some(quiesced) = self.quiesced.take() {
+            if let Err(e) = tokio::task::spawn_blocking(move || quiesced.resume()).await {
+                log::error!("Failed to resume services: {}", e);
             }
+        }
+    }
 
+    /// Runs the configured restart command, or asks `main` to exit when there
+    /// is none.
+    async fn restart_into_slot(&mut self) {
+        let Some((program, args)) = self.installer.restart_command().split_first() else {
+            log::info!("Exiting to restart into the new slot");
+            self.restart = true;
+            return;
+        };
+        log::info!("Restarting with {:?}", self.installer.restart_command());
+        match tokio::process::Command::new(program).args(args).status().await {
+            Ok(status) if status.success() => {}
+            Ok(status) => log::error!("Restart command failed: {}", status),
+            Err(e) => log::error!("Failed to run restart command: {}", e),
+        }
+    }
+
+    fn persist(&mut self) {
+        // Before the state, which forgets the installed manifest
+        let counter = self.logic.security_counter;
+        if counter != self.persisted_counter {
+            match self.counter_store.save(counter) {
+                Ok(()) => self.persisted_counter = counter,
+                Err(e) => log::error!("Failed to save the security counter: {}", e),
+            }
+        }
+        let state = self.logic.snapshot();
+        if state != self.persisted && self.store.save(&state).is_ok() {
+            self.persisted = state;
         }
-        Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Records the version now running. A failure is only logged, the version
+/// file is read again at the next check.
+fn record_running(path: &Path, version: &str) {
+    if let Err(e) = version::write_running(path, version) {
+        log::error!("Failed to record the running version: {}", e);
+    }
+}
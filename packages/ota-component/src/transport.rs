@@ -1,17 +1,26 @@
 use serde::{Deserialize, Serialize};
 pub mod http_client;
 pub mod mqtt;
-use crate::error::OtaErr;
+pub mod download;
+#[cfg(test)]
+pub mod test_server;
+#[cfg(test)]
+pub mod test_broker;
+use crate::error::{OtaErr, OtaError, Phase};
+use download::{DownloadRequest, Progress};
+use ota_package::manifest::SignedManifest;
+use crate::health::ProbeResult;
+use crate::identity::DeviceIdentity;
+use crate::install::VersionIds;
 
 
-pub struct HeaderJson {
-    pub name: &'static str,
-    pub value: &'static str,
-}
-
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct BodyJson {
     pub mac: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub model: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub serial: String,
     pub version_name: String,
     pub version_min_id: u32,
     pub version_number: u32
@@ -25,6 +34,12 @@ pub struct Data {
     pub download_id: u64,
     pub link: String,
     pub checksum: String,
+    /// Detached signature of the image, when it does not carry one itself
+    #[serde(default)]
+    pub signature_link: String,
+    /// Vendor-signed description of the update, required before downloading
+    #[serde(default)]
+    pub manifest: Option<SignedManifest>,
 }
 
 #[derive(Debug, Clone, Default ,Serialize, Deserialize, PartialEq)]
@@ -36,16 +51,51 @@ pub struct ResponseOtaHc {
 }
 // Define the HttpClientJson struct
 pub struct HttpClientJson {
-    pub url: &'static str,
-    pub headers: HeaderJson,
+    pub url: String,
+    /// Authenticates with the device credentials
+    pub client: reqwest::Client,
     pub body: BodyJson,
     pub response: ResponseOtaHc// Thêm một trường để giữ giá trị response
 }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateOutcome {
+    Committed,
+    RolledBack,
+}
+
+/// How an installed update turned out, sent to the server once known.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UpdateReport {
+    pub download_id: u64,
+    pub version_name: String,
+    /// Version running now, the previous one after a rollback
+    pub running_version: String,
+    pub outcome: UpdateOutcome,
+    /// Health probes of the last round before the outcome
+    pub probes: Vec<ProbeResult>,
+}
+
+impl UpdateReport {
+    pub async fn send(&self, url: &str, client: &reqwest::Client) -> Result<(), OtaError> {
+        let response = client
+            .post(url)
+            .json(self)
+            .send()
+            .await
+            .map_err(|e| OtaError::http(OtaErr::ReportErr, e).in_phase(Phase::Report))?;
+        if !response.status().is_success() {
+            log::error!("Update report refused: {}", response.status());
+            return Err(OtaError::new(OtaErr::ReportErr).with_status(response.status().as_u16()).in_phase(Phase::Report));
+        }
+        Ok(())
+    }
+}
+
 pub enum TransportIn {
     CheckOtaHc(HttpClientJson),
-    GetLink(String),
-    KeepAlive,
-    Suppend(Vec<i32>),
+    GetLink(DownloadRequest),
+    Report(UpdateReport),
 }
 
 #[derive(PartialEq, Clone)]
@@ -53,67 +103,60 @@ pub enum TransportOut {
     ResponseRequest(ResponseOtaHc),
     ResponseLink,
     ResponseKeepAlive,
-    ResponseSuppend
+    ResponseReport,
+    /// A download is still running
+    Progress(Progress),
 }
 impl HttpClientJson {
-    pub fn new(url: &'static str, headers: HeaderJson, body: BodyJson) -> Self {
-        Self { url, headers, body, response: ResponseOtaHc::default()}
+    pub fn new(url: String, client: reqwest::Client, body: BodyJson) -> Self {
+        Self { url, client, body, response: ResponseOtaHc::default()}
     }
-    pub fn new_template() -> Self {
+    /// Update check of the controller `identity`, running `version_name`
+    /// numbered `installed`.
+    pub fn check_update(url: &str, client: &reqwest::Client, identity: &DeviceIdentity, version_name: &str, installed: VersionIds) -> Self {
         HttpClientJson {
-            url: "https://api.smarthome.lumi.com.vn/ota/check-update-ota",
-            headers: HeaderJson {
-                name: "x-lumi-api-key",
-                value: "98CPB8ITIRGHVO3OJ5QT",
-            },
+            url: url.to_string(),
+            client: client.clone(),
             body: BodyJson {
-                mac: "14:c9:cf:17:af:8e".to_string(),
-                version_name: "1.0.1".to_string(),
-                version_min_id: 0, 
-                version_number: 1,
+                mac: identity.mac.clone(),
+                model: identity.model.clone(),
+                serial: identity.serial.clone(),
+                version_name: version_name.to_string(),
+                version_min_id: installed.version_min_id.into(),
+                version_number: installed.version_number.into(),
             },
             response: ResponseOtaHc::default()
         }
     }
-    pub async fn send(&mut self) -> Result<(), OtaErr>
+    pub async fn send(&mut self) -> Result<(), OtaError>
     {
-        let client = reqwest::Client::new();
-        let json_body = serde_json::to_value(&self.body).map_err(|_| {OtaErr::HttpErr})?;
+        let failed = |e: OtaError| e.in_phase(Phase::Check);
+        let json_body = serde_json::to_value(&self.body).map_err(|e| failed(OtaError::new(OtaErr::HttpErr).with_source(e)))?;
         
-        let response = client
-            .post(self.url)
-            .header(self.headers.name, self.headers.value)
+        let response = self.client
+            .post(&self.url)
             .json(&json_body)
             .send()
-            .await.map_err(|_| {OtaErr::HttpErr})?;
-
-        if response.status().is_success() {
-            log::info!("Response successfully");
-            let json_result: Result<ResponseOtaHc, _> = response.json().await;
-            match json_result {
-                Ok(parsed_response) => {
-                    log::info!("Response successfully");
-                    self.response = parsed_response;
-                }
-                Err(_) => {
-                    // Print more information about the error
-                    log::info!("The final version");
-                }
-            }
-        }
-        else {
-            return Err(OtaErr::HttpErr);
+            .await.map_err(|e| failed(OtaError::http(OtaErr::HttpErr, e)))?;
+
+        if !response.status().is_success() {
+            return Err(failed(OtaError::new(OtaErr::HttpErr).with_status(response.status().as_u16())));
         }
+        self.response = response.json().await.map_err(|e| if e.is_decode() {
+            failed(OtaError::new(OtaErr::JsonErr).with_source(e))
+        } else {
+            failed(OtaError::http(OtaErr::HttpErr, e))
+        })?;
+        log::info!("Response successfully");
         Ok(())
     }
-    pub async fn recv(&mut self) -> Result<TransportOut, OtaErr> {
-        // Kiểm tra xem response có tồn tại hay không
-        if self.response.success == true {
+    pub async fn recv(&mut self) -> Result<TransportOut, OtaError> {
+        if self.response.success {
             let res = self.response.clone();
             Ok(TransportOut::ResponseRequest(res))
         } 
         else {
-            Err(OtaErr::HttpErr)
+            Err(OtaError::new(OtaErr::HttpErr).in_phase(Phase::Check))
         }
     }
 }
@@ -121,7 +164,38 @@ impl HttpClientJson {
 
 #[async_trait::async_trait]
 pub trait Transport {
-    async fn send(&mut self, data: TransportIn) -> Result<(), OtaErr>;
-    async fn recv(&mut self) -> Result<TransportOut, OtaErr>;
+    async fn send(&mut self, data: TransportIn) -> Result<(), OtaError>;
+    async fn recv(&mut self) -> Result<TransportOut, OtaError>;
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::logic::HcType;
+    use test_server::TestServer;
+
+    fn check(server: &TestServer) -> HttpClientJson {
+        let identity = DeviceIdentity { mac: "14:c9:cf:17:af:8e".to_string(), hc_type: HcType::Hc01, model: String::new(), serial: String::new() };
+        let installed = VersionIds { version_number: 7, version_min_id: 5 };
+        HttpClientJson::check_update(&server.url_of("/check"), &reqwest::Client::new(), &identity, "1.0.1", installed)
+    }
+
+    #[tokio::test]
+    async fn test_check_response() {
+        let response = ResponseOtaHc { success: true, status_code: 200, data: Data::default() };
+        let server = TestServer::start(Vec::new()).await.with_file("/check", serde_json::to_vec(&response).unwrap());
+        let mut client = check(&server);
+        // The numbers of the installed version, not made up ones
+        assert_eq!((client.body.version_number, client.body.version_min_id), (7, 5));
+        client.send().await.unwrap();
+        assert!(client.recv().await == Ok(TransportOut::ResponseRequest(response)));
+
+        let server = TestServer::start(Vec::new()).await.with_file("/check", br#"{"success": true, "statusCo"#.to_vec());
+        let e = check(&server).send().await.unwrap_err();
+        assert_eq!((e.kind.clone(), e.phase), (OtaErr::JsonErr, Some(Phase::Check)));
+        assert!(std::error::Error::source(&e).is_some());
+
+        let e = HttpClientJson { url: server.url_of("/missing"), ..check(&server) }.send().await.unwrap_err();
+        assert_eq!((e.kind, e.status), (OtaErr::HttpErr, Some(404)));
+    }
+}
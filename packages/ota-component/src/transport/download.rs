@@ -0,0 +1,983 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use reqwest::header::{CONTENT_RANGE, ETAG, IF_RANGE, RANGE};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::fs::{self, OpenOptions};
+use openssl::hash::Hasher;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+use crate::error::{OtaErr, OtaError};
+use crate::quiesce;
+use crate::security::Checksum;
+use crate::transport::TransportOut;
+use ota_package::delta;
+use ota_package::manifest::{self, Delta};
+
+/// Bytes written between two updates of the `.part.json` metadata.
+const META_INTERVAL: u64 = 256 * 1024;
+/// Time to open a connection to the download server.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Sidecar of the `.part` file describing what it holds, so a download can be
+/// resumed after the connection drops or the controller restarts.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PartMeta {
+    pub link: String,
+    pub downloaded: u64,
+    pub total: Option<u64>,
+    pub etag: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DownloadRequest {
+    pub link: String,
+    /// Expected digest of the whole image, see `Checksum::parse`
+    pub checksum: String,
+    /// Detached vendor signature of the image, empty when it is appended
+    /// to the image instead
+    pub signature_link: String,
+    /// Exact image size from the manifest, larger downloads are cut short
+    pub size: Option<u64>,
+    /// Patch from the installed version to try first
+    pub delta: Option<DeltaRequest>,
+    /// Bytes per second the download may use, unlimited when `None`
+    pub rate_limit: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeltaRequest {
+    pub link: String,
+    /// Installed image the patch applies to
+    pub base: PathBuf,
+    pub delta: Delta,
+}
+
+/// Download rate limits, so updates leave the customer's uplink usable.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct BandwidthConfig {
+    /// Bytes per second, 0 for unlimited
+    pub limit: u64,
+    /// Bytes per second for downloads started inside a maintenance window,
+    /// `limit` when unset and 0 for unlimited
+    pub window_limit: Option<u64>,
+}
+
+impl BandwidthConfig {
+    pub fn rate_limit(&self, in_window: bool) -> Option<u64> {
+        let limit = if in_window { self.window_limit.unwrap_or(self.limit) } else { self.limit };
+        Some(limit).filter(|limit| *limit > 0)
+    }
+}
+
+/// Holds a stream back to `limit` bytes per second on average since it started.
+struct RateLimiter {
+    limit: u64,
+    started: Instant,
+    bytes: u64,
+}
+
+impl RateLimiter {
+    fn new(limit: u64) -> Self {
+        RateLimiter { limit, started: Instant::now(), bytes: 0 }
+    }
+
+    async fn consume(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        let due = Duration::from_secs_f64(self.bytes as f64 / self.limit as f64);
+        let elapsed = self.started.elapsed();
+        if due > elapsed {
+            tokio::time::sleep(due - elapsed).await;
+        }
+    }
+}
+
+/// Link of `name` in the same directory as `link`.
+pub fn sibling_link(link: &str, name: &str) -> Option<String> {
+    reqwest::Url::parse(link).ok()?.join(name).ok().map(String::from)
+}
+
+/// What a download is fetching.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadStage {
+    /// Delta patch from the installed version
+    Patch,
+    #[default]
+    Image,
+}
+
+/// How far a running download is.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Progress {
+    pub stage: DownloadStage,
+    pub bytes: u64,
+    pub total: Option<u64>,
+    /// Bytes per second since the current request started
+    pub rate: u64,
+    /// Seconds left at `rate`
+    pub eta_s: Option<u64>,
+}
+
+/// Sends `TransportOut::Progress` from a download, at most once per
+/// `interval` apart from the last one.
+pub struct ProgressReporter {
+    tx: mpsc::Sender<Result<TransportOut, OtaError>>,
+    interval: Duration,
+    throttle: Mutex<Throttle>,
+}
+
+struct Throttle {
+    /// Start of the current request and bytes at that point
+    started: Instant,
+    start_bytes: u64,
+    last: Option<Instant>,
+}
+
+impl ProgressReporter {
+    pub fn new(tx: mpsc::Sender<Result<TransportOut, OtaError>>, interval: Duration) -> Self {
+        let throttle = Throttle { started: Instant::now(), start_bytes: 0, last: None };
+        ProgressReporter { tx, interval, throttle: Mutex::new(throttle) }
+    }
+
+    /// Measures the rate from here on.
+    fn restart(&self, bytes: u64) {
+        let mut throttle = self.throttle.lock().unwrap_or_else(|e| e.into_inner());
+        throttle.started = Instant::now();
+        throttle.start_bytes = bytes;
+    }
+
+    fn report(&self, stage: DownloadStage, bytes: u64, total: Option<u64>) {
+        let now = Instant::now();
+        let mut throttle = self.throttle.lock().unwrap_or_else(|e| e.into_inner());
+        let done = total == Some(bytes);
+        if !done && throttle.last.is_some_and(|last| now.duration_since(last) < self.interval) {
+            return;
+        }
+        throttle.last = Some(now);
+
+        let elapsed_ms = now.duration_since(throttle.started).as_millis().max(1) as u64;
+        let rate = bytes.saturating_sub(throttle.start_bytes) * 1000 / elapsed_ms;
+        let eta_s = match total {
+            Some(total) if rate > 0 => Some(total.saturating_sub(bytes).div_ceil(rate)),
+            _ => None,
+        };
+        // Progress is dropped rather than holding up the download
+        let _ = self.tx.try_send(Ok(TransportOut::Progress(Progress { stage, bytes, total, rate, eta_s })));
+    }
+}
+
+/// Streams a firmware image to disk, resuming with HTTP `Range` requests.
+/// Data goes to `<path>.part` and is renamed to `path` once complete.
+pub struct Downloader {
+    client: reqwest::Client,
+    path: PathBuf,
+    /// Consecutive attempts without progress before giving up
+    pub max_attempts: u32,
+    /// Space to leave free on the filesystem after the image is downloaded
+    pub space_margin: u64,
+    /// Memory to leave available next to the base image while a patch is
+    /// applied (KiB), otherwise the whole image is downloaded
+    pub memory_margin_kb: u64,
+    /// Longest wait for the response or the next chunk of it before the
+    /// connection is taken as dead and the download resumed
+    pub read_timeout: Duration,
+    progress: Option<Arc<ProgressReporter>>,
+    stage: DownloadStage,
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+impl Downloader {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Downloader {
+            client,
+            path: path.into(),
+            max_attempts: 3,
+            space_margin: 0,
+            memory_margin_kb: 0,
+            read_timeout: Duration::from_secs(30),
+            progress: None,
+            stage: DownloadStage::Image,
+        }
+    }
+
+    pub fn with_progress(self, progress: ProgressReporter) -> Self {
+        Downloader { progress: Some(Arc::new(progress)), ..self }
+    }
+
+    pub fn part_path(&self) -> PathBuf {
+        with_suffix(&self.path, ".part")
+    }
+
+    pub fn meta_path(&self) -> PathBuf {
+        with_suffix(&self.path, ".part.json")
+    }
+
+    pub fn signature_path(&self) -> PathBuf {
+        with_suffix(&self.path, ".sig")
+    }
+
+    /// Removes the image and its signature once installed, so they can never
+    /// be installed again.
+    pub async fn discard(&self) {
+        for path in [self.path.clone(), self.signature_path()] {
+            if let Err(e) = fs::remove_file(&path).await {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("Failed to remove {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+
+    /// Downloads the image, from a delta patch when there is one that fits
+    /// the installed version, and its detached signature. Returns the size
+    /// of the image.
+    pub async fn download(&self, request: &DownloadRequest) -> Result<u64, OtaError> {
+        let checksum = Checksum::parse(&request.checksum)?;
+        if let Some(size) = request.size {
+            self.check_space(size).await?;
+        }
+        // A signature left from another image must never be paired with this one
+        let _ = fs::remove_file(self.signature_path()).await;
+
+        let delta = match &request.delta {
+            Some(delta) => self.apply_delta(delta, &checksum, request.rate_limit).await.inspect_err(|e| {
+                log::warn!("Delta from {} failed ({}), downloading the whole image", delta.delta.base_version, e);
+            }).ok(),
+            None => None,
+        };
+        let size = match delta {
+            Some(size) => size,
+            None => self.fetch_verified(&request.link, &checksum, request.size, request.rate_limit).await?,
+        };
+
+        if !request.signature_link.is_empty() {
+            self.fetch_signature(&request.signature_link).await?;
+        }
+        Ok(size)
+    }
+
+    /// Fails with `NoSpaceErr` unless the rest of an image of `size` bytes
+    /// fits next to the target path with `space_margin` to spare.
+    async fn check_space(&self, size: u64) -> Result<(), OtaError> {
+        let downloaded = fs::metadata(self.part_path()).await.map(|m| m.len()).unwrap_or(0);
+        let needed = size.saturating_sub(downloaded).saturating_add(self.space_margin);
+        let available = available_space(&self.path)?;
+        if available < needed {
+            log::error!("Need {} bytes free for the image, {} available", needed, available);
+            return Err(OtaError::new(OtaErr::NoSpaceErr).with_path(&self.path));
+        }
+        Ok(())
+    }
+
+    /// Builds the image from the installed one and a downloaded patch, and
+    /// checks it against the checksum of the whole image.
+    async fn apply_delta(&self, request: &DeltaRequest, checksum: &Checksum, rate_limit: Option<u64>) -> Result<u64, OtaError> {
+        let Delta { base_size, base_sha256, size, sha256, .. } = &request.delta;
+        // The base is patched in memory, the result goes straight to disk
+        quiesce::check_memory(base_size.div_ceil(1024).saturating_add(self.memory_margin_kb))?;
+        let mut base = Vec::new();
+        let unreadable = |e| OtaError::io(OtaErr::ChecksumErr, &request.base, e);
+        fs::File::open(&request.base).await
+            .map_err(unreadable)?
+            .take(*base_size)
+            .read_to_end(&mut base)
+            .await
+            .map_err(unreadable)?;
+        if base.len() as u64 != *base_size || manifest::sha256(&base[..])?.1 != *base_sha256 {
+            log::warn!("Installed image is not the base of the patch");
+            return Err(OtaError::new(OtaErr::ChecksumErr).with_path(&request.base));
+        }
+
+        let patcher = Downloader {
+            progress: self.progress.clone(),
+            stage: DownloadStage::Patch,
+            ..Downloader::new(with_suffix(&self.path, ".patch"))
+        };
+        patcher.fetch_verified(&request.link, &Checksum::parse(sha256)?, Some(*size), rate_limit).await?;
+
+        let (patch, patched) = (patcher.path.clone(), with_suffix(&self.path, ".patched"));
+        let output = patched.clone();
+        let built = tokio::task::spawn_blocking(move || {
+            let patch = std::fs::File::open(&patch).map_err(|e| OtaError::io(OtaErr::DownloadErr, &patch, e))?;
+            let write_failed = |e| write_error(&output, e);
+            let mut out = std::io::BufWriter::new(std::fs::File::create(&output).map_err(write_failed)?);
+            delta::apply(&base, std::io::BufReader::new(patch), &mut out).map_err(|e| {
+                log::error!("Patch does not apply");
+                OtaError::from(e)
+            })?;
+            let file = out.into_inner().map_err(|e| write_failed(e.into_error()))?;
+            file.sync_all().map_err(write_failed)?;
+            file.metadata().map(|metadata| metadata.len()).map_err(write_failed)
+        }).await.map_err(|e| OtaError::new(OtaErr::ChecksumErr).with_source(e)).and_then(|built| built);
+        let _ = fs::remove_file(&patcher.path).await;
+
+        let mut hasher = checksum.hasher()?;
+        let verified = match built {
+            Ok(len) => hash_file(&patched, &mut hasher, len).await
+                .and_then(|_| hasher.finish().map_err(|e| OtaError::new(OtaErr::ChecksumErr).with_source(e)))
+                .map(|digest| (len, checksum.matches(&digest))),
+            Err(e) => Err(e),
+        };
+        let len = match verified {
+            Ok((len, true)) => len,
+            Ok((_, false)) => {
+                log::error!("Patched image does not match the checksum");
+                let _ = fs::remove_file(&patched).await;
+                return Err(OtaErr::ChecksumErr.into());
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&patched).await;
+                return Err(e);
+            }
+        };
+        fs::rename(&patched, &self.path).await.map_err(|e| write_error(&self.path, e))?;
+        // A partial full download is of no use any more
+        let _ = fs::remove_file(self.part_path()).await;
+        let _ = fs::remove_file(self.meta_path()).await;
+        log::info!("Image built from a {} bytes patch", size);
+        Ok(len)
+    }
+
+    /// Downloads `link` to the target path, continuing a previous partial
+    /// download of the same link if there is one, and checks it against
+    /// `checksum` while it streams in, at no more than `rate_limit` bytes per
+    /// second.
+    async fn fetch_verified(&self, link: &str, checksum: &Checksum, size: Option<u64>, rate_limit: Option<u64>) -> Result<u64, OtaError> {
+        let mut meta = self.load_meta(link).await;
+        let mut hasher = checksum.hasher()?;
+        if meta.downloaded > 0 {
+            hash_file(&self.part_path(), &mut hasher, meta.downloaded).await?;
+        }
+
+        let mut failures = 0;
+        loop {
+            let before = meta.downloaded;
+            match self.fetch(link, size, rate_limit, &mut meta, checksum, &mut hasher).await {
+                Ok(()) => break,
+                Err(e) if e.kind == OtaErr::ChecksumErr => {
+                    let _ = fs::remove_file(self.part_path()).await;
+                    let _ = fs::remove_file(self.meta_path()).await;
+                    return Err(e);
+                }
+                Err(e) => {
+                    self.save_meta(&meta).await;
+                    if meta.downloaded > before {
+                        failures = 0;
+                    }
+                    failures += 1;
+                    if failures >= self.max_attempts {
+                        log::error!("Download failed at {} bytes: {}", meta.downloaded, e);
+                        return Err(e);
+                    }
+                    log::warn!("Download interrupted at {} bytes ({}), resuming", meta.downloaded, e);
+                }
+            }
+        }
+
+        let digest = hasher.finish().map_err(|e| OtaError::new(OtaErr::ChecksumErr).with_source(e))?;
+        if !checksum.matches(&digest) {
+            log::error!("Checksum mismatch: expected {}, got {}", hex::encode(&checksum.digest), hex::encode(digest));
+            let _ = fs::remove_file(self.part_path()).await;
+            let _ = fs::remove_file(self.meta_path()).await;
+            return Err(OtaErr::ChecksumErr.into());
+        }
+
+        fs::rename(self.part_path(), &self.path).await.map_err(|e| {
+            log::error!("Failed to move download into place: {}", e);
+            write_error(&self.path, e)
+        })?;
+        let _ = fs::remove_file(self.meta_path()).await;
+        log::info!("Download complete, {} bytes", meta.downloaded);
+        Ok(meta.downloaded)
+    }
+
+    /// Downloads the detached signature next to the image.
+    async fn fetch_signature(&self, link: &str) -> Result<(), OtaError> {
+        let response = self.client.get(link).send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| {
+                log::error!("Failed to get signature {}: {}", link, e);
+                OtaError::http(OtaErr::DownloadErr, e)
+            })?;
+        let signature = response.bytes().await.map_err(|e| OtaError::http(OtaErr::DownloadErr, e))?;
+        let path = self.signature_path();
+        fs::write(&path, &signature).await.map_err(|e| write_error(&path, e))
+    }
+
+    /// Metadata of the partial download to continue, or a fresh one when the
+    /// `.part` file belongs to another link or is missing.
+    async fn load_meta(&self, link: &str) -> PartMeta {
+        let fresh = PartMeta { link: link.to_string(), ..PartMeta::default() };
+        let meta: Option<PartMeta> = fs::read(self.meta_path()).await.ok()
+            .and_then(|content| serde_json::from_slice(&content).ok());
+        let part_len = fs::metadata(self.part_path()).await.map(|m| m.len()).ok();
+
+        match (meta, part_len) {
+            (Some(meta), Some(len)) if meta.link == link => {
+                log::info!("Resuming download of {} from {} bytes", link, len);
+                // The file is the truth, the metadata may lag behind it
+                PartMeta { downloaded: len, ..meta }
+            }
+            _ => {
+                let _ = fs::remove_file(self.part_path()).await;
+                fresh
+            }
+        }
+    }
+
+    async fn save_meta(&self, meta: &PartMeta) {
+        if let Ok(data) = serde_json::to_vec(meta) {
+            if let Err(e) = fs::write(self.meta_path(), data).await {
+                log::warn!("Failed to save download metadata: {}", e);
+            }
+        }
+    }
+
+    /// One HTTP request, appending to the `.part` file from `meta.downloaded`.
+    async fn fetch(&self, link: &str, size: Option<u64>, rate_limit: Option<u64>, meta: &mut PartMeta, checksum: &Checksum, hasher: &mut Hasher) -> Result<(), OtaError> {
+        if meta.total.is_some() && Some(meta.downloaded) == meta.total {
+            return Ok(());
+        }
+
+        let mut request = self.client.get(link);
+        if meta.downloaded > 0 {
+            request = request.header(RANGE, format!("bytes={}-", meta.downloaded));
+            if let Some(etag) = &meta.etag {
+                request = request.header(IF_RANGE, etag);
+            }
+        }
+        let mut response = match tokio::time::timeout(self.read_timeout, request.send()).await {
+            Ok(response) => response.map_err(|e| {
+                log::error!("Download request failed: {}", e);
+                OtaError::http(OtaErr::DownloadErr, e)
+            })?,
+            Err(elapsed) => return Err(OtaError::new(OtaErr::DownloadErr).with_source(elapsed)),
+        };
+
+        let resume = match response.status() {
+            StatusCode::PARTIAL_CONTENT => true,
+            StatusCode::OK => false,
+            StatusCode::RANGE_NOT_SATISFIABLE => {
+                // The image changed under the partial file, start over
+                meta.downloaded = 0;
+                meta.total = None;
+                let _ = fs::remove_file(self.part_path()).await;
+                return Err(OtaError::new(OtaErr::DownloadErr).with_status(StatusCode::RANGE_NOT_SATISFIABLE.as_u16()));
+            }
+            status => {
+                log::error!("Download returned status {}", status);
+                return Err(OtaError::new(OtaErr::DownloadErr).with_status(status.as_u16()));
+            }
+        };
+
+        let etag = response.headers().get(ETAG).and_then(|v| v.to_str().ok()).map(str::to_string);
+        if resume {
+            let start = response.headers().get(CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_content_range_start);
+            if start != Some(meta.downloaded) {
+                log::error!("Server resumed at {:?} instead of {}", start, meta.downloaded);
+                meta.downloaded = 0;
+                let _ = fs::remove_file(self.part_path()).await;
+                return Err(OtaErr::DownloadErr.into());
+            }
+        } else {
+            meta.downloaded = 0;
+            meta.total = response.content_length();
+            *hasher = checksum.hasher()?;
+        }
+        if let (Some(size), Some(total)) = (size, meta.total) {
+            if total != size {
+                log::error!("Server sends {} bytes, the manifest says {}", total, size);
+                return Err(OtaErr::ChecksumErr.into());
+            }
+        }
+        meta.etag = etag.or(meta.etag.take());
+
+        let path = self.part_path();
+        let write_failed = |e| write_error(&path, e);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resume)
+            .truncate(!resume)
+            .open(&path)
+            .await
+            .map_err(write_failed)?;
+        self.save_meta(meta).await;
+        if let Some(progress) = &self.progress {
+            progress.restart(meta.downloaded);
+        }
+
+        let mut limiter = rate_limit.map(RateLimiter::new);
+        let mut since_meta = 0;
+        loop {
+            let chunk = match tokio::time::timeout(self.read_timeout, response.chunk()).await {
+                Ok(Ok(Some(chunk))) => chunk,
+                Ok(Ok(None)) => break,
+                Ok(Err(e)) => {
+                    log::warn!("Download stream error: {}", e);
+                    let _ = file.flush().await;
+                    return Err(OtaError::http(OtaErr::DownloadErr, e));
+                }
+                // A half-open connection never fails on its own
+                Err(elapsed) => {
+                    log::warn!("No data for {:?}, dropping the connection", self.read_timeout);
+                    let _ = file.flush().await;
+                    return Err(OtaError::new(OtaErr::DownloadErr).with_source(elapsed));
+                }
+            };
+            file.write_all(&chunk).await.map_err(write_failed)?;
+            hasher.update(&chunk).map_err(|e| OtaError::new(OtaErr::ChecksumErr).with_source(e))?;
+            meta.downloaded += chunk.len() as u64;
+            if size.is_some_and(|size| meta.downloaded > size) {
+                log::error!("Download exceeds the {:?} bytes of the manifest", size);
+                return Err(OtaErr::ChecksumErr.into());
+            }
+            if let Some(progress) = &self.progress {
+                progress.report(self.stage, meta.downloaded, meta.total.or(size));
+            }
+            since_meta += chunk.len() as u64;
+            if since_meta >= META_INTERVAL {
+                file.flush().await.map_err(write_failed)?;
+                self.save_meta(meta).await;
+                since_meta = 0;
+            }
+            if let Some(limiter) = &mut limiter {
+                limiter.consume(chunk.len() as u64).await;
+            }
+        }
+        file.sync_all().await.map_err(write_failed)?;
+
+        match meta.total {
+            Some(total) if total != meta.downloaded => {
+                log::warn!("Download ended at {} of {} bytes", meta.downloaded, total);
+                Err(OtaErr::DownloadErr.into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Feeds the first `len` bytes of the file at `path` to `hasher`.
+async fn hash_file(path: &Path, hasher: &mut Hasher, len: u64) -> Result<(), OtaError> {
+    let file = fs::File::open(path).await.map_err(|e| OtaError::io(OtaErr::DownloadErr, path, e))?;
+    let mut reader = file.take(len);
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf).await.map_err(|e| OtaError::io(OtaErr::DownloadErr, path, e))?;
+        if n == 0 {
+            return Ok(());
+        }
+        hasher.update(&buf[..n]).map_err(|e| OtaError::new(OtaErr::ChecksumErr).with_source(e))?;
+    }
+}
+
+/// A failed write of `path`: `NoSpaceErr` when the disk is full, otherwise a
+/// `DownloadErr` that starts the download over.
+fn write_error(path: &Path, e: std::io::Error) -> OtaError {
+    let kind = match e.kind() {
+        std::io::ErrorKind::StorageFull => OtaErr::NoSpaceErr,
+        _ => OtaErr::DownloadErr,
+    };
+    OtaError::io(kind, path, e)
+}
+
+/// Bytes available to unprivileged writes on the filesystem holding `path`,
+/// which need not exist yet.
+pub fn available_space(path: &Path) -> Result<u64, OtaError> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let stat = nix::sys::statvfs::statvfs(dir).map_err(|e| {
+        log::error!("Failed to get free space of {}: {}", dir.display(), e);
+        OtaError::new(OtaErr::NoSpaceErr).with_path(dir).with_source(e)
+    })?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+/// First byte position of a `Content-Range: bytes <start>-<end>/<total>` value.
+fn parse_content_range_start(value: &str) -> Option<u64> {
+    value.strip_prefix("bytes ")?.split('-').next()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::test_server::TestServer;
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    fn request(server: &TestServer, body: &[u8]) -> DownloadRequest {
+        let digest = openssl::hash::hash(openssl::hash::MessageDigest::sha256(), body).unwrap();
+        DownloadRequest {
+            link: server.url(),
+            checksum: format!("sha256:{}", hex::encode(digest)),
+            signature_link: String::new(),
+            size: Some(body.len() as u64),
+            delta: None,
+            rate_limit: None,
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ota-download-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("update_ota.bin")
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(parse_content_range_start("bytes 100-199/200"), Some(100));
+        assert_eq!(parse_content_range_start("bytes */200"), None);
+        assert_eq!(parse_content_range_start("items 1-2/3"), None);
+    }
+
+    #[tokio::test]
+    async fn test_download_whole_file() {
+        let body = image(300_000);
+        let server = TestServer::start(body.clone()).await;
+        let path = temp_path("whole");
+
+        let len = Downloader::new(&path).download(&request(&server, &body)).await.unwrap();
+        assert_eq!(len, body.len() as u64);
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        assert!(!Downloader::new(&path).part_path().exists());
+        assert!(!Downloader::new(&path).meta_path().exists());
+        assert_eq!(server.ranges(), vec![None]);
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_connection_drop() {
+        let body = image(500_000);
+        // The first two connections are cut after 120000 bytes each
+        let server = TestServer::start(body.clone()).await.cut_after(120_000, 2);
+        let path = temp_path("resume");
+
+        Downloader::new(&path).download(&request(&server, &body)).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        assert_eq!(server.ranges(), vec![None, Some(120_000), Some(240_000)]);
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_stall() {
+        let body = image(300_000);
+        // The first connection stops sending without closing
+        let server = TestServer::start(body.clone()).await.stall_after(100_000, 1);
+        let path = temp_path("stall");
+
+        let mut downloader = Downloader::new(&path);
+        downloader.read_timeout = Duration::from_millis(300);
+        downloader.download(&request(&server, &body)).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        assert_eq!(server.ranges(), vec![None, Some(100_000)]);
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_restart() {
+        let body = image(400_000);
+        let server = TestServer::start(body.clone()).await.cut_after(150_000, 1);
+        let path = temp_path("restart");
+
+        // Give up right away, as if the controller rebooted mid-transfer
+        let mut downloader = Downloader::new(&path);
+        downloader.max_attempts = 1;
+        assert_eq!(downloader.download(&request(&server, &body)).await.map_err(|e| e.kind), Err(OtaErr::DownloadErr));
+        assert_eq!(std::fs::metadata(downloader.part_path()).unwrap().len(), 150_000);
+        let meta: PartMeta = serde_json::from_slice(&std::fs::read(downloader.meta_path()).unwrap()).unwrap();
+        assert_eq!(meta.downloaded, 150_000);
+        assert_eq!(meta.total, Some(400_000));
+
+        let downloader = Downloader::new(&path);
+        downloader.download(&request(&server, &body)).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        assert_eq!(server.ranges(), vec![None, Some(150_000)]);
+    }
+
+    #[tokio::test]
+    async fn test_part_of_other_link_is_discarded() {
+        let body = image(10_000);
+        let server = TestServer::start(body.clone()).await;
+        let path = temp_path("other");
+        let downloader = Downloader::new(&path);
+        std::fs::write(downloader.part_path(), b"stale").unwrap();
+        let meta = PartMeta { link: "http://example.invalid/old.bin".to_string(), downloaded: 5, ..PartMeta::default() };
+        std::fs::write(downloader.meta_path(), serde_json::to_vec(&meta).unwrap()).unwrap();
+
+        downloader.download(&request(&server, &body)).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        assert_eq!(server.ranges(), vec![None]);
+    }
+
+    #[tokio::test]
+    async fn test_server_without_range_support_restarts() {
+        let body = image(200_000);
+        let server = TestServer::start(body.clone()).await.cut_after(50_000, 1).without_ranges();
+        let path = temp_path("norange");
+
+        Downloader::new(&path).download(&request(&server, &body)).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        assert_eq!(server.ranges(), vec![None, Some(50_000)]);
+    }
+
+    #[tokio::test]
+    async fn test_checksum_mismatch() {
+        let body = image(100_000);
+        let server = TestServer::start(body.clone()).await;
+        let path = temp_path("mismatch");
+        let downloader = Downloader::new(&path);
+        let mut other = body.clone();
+        other[5] ^= 1;
+
+        assert_eq!(downloader.download(&request(&server, &other)).await.map_err(|e| e.kind), Err(OtaErr::ChecksumErr));
+        assert!(!path.exists());
+        assert!(!downloader.part_path().exists());
+        assert!(!downloader.meta_path().exists());
+
+        let missing = DownloadRequest { link: server.url(), ..DownloadRequest::default() };
+        assert_eq!(downloader.download(&missing).await.map_err(|e| e.kind), Err(OtaErr::ChecksumErr));
+        // Rejected before anything was fetched
+        assert_eq!(server.ranges().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_checksum_covers_resumed_part() {
+        let body = image(300_000);
+        let server = TestServer::start(body.clone()).await.cut_after(100_000, 1);
+        let path = temp_path("resumed-checksum");
+        let mut downloader = Downloader::new(&path);
+        downloader.max_attempts = 1;
+        assert_eq!(downloader.download(&request(&server, &body)).await.map_err(|e| e.kind), Err(OtaErr::DownloadErr));
+
+        // Corrupt what is already on disk, the resumed download must notice
+        let mut part = std::fs::read(downloader.part_path()).unwrap();
+        part[10] ^= 1;
+        std::fs::write(downloader.part_path(), part).unwrap();
+
+        let downloader = Downloader::new(&path);
+        assert_eq!(downloader.download(&request(&server, &body)).await.map_err(|e| e.kind), Err(OtaErr::ChecksumErr));
+        assert_eq!(server.ranges(), vec![None, Some(100_000)]);
+
+        // After discarding the part the next attempt starts over and succeeds
+        downloader.download(&request(&server, &body)).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+    }
+
+    #[tokio::test]
+    async fn test_detached_signature_is_fetched() {
+        let body = image(50_000);
+        let server = TestServer::start(body.clone()).await.with_file("/update_ota.bin.sig", b"signature".to_vec());
+        let path = temp_path("signature");
+        let downloader = Downloader::new(&path);
+
+        let request = DownloadRequest { signature_link: server.url_of("/update_ota.bin.sig"), ..request(&server, &body) };
+        downloader.download(&request).await.unwrap();
+        assert_eq!(std::fs::read(downloader.signature_path()).unwrap(), b"signature");
+
+        // Installed images are not kept around
+        downloader.discard().await;
+        assert!(!path.exists() && !downloader.signature_path().exists());
+        downloader.download(&request).await.unwrap();
+
+        // The signature of the previous image does not survive the next download
+        downloader.download(&self::request(&server, &body)).await.unwrap();
+        assert!(!downloader.signature_path().exists());
+
+        let missing = DownloadRequest { signature_link: server.url_of("/missing.sig"), ..self::request(&server, &body) };
+        let e = downloader.download(&missing).await.unwrap_err();
+        assert_eq!((e.kind, e.status), (OtaErr::DownloadErr, Some(404)));
+    }
+
+    #[tokio::test]
+    async fn test_size_mismatch() {
+        let body = image(50_000);
+        let server = TestServer::start(body.clone()).await;
+        let path = temp_path("size");
+        let downloader = Downloader::new(&path);
+
+        let request = DownloadRequest { size: Some(40_000), ..request(&server, &body) };
+        assert_eq!(downloader.download(&request).await.map_err(|e| e.kind), Err(OtaErr::ChecksumErr));
+        assert!(!downloader.part_path().exists());
+        // Given up right away
+        assert_eq!(server.ranges().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_not_enough_space() {
+        let body = image(1_000);
+        let server = TestServer::start(body.clone()).await;
+        let path = temp_path("space");
+        let available = available_space(&path).unwrap();
+        assert!(available > 0);
+
+        let too_large = DownloadRequest { size: Some(available + 1), ..request(&server, &body) };
+        assert_eq!(Downloader::new(&path).download(&too_large).await.map_err(|e| e.kind), Err(OtaErr::NoSpaceErr));
+        let margin = Downloader { space_margin: available, ..Downloader::new(&path) };
+        assert_eq!(margin.download(&request(&server, &body)).await.map_err(|e| e.kind), Err(OtaErr::NoSpaceErr));
+        // Refused before anything was requested
+        assert!(server.ranges().is_empty());
+        assert!(!path.exists());
+
+        assert_eq!(Downloader::new(&path).download(&request(&server, &body)).await, Ok(1_000));
+    }
+
+    #[tokio::test]
+    async fn test_write_failure() {
+        let body = image(1_000);
+        let server = TestServer::start(body.clone()).await;
+        let path = temp_path("write");
+        let downloader = Downloader::new(&path);
+        std::fs::create_dir(downloader.part_path()).unwrap();
+
+        // Not a memory problem: the download starts over
+        let e = downloader.download(&request(&server, &body)).await.unwrap_err();
+        assert_eq!((e.kind, e.path), (OtaErr::DownloadErr, Some(downloader.part_path())));
+        assert!(!path.exists());
+
+        let full = std::io::Error::from(std::io::ErrorKind::StorageFull);
+        assert_eq!(write_error(&path, full), OtaErr::NoSpaceErr);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let body = image(200_000);
+        let server = TestServer::start(body.clone()).await;
+        let path = temp_path("rate-limit");
+
+        let started = Instant::now();
+        let limited = DownloadRequest { rate_limit: Some(400_000), ..request(&server, &body) };
+        assert_eq!(Downloader::new(&path).download(&limited).await, Ok(200_000));
+        assert!(started.elapsed() >= Duration::from_millis(450), "took {:?}", started.elapsed());
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+
+        let started = Instant::now();
+        assert_eq!(Downloader::new(&path).download(&request(&server, &body)).await, Ok(200_000));
+        assert!(started.elapsed() < Duration::from_millis(450), "took {:?}", started.elapsed());
+    }
+
+    #[test]
+    fn test_bandwidth_config() {
+        assert_eq!(BandwidthConfig::default().rate_limit(false), None);
+        let config = BandwidthConfig { limit: 50_000, window_limit: None };
+        assert_eq!((config.rate_limit(false), config.rate_limit(true)), (Some(50_000), Some(50_000)));
+        let config = BandwidthConfig { window_limit: Some(0), ..config };
+        assert_eq!((config.rate_limit(false), config.rate_limit(true)), (Some(50_000), None));
+    }
+
+    #[tokio::test]
+    async fn test_progress_is_throttled() {
+        let body = image(300_000);
+        let server = TestServer::start(body.clone()).await;
+        let path = temp_path("progress");
+        let (tx, mut rx) = mpsc::channel(100);
+
+        let downloader = Downloader::new(&path).with_progress(ProgressReporter::new(tx.clone(), Duration::from_secs(3600)));
+        downloader.download(&request(&server, &body)).await.unwrap();
+        drop(downloader);
+        drop(tx);
+        let mut reports = Vec::new();
+        while let Some(Ok(TransportOut::Progress(progress))) = rx.recv().await {
+            reports.push(progress);
+        }
+        // The first chunk and the completed download only
+        assert_eq!(reports.len(), 2);
+        assert!(reports[0].bytes < 300_000);
+        assert_eq!(reports[1].stage, DownloadStage::Image);
+        assert_eq!((reports[1].bytes, reports[1].total, reports[1].eta_s), (300_000, Some(300_000), Some(0)));
+        assert!(reports[1].rate > 0);
+
+        let (tx, mut rx) = mpsc::channel(1000);
+        let downloader = Downloader::new(temp_path("progress-all")).with_progress(ProgressReporter::new(tx, Duration::ZERO));
+        downloader.download(&request(&server, &body)).await.unwrap();
+        drop(downloader);
+        let mut count = 0;
+        while let Some(Ok(TransportOut::Progress(_))) = rx.recv().await {
+            count += 1;
+        }
+        assert!(count > 2);
+    }
+
+    fn delta_request(server: &TestServer, base_path: &Path, base: &[u8], patch: &[u8]) -> DeltaRequest {
+        let (base_size, base_sha256) = manifest::sha256(base).unwrap();
+        let (size, sha256) = manifest::sha256(patch).unwrap();
+        DeltaRequest {
+            link: sibling_link(&server.url(), "update.patch").unwrap(),
+            base: base_path.to_path_buf(),
+            delta: Delta { base_version: "1.0.1".to_string(), base_size, base_sha256, patch: "update.patch".to_string(), size, sha256 },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delta_update() {
+        let body = image(50_000);
+        let mut base = body.clone();
+        base[1_000..1_200].fill(0);
+        let patch = delta::diff(&base, &body).unwrap();
+        let server = TestServer::start(body.clone()).await.with_file("/update.patch", patch.clone());
+        let path = temp_path("delta");
+        let base_path = path.with_file_name("slot_a.img");
+        std::fs::write(&base_path, &base).unwrap();
+        let downloader = Downloader::new(&path);
+
+        let delta_update = DownloadRequest { delta: Some(delta_request(&server, &base_path, &base, &patch)), ..request(&server, &body) };
+        assert_eq!(downloader.download(&delta_update).await, Ok(50_000));
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        // The full image was never requested
+        assert_eq!(server.ranges().len(), 0);
+        assert!(!path.with_file_name("update_ota.bin.patch").exists());
+        assert!(!path.with_file_name("update_ota.bin.patched").exists());
+    }
+
+    #[tokio::test]
+    async fn test_delta_falls_back_to_full_image() {
+        let body = image(50_000);
+        let base = image(40_000);
+        let patch = delta::diff(&base, &body).unwrap();
+        let broken = delta::diff(&base, &image(45_000)).unwrap();
+        let server = TestServer::start(body.clone()).await
+            .with_file("/update.patch", patch.clone())
+            .with_file("/broken.patch", broken.clone());
+        let path = temp_path("delta-fallback");
+        let base_path = path.with_file_name("slot_a.img");
+        let downloader = Downloader::new(&path);
+
+        // Something else is installed
+        std::fs::write(&base_path, image(39_000)).unwrap();
+        let delta_update = DownloadRequest { delta: Some(delta_request(&server, &base_path, &base, &patch)), ..request(&server, &body) };
+        assert_eq!(downloader.download(&delta_update).await, Ok(50_000));
+        assert_eq!(server.ranges().len(), 1);
+
+        // The patch builds something else than the image
+        std::fs::write(&base_path, &base).unwrap();
+        let mut delta = delta_request(&server, &base_path, &base, &broken);
+        delta.link = server.url_of("/broken.patch");
+        let broken_update = DownloadRequest { delta: Some(delta), ..request(&server, &body) };
+        assert_eq!(downloader.download(&broken_update).await, Ok(50_000));
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        assert_eq!(server.ranges().len(), 2);
+        assert!(!path.with_file_name("update_ota.bin.patched").exists());
+
+        // Not enough memory to hold the base image
+        let low_memory = Downloader { memory_margin_kb: u64::MAX / 2, ..Downloader::new(&path) };
+        let delta_update = DownloadRequest { delta: Some(delta_request(&server, &base_path, &base, &patch)), ..request(&server, &body) };
+        assert_eq!(low_memory.download(&delta_update).await, Ok(50_000));
+        assert_eq!(server.ranges().len(), 3);
+    }
+}
@@ -1,149 +1,68 @@
-use crate::error::OtaErr;
+use crate::error::{OtaErr, OtaError, Phase};
 use super::{Transport,TransportIn,TransportOut};
-use rumqttc::{QoS,Event};
 use tokio::sync::mpsc;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
-use reqwest;
-use rumqttc::{self, AsyncClient, MqttOptions};
+use super::download::{Downloader, ProgressReporter};
+use std::path::PathBuf;
 use std::time::Duration;
 
 pub struct HttpClient {
-    pub tx: mpsc::Sender<Result<TransportOut, OtaErr>>,
-    pub rx: mpsc::Receiver<Result<TransportOut, OtaErr>>
+    pub tx: mpsc::Sender<Result<TransportOut, OtaError>>,
+    pub rx: mpsc::Receiver<Result<TransportOut, OtaError>>,
+    pub download_path: PathBuf,
+    /// Where update reports are posted
+    pub report_url: String,
+    /// Authenticates with the device credentials
+    pub client: reqwest::Client,
+    /// Least time between two progress messages of a download
+    pub progress_interval: Duration,
+    /// Space to leave free next to a downloaded image
+    pub space_margin: u64,
+    /// Memory to leave available while a patch is applied (KiB)
+    pub memory_margin_kb: u64,
 }
 
 impl HttpClient {}
 
 #[async_trait::async_trait]
 impl Transport for HttpClient {
-    async fn send(&mut self, mut data: TransportIn)  -> Result<(), OtaErr> {
+    async fn send(&mut self, mut data: TransportIn)  -> Result<(), OtaError> {
         match data {
             TransportIn::CheckOtaHc(ref mut client) => {
-                let _ = client.send().await;
-                let response = client.recv().await;
-                self.tx.send(response).await.unwrap();
+                let response = match client.send().await {
+                    Ok(()) => client.recv().await,
+                    Err(e) => Err(e),
+                };
+                let _ = self.tx.send(response).await;
             }
-            TransportIn::GetLink(link) => {
+            TransportIn::GetLink(request) => {
                 let tx_clone = self.tx.clone(); // Clone the Sender for the spawned task
+                let progress = ProgressReporter::new(self.tx.clone(), self.progress_interval);
+                let mut downloader = Downloader::new(self.download_path.clone()).with_progress(progress);
+                downloader.space_margin = self.space_margin;
+                downloader.memory_margin_kb = self.memory_margin_kb;
                 tokio::spawn(async move{
-                    let client = reqwest::Client::new();
-                    // get link
-                    match client.get(link).send().await {
-                        Ok(response) =>{
-                            match File::create("update_ota.bin").await {
-                                Ok(mut dest) => {
-                                    let bytes = response.bytes().await.unwrap();
-                                    match dest.write_all(&bytes).await {
-                                        Ok(_) => {
-                                            let _ = tx_clone.send(Ok(TransportOut::ResponseLink)).await.unwrap();
-                                        }
-                                        Err(_) => {
-                                            let _ = tx_clone.send(Err(OtaErr::NotEnoughMemoryErr)).await.unwrap();
-                                        }
-                                    }
-                                   
-                                }
-                                Err(_) => {
-                                    let _ = tx_clone.send(Err(OtaErr::NotEnoughMemoryErr)).await.unwrap();
-                                }
-                            }
-                        }
-
-                        Err(_) => {
-                            let _ = tx_clone.send(Err(OtaErr::DownloadErr)).await.unwrap();
-                        }
-                    };
+                    let result = downloader.download(&request).await
+                        .map(|_| TransportOut::ResponseLink)
+                        .map_err(|e| e.in_phase(Phase::Download));
+                    let _ = tx_clone.send(result).await;
                 });
             }
 
-            TransportIn::KeepAlive => {
-                let mut mqttoptions = MqttOptions::new("keep_alive", "localhost", 1883);
-                mqttoptions.set_keep_alive(Duration::from_secs(5));
-                let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
-
-                client
-                    .publish("master_service", QoS::ExactlyOnce, false, "keep alive")
-                    .await
-                    .unwrap();
-                
-                client
-                    .publish("master_service", QoS::ExactlyOnce, false, "ota")
-                    .await
-                    .unwrap();
-                
-                loop {
-                    let event = eventloop.poll().await;
-                    match &event {
-                        Ok(v) => {
-                            match v {
-                                Event::Incoming(pack) => {
-                                    match pack {
-                                        rumqttc::Packet::PubComp(pubcomp) => {
-                                            log::info!("pubcomp: {:?}",pubcomp);
-                                            self.tx.send(Ok(TransportOut::ResponseKeepAlive)).await.unwrap();
-                                            return Ok(());
-                                        }
-                                        _ => {}
-                                    }
-                                }
-                                Event::Outgoing(_out) =>{}
-                            }
-                        }
-                        Err(e) => {
-                            log::info!("Error = {e:?}");
-                            return Err(OtaErr::MqttErr)
-                        }
-                    }
-                }
-            }
-
-            TransportIn::Suppend(pid) => { 
-                let pid_bytes: Vec<u8> = pid.iter().map(|&x| x as u8).collect();
-                let mut mqttoptions = MqttOptions::new("suppend", "localhost", 1883);
-                mqttoptions.set_keep_alive(Duration::from_secs(5));
-                let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
-
-                client
-                    .publish("master_service", QoS::ExactlyOnce, false, "ota suppend")
-                    .await
-                    .unwrap();
-                
-                client
-                    .publish("master_service", QoS::ExactlyOnce, false, pid_bytes.clone())
-                    .await
-                    .unwrap();
-                loop {
-                    let event: Result<Event, rumqttc::ConnectionError> = eventloop.poll().await;
-                    match &event {
-                        Ok(v) => {
-                            match v {
-                                Event::Incoming(pack) => {
-                                    match pack {
-                                        rumqttc::Packet::PubComp(pubcomp) => {
-                                            log::info!("pubcomp: {:?}",pubcomp);
-                                            self.tx.send(Ok(TransportOut::ResponseSuppend)).await.unwrap();
-                                            return Ok(());
-                                        }
-                                        _ => {}
-                                    }
-                                }
-                                Event::Outgoing(_out) =>{}
-                            }
-                        }
-                        Err(e) => {
-                            log::info!("Error = {e:?}");
-                            return Ok(());
-                        }
-                    }
-                }
+            TransportIn::Report(report) => {
+                let tx_clone = self.tx.clone();
+                let (url, client) = (self.report_url.clone(), self.client.clone());
+                tokio::spawn(async move {
+                    let result = report.send(&url, &client).await.map(|_| TransportOut::ResponseReport);
+                    let _ = tx_clone.send(result).await;
+                });
             }
         }
         Ok(())
     }
 
-    async fn recv(&mut self) -> Result<TransportOut, OtaErr> {
-        self.rx.recv().await.unwrap()
+    async fn recv(&mut self) -> Result<TransportOut, OtaError> {
+        // Never closed, `self` holds a sender
+        self.rx.recv().await.unwrap_or_else(|| Err(OtaError::new(OtaErr::HttpErr)))
     }  
 }
 
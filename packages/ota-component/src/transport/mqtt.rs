@@ -1,81 +1,454 @@
-use rumqttc::{MqttOptions, AsyncClient, EventLoop, Event, QoS};
-use crate::error::OtaErr;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rumqttc::{MqttOptions, AsyncClient, EventLoop, Event, Key, Outgoing, Packet, PubAck, PubComp, QoS, TlsConfiguration, Transport};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use crate::error::{OtaErr, OtaError};
+use crate::retry::{Backoff, RetryPolicy};
+use crate::state::OtaPhase;
+use crate::transport::download::Progress;
 use tokio::time::Duration;
 
+/// Connection to the broker, used by every MQTT client of ota-component.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    /// `{mac}` is replaced with the controller's MAC address, brokers drop
+    /// one of two sessions with the same id
+    pub client_id: String,
+    pub keep_alive_s: u64,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Connect over TLS when set
+    pub tls: Option<MqttTls>,
+    /// Delay between reconnect attempts while the broker is down,
+    /// `max_attempts` is ignored
+    pub reconnect: RetryPolicy,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MqttTls {
+    /// CA certificates the broker's certificate must chain to (PEM)
+    pub ca_file: PathBuf,
+    /// Client certificate (PEM) for mutual TLS, together with `client_key`
+    #[serde(default)]
+    pub client_cert: Option<PathBuf>,
+    /// PKCS#8 or PKCS#1 RSA private key of `client_cert` (PEM)
+    #[serde(default)]
+    pub client_key: Option<PathBuf>,
+}
 
+impl MqttConfig {
+    pub fn client_id_for(&self, mac: &str) -> String {
+        self.client_id.replace("{mac}", mac)
+    }
 
+    /// Options for a client with `client_id`, reading the TLS files.
+    pub fn options(&self, client_id: &str) -> Result<MqttOptions, OtaError> {
+        let mut options = MqttOptions::new(client_id, &self.host, self.port);
+        options.set_keep_alive(Duration::from_secs(self.keep_alive_s));
+        if let Some(username) = &self.username {
+            options.set_credentials(username, self.password.clone().unwrap_or_default());
+        } else if self.password.is_some() {
+            log::error!("Mqtt password given without username");
+            return Err(OtaErr::ConfigErr.into());
+        }
+        if let Some(tls) = &self.tls {
+            options.set_transport(Transport::tls_with_config(tls.configuration()?));
+        }
+        Ok(options)
+    }
+}
 
-#[derive(Debug)]
+impl MqttTls {
+    fn configuration(&self) -> Result<TlsConfiguration, OtaError> {
+        let client_auth = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let key = read_pem(key)?;
+                // rumqttc reads RSA keys as PKCS#1 and everything else as PKCS#8
+                let key = if String::from_utf8_lossy(&key).contains("BEGIN RSA PRIVATE KEY") {
+                    Key::RSA(key)
+                } else {
+                    Key::ECC(key)
+                };
+                Some((read_pem(cert)?, key))
+            }
+            (None, None) => None,
+            _ => {
+                log::error!("Mqtt client_cert and client_key go together");
+                return Err(OtaErr::ConfigErr.into());
+            }
+        };
+        Ok(TlsConfiguration::Simple { ca: read_pem(&self.ca_file)?, alpn: None, client_auth })
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>, OtaError> {
+    std::fs::read(path).map_err(|e| OtaError::io(OtaErr::ConfigErr, path, e))
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "ota-{mac}".to_string(),
+            keep_alive_s: 5,
+            username: None,
+            password: None,
+            tls: None,
+            reconnect: RetryPolicy {
+                initial_delay_ms: 1_000,
+                max_delay_ms: 60_000,
+                max_attempts: 0,
+                ..RetryPolicy::default()
+            },
+        }
+    }
+}
+
+/// Where and how often the progress of an update is published.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct StatusConfig {
+    /// `{mac}` is replaced with the controller's MAC address
+    pub topic: String,
+    /// Least time between two progress messages of a download
+    pub interval_ms: u64,
+}
+
+impl Default for StatusConfig {
+    fn default() -> Self {
+        StatusConfig {
+            topic: "ota/{mac}/status".to_string(),
+            interval_ms: 1000,
+        }
+    }
+}
+
+impl StatusConfig {
+    pub fn topic_for(&self, mac: &str) -> String {
+        self.topic.replace("{mac}", mac)
+    }
+}
+
+/// Status message of the update in progress.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OtaStatus {
+    pub download_id: u64,
+    pub version_name: String,
+    pub phase: OtaPhase,
+    pub progress: Progress,
+}
+
+#[derive(Debug, PartialEq)]
 pub struct ResponseMqtt {
     pub topic: String,
     pub message: String,
 }
+
+/// Publish whose acknowledgement by the broker is reported back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MqttRequest {
+    KeepAlive,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MqttEvent {
+    Message(ResponseMqtt),
+    /// The broker has the publish tagged with the request
+    Acked(MqttRequest),
+    Connected,
+    /// Lost the broker, reconnecting in the background
+    Disconnected,
+}
+
+/// The one MQTT session of ota-component. The eventloop runs in its own
+/// task, which reconnects with backoff while the broker is down. Topics are
+/// subscribed again on every connection.
 pub struct MqttDriver {
-    pub options: MqttOptions, 
-    pub client: AsyncClient,
-    pub eventloop: EventLoop,
-    pub flag:bool,
-}
-
-impl MqttDriver { 
-    pub async fn new(id:String, host:String, port: u16, keep_alive:u64) -> Self {
-        let mut mqttoptions = MqttOptions::new(id, host, port);
-        mqttoptions.set_keep_alive(Duration::from_secs(keep_alive));
-
-        let (client, eventloop) = AsyncClient::new(mqttoptions.clone(), 10);
-        client
-            .subscribe("master/ota", QoS::AtMostOnce)
-            .await
-            .unwrap();
-    
-        MqttDriver {
-            options: mqttoptions.clone(),
-            client: client,
-            eventloop: eventloop, 
-            flag:false                                        
+    client: AsyncClient,
+    /// Events of the eventloop, `None` for a connection error
+    events: mpsc::Receiver<Option<Event>>,
+    connected: bool,
+    subscriptions: Vec<String>,
+    /// Tags of the publishes handed to the client, in order, until the
+    /// eventloop sends them and assigns their packet id
+    unsent: VecDeque<Option<MqttRequest>>,
+    /// QoS 1 and 2 publishes sent and not acknowledged yet, by packet id
+    in_flight: HashMap<u16, Option<MqttRequest>>,
+}
+
+impl MqttDriver {
+    /// Session with `config.client_id` as is, see `MqttConfig::client_id_for`.
+    pub async fn new(config: &MqttConfig) -> Result<Self, OtaError> {
+        let (client, eventloop) = AsyncClient::new(config.options(&config.client_id)?, 10);
+        let (tx, events) = mpsc::channel(10);
+        tokio::spawn(run(eventloop, tx, config.reconnect.clone()));
+        Ok(MqttDriver {
+            client,
+            events,
+            connected: false,
+            subscriptions: vec!["master/ota".to_string()],
+            unsent: VecDeque::new(),
+            in_flight: HashMap::new(),
+        })
+    }
+
+    /// Queues a publish on the session, `request` is returned by `recv` once
+    /// the broker acknowledges it (at once for QoS 0).
+    pub async fn send(&mut self, topic: &str, message: impl Into<Vec<u8>>, qos: QoS, request: Option<MqttRequest>) -> Result<(), OtaErr> {
+        self.client.publish(topic, qos, false, message).await.map_err(|e| {
+            log::error!("Failed to publish to {}: {}", topic, e);
+            OtaErr::MqttErr
+        })?;
+        self.unsent.push_back(request);
+        Ok(())
+    }
+
+    /// Tells the manager service ota-component is alive, acknowledged with
+    /// `MqttRequest::KeepAlive`.
+    pub async fn keep_alive(&mut self) -> Result<(), OtaErr> {
+        self.send("master_service", "keep alive", QoS::ExactlyOnce, None).await?;
+        self.send("master_service", "ota", QoS::ExactlyOnce, Some(MqttRequest::KeepAlive)).await
+    }
+
+    /// Publishes `status` as JSON, retained for dashboards subscribing later.
+    /// Never waits for the broker, a status that does not fit is dropped.
+    pub fn publish_status(&mut self, topic: &str, status: &OtaStatus) -> Result<(), OtaErr> {
+        let payload = serde_json::to_vec(status).map_err(|_| OtaErr::MqttErr)?;
+        self.client.try_publish(topic, QoS::AtMostOnce, true, payload).map_err(|e| {
+            log::warn!("Failed to publish ota status: {}", e);
+            OtaErr::MqttErr
+        })?;
+        self.unsent.push_back(None);
+        Ok(())
+    }
+
+    pub async fn recv(&mut self) -> MqttEvent {
+        loop {
+            match self.events.recv().await {
+                Some(Some(event)) => {
+                    if let Some(event) = self.handle(event) {
+                        return event;
+                    }
+                }
+                Some(None) if self.connected => {
+                    self.connected = false;
+                    return MqttEvent::Disconnected;
+                }
+                Some(None) => {}
+                None => {
+                    log::error!("Mqtt eventloop stopped");
+                    std::future::pending::<()>().await;
+                }
+            }
         }
     }
-    pub async fn send(&mut self, topic: String, message: Vec<u8>, qos: QoS, retain: bool)-> Result<(),OtaErr> {
-        match self.client.publish(topic, qos, retain, message).await {
-            Ok(res) => {
-                Ok(res)
+
+    fn handle(&mut self, event: Event) -> Option<MqttEvent> {
+        match event {
+            Event::Incoming(Packet::ConnAck(_)) => {
+                log::info!("Connected to mqtt broker");
+                for topic in &self.subscriptions {
+                    if let Err(e) = self.client.try_subscribe(topic, QoS::AtMostOnce) {
+                        log::error!("Failed to subscribe to {}: {}", topic, e);
+                    }
+                }
+                self.connected = true;
+                Some(MqttEvent::Connected)
+            }
+            Event::Incoming(Packet::Publish(publish)) => {
+                let res = ResponseMqtt {
+                    topic: publish.topic.clone(),
+                    message: String::from_utf8_lossy(&publish.payload).to_string(),
+                };
+                log::info!("res: {:?}", res);
+                Some(MqttEvent::Message(res))
             }
-            Err(_) => {
-                Err(OtaErr::MqttErr)
+            Event::Incoming(Packet::PubAck(PubAck { pkid, .. }) | Packet::PubComp(PubComp { pkid, .. })) => {
+                self.in_flight.remove(&pkid).flatten().map(MqttEvent::Acked)
             }
+            // Publishes not acknowledged before a reconnect are sent again
+            // with the same packet id
+            Event::Outgoing(Outgoing::Publish(pkid)) if self.in_flight.contains_key(&pkid) => None,
+            Event::Outgoing(Outgoing::Publish(pkid)) => {
+                let request = self.unsent.pop_front().flatten();
+                if pkid == 0 {
+                    request.map(MqttEvent::Acked)
+                } else {
+                    self.in_flight.insert(pkid, request);
+                    None
+                }
+            }
+            _ => None,
         }
     }
+}
 
-    pub async fn recv(&mut self) -> Result<ResponseMqtt, OtaErr> {
-        loop {
-            let event = self.eventloop.poll().await;
-            match &event {
-                Ok(v) => {
-                    match v {
-                        Event::Incoming(packet) => {
-                            match packet {
-                                rumqttc::Packet::Publish(publish) => {
-                                    let payload_str: String = String::from_utf8_lossy(&publish.payload).to_string();
-                                    let res = ResponseMqtt {
-                                        topic: publish.topic.clone(),
-                                        message: payload_str,
-                                        
-                                    };
-                                    log::info!("res: {:?}", res);
-                                    return Ok(res);
-                                }
-                                _ => {
-                                }
-                            }
-                        }
-                        Event::Outgoing(_) => {}
-                    }
+/// Polls the eventloop, backing off between failed connection attempts
+/// instead of spinning against a broker that is down.
+async fn run(mut eventloop: EventLoop, tx: mpsc::Sender<Option<Event>>, policy: RetryPolicy) {
+    let mut backoff = Backoff::default();
+    let mut rng = StdRng::from_entropy();
+    let policy = RetryPolicy { max_attempts: 0, ..policy };
+    loop {
+        match eventloop.poll().await {
+            Ok(event) => {
+                if matches!(event, Event::Incoming(Packet::ConnAck(_))) {
+                    backoff.reset();
                 }
-                Err(e) => {
-                    log::info!("Error = {e:?}");
-                    return Err(OtaErr::MqttErr);
+                if tx.send(Some(event)).await.is_err() {
+                    return;
                 }
             }
+            Err(e) => {
+                let delay = backoff.next_delay(&policy, &mut rng).unwrap_or_default();
+                log::warn!("Mqtt connection failed: {}, reconnecting in {:?}", e, delay);
+                if tx.send(None).await.is_err() {
+                    return;
+                }
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::test_broker::TestBroker;
+
+    fn config(port: u16) -> MqttConfig {
+        let reconnect = RetryPolicy { initial_delay_ms: 100, max_delay_ms: 400, jitter: 0.0, ..RetryPolicy::default() };
+        MqttConfig { port, client_id: format!("ota-test-{}", port), reconnect, ..MqttConfig::default() }
+    }
+
+    async fn next(mqtt: &mut MqttDriver) -> MqttEvent {
+        tokio::time::timeout(Duration::from_secs(5), mqtt.recv()).await.expect("no mqtt event")
+    }
+
+    #[test]
+    fn test_status_topic() {
+        let config = StatusConfig::default();
+        assert_eq!(config.topic_for("14:c9:cf:17:af:8e"), "ota/14:c9:cf:17:af:8e/status");
+        let config = StatusConfig { topic: "devices/{mac}/ota".to_string(), ..config };
+        assert_eq!(config.topic_for("aa"), "devices/aa/ota");
+    }
+
+    #[tokio::test]
+    async fn test_acks_are_matched_to_requests() {
+        let mut mqtt = MqttDriver::new(&config(1)).await.unwrap();
+        mqtt.keep_alive().await.unwrap();
+        mqtt.publish_status("ota/status", &OtaStatus {
+            download_id: 1,
+            version_name: "1.0.2".to_string(),
+            phase: OtaPhase::Downloading,
+            progress: Progress::default(),
+        }).unwrap();
+
+        for pkid in [1, 2, 0] {
+            assert_eq!(mqtt.handle(Event::Outgoing(Outgoing::Publish(pkid))), None);
+        }
+        // Resent after a reconnect, still waiting for the same ack
+        assert_eq!(mqtt.handle(Event::Outgoing(Outgoing::Publish(2))), None);
+        assert_eq!(mqtt.handle(Event::Incoming(Packet::PubComp(PubComp::new(1)))), None);
+        assert_eq!(mqtt.handle(Event::Incoming(Packet::PubComp(PubComp::new(2)))), Some(MqttEvent::Acked(MqttRequest::KeepAlive)));
+        assert_eq!(mqtt.handle(Event::Incoming(Packet::PubComp(PubComp::new(2)))), None);
+        assert!(mqtt.unsent.is_empty() && mqtt.in_flight.is_empty());
+
+        let publish = rumqttc::Publish::new("master/ota", QoS::AtMostOnce, "true");
+        let message = ResponseMqtt { topic: "master/ota".to_string(), message: "true".to_string() };
+        assert_eq!(mqtt.handle(Event::Incoming(Packet::Publish(publish))), Some(MqttEvent::Message(message)));
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_and_resubscribes() {
+        let broker = TestBroker::start().await;
+        let mut mqtt = MqttDriver::new(&config(broker.addr().port())).await.unwrap();
+        assert_eq!(next(&mut mqtt).await, MqttEvent::Connected);
+        mqtt.keep_alive().await.unwrap();
+        assert_eq!(next(&mut mqtt).await, MqttEvent::Acked(MqttRequest::KeepAlive));
+        assert_eq!(broker.subscriptions(), vec!["master/ota".to_string()]);
+        broker.publish("master/ota", "true");
+        let message = ResponseMqtt { topic: "master/ota".to_string(), message: "true".to_string() };
+        assert_eq!(next(&mut mqtt).await, MqttEvent::Message(message));
+
+        let addr = broker.stop();
+        assert_eq!(next(&mut mqtt).await, MqttEvent::Disconnected);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let broker = TestBroker::start_on(addr).await;
+        assert_eq!(next(&mut mqtt).await, MqttEvent::Connected);
+        // Published over the same session, without new connections
+        mqtt.keep_alive().await.unwrap();
+        assert_eq!(next(&mut mqtt).await, MqttEvent::Acked(MqttRequest::KeepAlive));
+        assert_eq!(broker.connections(), 1);
+        assert_eq!(broker.subscriptions(), vec!["master/ota".to_string()]);
+        assert_eq!(broker.published().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_backs_off_while_broker_is_down() {
+        // Accepts connections and drops them right away
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let attempts = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let counter = attempts.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                drop(stream);
+            }
+        });
+
+        let mut mqtt = MqttDriver::new(&config(port)).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(1_000), mqtt.recv()).await.is_err());
+        // 0, 100, 300, 700 ms with the delay doubling from 100 ms
+        let attempts = attempts.load(std::sync::atomic::Ordering::SeqCst);
+        assert!((3..=5).contains(&attempts), "{} attempts", attempts);
+    }
+
+    #[tokio::test]
+    async fn test_tls_and_login() {
+        let dir = std::env::temp_dir().join(format!("ota-mqtt-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (acceptor, tls) = crate::transport::test_broker::test_pki(&dir);
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let broker = TestBroker::start_with(addr, Some(acceptor), Some(("hc", "secret"))).await;
+        let config = MqttConfig {
+            host: "localhost".to_string(),
+            client_id: "ota-{mac}".to_string(),
+            username: Some("hc".to_string()),
+            password: Some("secret".to_string()),
+            tls: Some(tls.clone()),
+            ..config(broker.addr().port())
+        };
+
+        let connect = MqttConfig { client_id: config.client_id_for("14:c9:cf:17:af:8e"), ..config.clone() };
+        let mut mqtt = MqttDriver::new(&connect).await.unwrap();
+        assert_eq!(next(&mut mqtt).await, MqttEvent::Connected);
+        mqtt.keep_alive().await.unwrap();
+        assert_eq!(next(&mut mqtt).await, MqttEvent::Acked(MqttRequest::KeepAlive));
+        assert_eq!(broker.clients(), vec![("ota-14:c9:cf:17:af:8e".to_string(), Some("hc".to_string()))]);
+
+        // Wrong password, and no client certificate
+        let wrong_password = MqttConfig { password: Some("guess".to_string()), ..config.clone() };
+        let no_cert = MqttConfig { tls: Some(MqttTls { client_cert: None, client_key: None, ..tls.clone() }), ..config.clone() };
+        for config in [wrong_password, no_cert] {
+            let mut mqtt = MqttDriver::new(&config).await.unwrap();
+            assert!(tokio::time::timeout(Duration::from_millis(500), mqtt.recv()).await.is_err());
         }
+        assert_eq!(broker.connections(), 1);
+
+        // Files are checked when the options are built
+        let no_key = MqttTls { client_key: None, ..tls.clone() };
+        assert!(matches!(MqttConfig { tls: Some(no_key), ..config.clone() }.options("ota"), Err(e) if e == OtaErr::ConfigErr));
+        let missing = MqttTls { ca_file: dir.join("missing.pem"), ..tls };
+        let e = MqttConfig { tls: Some(missing), ..config }.options("ota").err().unwrap();
+        assert_eq!((e.kind, e.path), (OtaErr::ConfigErr, Some(dir.join("missing.pem"))));
     }
 }
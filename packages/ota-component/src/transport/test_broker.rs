@@ -0,0 +1,247 @@
+//! Minimal in-process MQTT 3.1.1 broker for driver tests. It acknowledges
+//! everything, records subscriptions and publishes, and can be stopped and
+//! started again on the same port to simulate an outage. With `start_with`
+//! it serves TLS and checks the username and password.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use bytes::BytesMut;
+use rumqttc::mqttbytes::v4::{self, ConnAck, ConnectReturnCode, Packet, PingResp, PubAck, PubComp, PubRec, Publish, SubAck, SubscribeReasonCode};
+use rumqttc::mqttbytes::Error;
+use rumqttc::QoS;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+
+#[derive(Default)]
+struct Shared {
+    connections: u32,
+    /// Client id and username of every accepted session
+    clients: Vec<(String, Option<String>)>,
+    /// Username and password sessions must log in with
+    login: Option<(String, String)>,
+    subscriptions: Vec<String>,
+    published: Vec<(String, Vec<u8>)>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+pub struct TestBroker {
+    addr: SocketAddr,
+    shared: Arc<Mutex<Shared>>,
+    /// Publishes to the connected clients
+    outgoing: broadcast::Sender<Publish>,
+}
+
+impl TestBroker {
+    pub async fn start() -> Self {
+        Self::start_on("127.0.0.1:0".parse().unwrap()).await
+    }
+
+    pub async fn start_on(addr: SocketAddr) -> Self {
+        Self::start_with(addr, None, None).await
+    }
+
+    /// Broker behind `tls`, accepting only `login` when given.
+    pub async fn start_with(addr: SocketAddr, tls: Option<TlsAcceptor>, login: Option<(&str, &str)>) -> Self {
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shared = Arc::new(Mutex::new(Shared {
+            login: login.map(|(username, password)| (username.to_string(), password.to_string())),
+            ..Shared::default()
+        }));
+        let (outgoing, _) = broadcast::channel(16);
+
+        let state = shared.clone();
+        let publishes = outgoing.clone();
+        let accept = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let task = match &tls {
+                    Some(tls) => {
+                        let (tls, state, publishes) = (tls.clone(), state.clone(), publishes.subscribe());
+                        tokio::spawn(async move {
+                            // Handshake failures are counted as refused connections
+                            if let Ok(stream) = tls.accept(stream).await {
+                                serve(stream, state, publishes).await;
+                            }
+                        })
+                    }
+                    None => tokio::spawn(serve(stream, state.clone(), publishes.subscribe())),
+                };
+                state.lock().unwrap().tasks.push(task);
+            }
+        });
+        shared.lock().unwrap().tasks.push(accept);
+        TestBroker { addr, shared, outgoing }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Closes the listener and every connection.
+    pub fn stop(self) -> SocketAddr {
+        for task in self.shared.lock().unwrap().tasks.drain(..) {
+            task.abort();
+        }
+        self.addr
+    }
+
+    pub fn publish(&self, topic: &str, payload: &str) {
+        let _ = self.outgoing.send(Publish::new(topic, QoS::AtMostOnce, payload));
+    }
+
+    /// Sessions accepted so far.
+    pub fn connections(&self) -> u32 {
+        self.shared.lock().unwrap().connections
+    }
+
+    pub fn clients(&self) -> Vec<(String, Option<String>)> {
+        self.shared.lock().unwrap().clients.clone()
+    }
+
+    pub fn subscriptions(&self) -> Vec<String> {
+        self.shared.lock().unwrap().subscriptions.clone()
+    }
+
+    pub fn published(&self) -> Vec<(String, Vec<u8>)> {
+        self.shared.lock().unwrap().published.clone()
+    }
+}
+
+async fn serve<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, shared: Arc<Mutex<Shared>>, mut outgoing: broadcast::Receiver<Publish>) {
+    let mut read = BytesMut::new();
+    loop {
+        let mut write = BytesMut::new();
+        match v4::read(&mut read, 1024 * 1024) {
+            Ok(packet) => {
+                let written = match packet {
+                    Packet::Connect(connect) => {
+                        let login = connect.login.map(|login| (login.username, login.password));
+                        let accepted = {
+                            let mut shared = shared.lock().unwrap();
+                            let accepted = shared.login.is_none() || shared.login == login;
+                            if accepted {
+                                shared.connections += 1;
+                                shared.clients.push((connect.client_id, login.map(|(username, _)| username)));
+                            }
+                            accepted
+                        };
+                        if !accepted {
+                            let _ = ConnAck::new(ConnectReturnCode::BadUserNamePassword, false).write(&mut write);
+                            let _ = stream.write_all(&write).await;
+                            return;
+                        }
+                        ConnAck::new(ConnectReturnCode::Success, false).write(&mut write)
+                    }
+                    Packet::Subscribe(subscribe) => {
+                        let codes = subscribe.filters.iter().map(|_| SubscribeReasonCode::Success(QoS::AtMostOnce)).collect();
+                        let mut shared = shared.lock().unwrap();
+                        shared.subscriptions.extend(subscribe.filters.into_iter().map(|filter| filter.path));
+                        SubAck::new(subscribe.pkid, codes).write(&mut write)
+                    }
+                    Packet::Publish(publish) => {
+                        shared.lock().unwrap().published.push((publish.topic.clone(), publish.payload.to_vec()));
+                        match publish.qos {
+                            QoS::AtMostOnce => Ok(0),
+                            QoS::AtLeastOnce => PubAck::new(publish.pkid).write(&mut write),
+                            QoS::ExactlyOnce => PubRec::new(publish.pkid).write(&mut write),
+                        }
+                    }
+                    Packet::PubRel(pubrel) => PubComp::new(pubrel.pkid).write(&mut write),
+                    Packet::PingReq => PingResp.write(&mut write),
+                    Packet::Disconnect => return,
+                    _ => Ok(0),
+                };
+                if written.is_err() || stream.write_all(&write).await.is_err() {
+                    return;
+                }
+            }
+            Err(Error::InsufficientBytes(_)) => {
+                tokio::select! {
+                    n = stream.read_buf(&mut read) => {
+                        if !matches!(n, Ok(n) if n > 0) {
+                            return;
+                        }
+                    }
+                    Ok(publish) = outgoing.recv() => {
+                        if publish.write(&mut write).is_err() || stream.write_all(&write).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+/// Writes a CA, a server certificate for `localhost` and a client
+/// certificate to `dir`. Returns a TLS acceptor requiring client
+/// certificates from the CA and the client side files.
+pub fn test_pki(dir: &std::path::Path) -> (TlsAcceptor, crate::transport::mqtt::MqttTls) {
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::extension::{BasicConstraints, ExtendedKeyUsage, SubjectAlternativeName};
+    use openssl::x509::{X509, X509NameBuilder};
+    use tokio_rustls::rustls::{server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig};
+
+    fn key() -> PKey<Private> {
+        PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap()).unwrap()
+    }
+    fn cert(name: &str, key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>, serial: u32) -> X509 {
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&openssl::bn::BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder.set_not_before(&openssl::asn1::Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&openssl::asn1::Asn1Time::days_from_now(1).unwrap()).unwrap();
+        match issuer {
+            None => {
+                builder.set_issuer_name(&subject).unwrap();
+                builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+                builder.sign(key, openssl::hash::MessageDigest::sha256()).unwrap();
+            }
+            Some((ca, ca_key)) => {
+                builder.set_issuer_name(ca.subject_name()).unwrap();
+                builder.append_extension(BasicConstraints::new().build().unwrap()).unwrap();
+                builder.append_extension(ExtendedKeyUsage::new().server_auth().client_auth().build().unwrap()).unwrap();
+                let san = SubjectAlternativeName::new().dns(name).build(&builder.x509v3_context(Some(ca), None)).unwrap();
+                builder.append_extension(san).unwrap();
+                builder.sign(ca_key, openssl::hash::MessageDigest::sha256()).unwrap();
+            }
+        }
+        builder.build()
+    }
+
+    let ca_key = key();
+    let ca = cert("ota-test-ca", &ca_key, None, 1);
+    let server_key = key();
+    let server = cert("localhost", &server_key, Some((&ca, &ca_key)), 2);
+    let client_key = key();
+    let client = cert("ota-test-client", &client_key, Some((&ca, &ca_key)), 3);
+
+    let files = crate::transport::mqtt::MqttTls {
+        ca_file: dir.join("ca.pem"),
+        client_cert: Some(dir.join("client.pem")),
+        client_key: Some(dir.join("client.key")),
+    };
+    std::fs::write(&files.ca_file, ca.to_pem().unwrap()).unwrap();
+    std::fs::write(files.client_cert.as_ref().unwrap(), client.to_pem().unwrap()).unwrap();
+    std::fs::write(files.client_key.as_ref().unwrap(), client_key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots.add(&Certificate(ca.to_der().unwrap())).unwrap();
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        .with_single_cert(vec![Certificate(server.to_der().unwrap())], PrivateKey(server_key.private_key_to_pkcs8().unwrap()))
+        .unwrap();
+    (TlsAcceptor::from(Arc::new(config)), files)
+}
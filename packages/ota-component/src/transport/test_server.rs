@@ -0,0 +1,148 @@
+//! Minimal HTTP/1.1 file server for download tests. It understands `Range:
+//! bytes=N-` requests and can cut or stall connections part way through a
+//! response to simulate a flaky link.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(Default)]
+struct Shared {
+    body: Vec<u8>,
+    /// Served as is instead of `body` on their path
+    files: HashMap<String, Vec<u8>>,
+    ranges: Vec<Option<u64>>,
+    /// Bytes sent before cutting, and how many more connections to cut
+    cut: Option<(usize, u32)>,
+    /// Cut connections are held open without sending more
+    stall: bool,
+    no_ranges: bool,
+}
+
+pub struct TestServer {
+    addr: SocketAddr,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl TestServer {
+    /// Serves `body` on every path not registered with `with_file`.
+    pub async fn start(body: Vec<u8>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shared = Arc::new(Mutex::new(Shared { body, ..Shared::default() }));
+
+        let state = shared.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, state.clone()));
+            }
+        });
+        TestServer { addr, shared }
+    }
+
+    pub fn cut_after(self, bytes: usize, connections: u32) -> Self {
+        self.shared.lock().unwrap().cut = Some((bytes, connections));
+        self
+    }
+
+    /// Like `cut_after`, but leaves the connections open and silent.
+    pub fn stall_after(self, bytes: usize, connections: u32) -> Self {
+        self.shared.lock().unwrap().stall = true;
+        self.cut_after(bytes, connections)
+    }
+
+    pub fn with_file(self, path: &str, content: Vec<u8>) -> Self {
+        self.shared.lock().unwrap().files.insert(path.to_string(), content);
+        self
+    }
+
+    pub fn without_ranges(self) -> Self {
+        self.shared.lock().unwrap().no_ranges = true;
+        self
+    }
+
+    pub fn url(&self) -> String {
+        self.url_of("/update_ota.bin")
+    }
+
+    pub fn url_of(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// `Range` start of every request received so far.
+    pub fn ranges(&self) -> Vec<Option<u64>> {
+        self.shared.lock().unwrap().ranges.clone()
+    }
+}
+
+async fn serve(mut stream: TcpStream, shared: Arc<Mutex<Shared>>) {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    }
+    let request = String::from_utf8_lossy(&request).to_string();
+    let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+    let file = shared.lock().unwrap().files.get(&path).cloned();
+    if let Some(content) = file {
+        let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", content.len());
+        let _ = stream.write_all(header.as_bytes()).await;
+        let _ = stream.write_all(&content).await;
+        let _ = stream.shutdown().await;
+        return;
+    }
+    if path != "/update_ota.bin" {
+        let _ = stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+        return;
+    }
+    let range: Option<u64> = request.lines()
+        .find_map(|line| line.strip_prefix("Range: bytes=").or_else(|| line.strip_prefix("range: bytes=")))
+        .and_then(|value| value.trim_end_matches('-').parse().ok());
+
+    let (body, start, cut, stall) = {
+        let mut shared = shared.lock().unwrap();
+        shared.ranges.push(range);
+        let body = shared.body.clone();
+        let start = if shared.no_ranges { None } else { range };
+        let cut = match &mut shared.cut {
+            Some((bytes, remaining)) if *remaining > 0 => {
+                *remaining -= 1;
+                Some(*bytes)
+            }
+            _ => None,
+        };
+        (body, start, cut, shared.stall)
+    };
+
+    let header = match start {
+        Some(start) if start as usize >= body.len() => {
+            let header = format!("HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", body.len());
+            let _ = stream.write_all(header.as_bytes()).await;
+            return;
+        }
+        Some(start) => format!(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\nETag: \"test\"\r\nConnection: close\r\n\r\n",
+            start, body.len() - 1, body.len(), body.len() - start as usize
+        ),
+        None => format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nETag: \"test\"\r\nConnection: close\r\n\r\n",
+            body.len()
+        ),
+    };
+    let content = &body[start.unwrap_or(0) as usize..];
+    let content = match cut {
+        Some(bytes) => &content[..bytes.min(content.len())],
+        None => content,
+    };
+    let _ = stream.write_all(header.as_bytes()).await;
+    let _ = stream.write_all(content).await;
+    if stall && cut.is_some() {
+        std::future::pending::<()>().await;
+    }
+    let _ = stream.shutdown().await;
+}
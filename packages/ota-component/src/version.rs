@@ -0,0 +1,113 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{OtaErr, OtaError};
+use crate::state::write_atomic;
+
+/// Parses a firmware version name such as `1.2.0` or `v1.2.0\n`.
+pub fn parse(name: &str) -> Result<Version, OtaErr> {
+    let name = name.trim();
+    Version::parse(name.strip_prefix('v').unwrap_or(name)).map_err(|_| {
+        log::error!("Invalid version {:?}", name);
+        OtaErr::VersionErr
+    })
+}
+
+/// Reads the version name of the running firmware.
+pub fn read_running(path: &Path) -> Result<String, OtaError> {
+    fs::read_to_string(path)
+        .map(|name| name.trim().to_string())
+        .map_err(|e| OtaError::io(OtaErr::VersionErr, path, e))
+}
+
+/// Records the version name of the firmware now running.
+pub fn write_running(path: &Path, name: &str) -> Result<(), OtaError> {
+    write_atomic(path, format!("{}\n", name).as_bytes()).map_err(|e| OtaError::io(OtaErr::VersionErr, path, e))
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CounterFile {
+    security_counter: u16,
+}
+
+/// Anti-rollback counter: the highest `version_number` of any installed
+/// update. Images with a `version_number` below it are never installed.
+/// The file only ever moves up.
+pub struct CounterStore {
+    path: PathBuf,
+}
+
+impl CounterStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        CounterStore { path: path.into() }
+    }
+
+    pub fn load(&self) -> u16 {
+        match fs::read(&self.path) {
+            Ok(content) => match serde_json::from_slice::<CounterFile>(&content) {
+                Ok(file) => file.security_counter,
+                Err(e) => {
+                    log::error!("Corrupted security counter {}: {}", self.path.display(), e);
+                    0
+                }
+            },
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::error!("Failed to read security counter {}: {}", self.path.display(), e);
+                }
+                0
+            }
+        }
+    }
+
+    /// Raises the stored counter to `counter`, never lowers it.
+    pub fn save(&self, counter: u16) -> Result<(), OtaError> {
+        let current = self.load();
+        if counter <= current {
+            return Ok(());
+        }
+        let data = serde_json::to_vec(&CounterFile { security_counter: counter })
+            .map_err(|e| OtaError::new(OtaErr::StateErr).with_path(&self.path).with_source(e))?;
+        write_atomic(&self.path, &data).map_err(|e| OtaError::io(OtaErr::StateErr, &self.path, e))?;
+        log::info!("Security counter raised from {} to {}", current, counter);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("1.2.0").unwrap(), Version::new(1, 2, 0));
+        assert_eq!(parse("v1.10.0\n").unwrap(), Version::new(1, 10, 0));
+        assert!(parse("1.10.0").unwrap() > parse("1.9.3").unwrap());
+        assert!(parse("1.2.0-rc.1").unwrap() < parse("1.2.0").unwrap());
+        assert_eq!(parse(""), Err(OtaErr::VersionErr));
+        assert_eq!(parse("1.2"), Err(OtaErr::VersionErr));
+    }
+
+    #[test]
+    fn test_counter_only_moves_up() {
+        let dir = std::env::temp_dir().join(format!("ota-version-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let store = CounterStore::new(dir.join("security_counter.json"));
+        assert_eq!(store.load(), 0);
+
+        store.save(3).unwrap();
+        assert_eq!(store.load(), 3);
+        store.save(2).unwrap();
+        assert_eq!(store.load(), 3);
+        store.save(5).unwrap();
+        assert_eq!(CounterStore::new(dir.join("security_counter.json")).load(), 5);
+
+        fs::write(dir.join("version.txt"), "2.1.1\n").unwrap();
+        assert_eq!(read_running(&dir.join("version.txt")), Ok("2.1.1".to_string()));
+        let e = read_running(&dir.join("missing.txt")).unwrap_err();
+        assert_eq!((e.kind, e.path), (OtaErr::VersionErr, Some(dir.join("missing.txt"))));
+    }
+}
@@ -0,0 +1,34 @@
+use clap::{Parser, Subcommand};
+
+pub mod pack;
+pub mod verify;
+
+/// Builds and checks signed firmware packages for the OTA component.
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Sign a firmware image and write the release package
+    Pack(Box<pack::PackArgs>),
+    /// Check a release package against the vendor public key
+    Verify(verify::VerifyArgs),
+}
+
+fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let args = Args::parse();
+    let result = match args.command {
+        Command::Pack(args) => pack::run(&args).map(|_| ()),
+        Command::Verify(args) => verify::run(&args),
+    };
+    if let Err(e) = result {
+        log::error!("{:?}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,299 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use ota_package::delta;
+use ota_package::manifest::{self, Delta, Manifest, SignedManifest, MANIFEST_FILE};
+use ota_package::signature;
+use ota_package::PackageErr;
+
+/// Entry the check-update server returns to controllers, written as
+/// `update.json` next to the manifest.
+pub const UPDATE_FILE: &str = "update.json";
+
+#[derive(Debug, Args)]
+pub struct PackArgs {
+    /// Firmware image to sign
+    #[arg(long)]
+    pub image: PathBuf,
+    /// Version name, e.g. 1.2.0
+    #[arg(long = "version-name")]
+    pub version_name: String,
+    #[arg(long = "version-number")]
+    pub version_number: u16,
+    #[arg(long = "version-min-id", default_value_t = 0)]
+    pub version_min_id: u16,
+    /// Controller type the image is built for, repeat for several
+    #[arg(long = "hc-type", required = true)]
+    pub hc_types: Vec<String>,
+    /// Oldest version the update may be installed over
+    #[arg(long = "min-version", default_value = "")]
+    pub min_version: String,
+    /// Let controllers running a newer version install this one
+    #[arg(long = "allow-downgrade")]
+    pub allow_downgrade: bool,
+    /// Text file with the release notes
+    #[arg(long = "release-notes")]
+    pub release_notes: Option<PathBuf>,
+    /// Days until controllers refuse to install the update
+    #[arg(long = "expires-days")]
+    pub expires_days: Option<u64>,
+    /// Vendor private key (PEM)
+    #[arg(long, env = "OTA_SIGNING_KEY")]
+    pub key: PathBuf,
+    /// Directory the package is written to
+    #[arg(long)]
+    pub out: PathBuf,
+    /// URL the package directory is published under
+    #[arg(long = "base-url")]
+    pub base_url: String,
+    /// Identifies this release to controllers, defaults to the current time
+    #[arg(long = "download-id")]
+    pub download_id: Option<u64>,
+    /// Append the signature to the image instead of only a separate `.sig`
+    #[arg(long = "embed-signature")]
+    pub embed_signature: bool,
+    /// File name prefix of the published image
+    #[arg(long, default_value = "firmware")]
+    pub name: String,
+    /// Also publish a patch from an older version, as `<version>=<image>`
+    /// with the image as installed (unsigned). Repeat for several.
+    #[arg(long = "delta", value_parser = parse_delta)]
+    pub deltas: Vec<(String, PathBuf)>,
+}
+
+fn parse_delta(value: &str) -> Result<(String, PathBuf), String> {
+    match value.split_once('=') {
+        Some((version, image)) if !version.is_empty() && !image.is_empty() => Ok((version.to_string(), PathBuf::from(image))),
+        _ => Err("expected <version>=<image>".to_string()),
+    }
+}
+
+/// Mirrors `Data` of the OTA component's check-update response.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UpdateEntry {
+    pub version_number: u16,
+    pub version_name: String,
+    pub version_min_id: u16,
+    pub download_id: u64,
+    pub link: String,
+    pub checksum: String,
+    pub signature_link: String,
+    pub manifest: SignedManifest,
+}
+
+pub(crate) fn read(path: &Path) -> Result<Vec<u8>, PackageErr> {
+    fs::read(path).map_err(|e| {
+        log::error!("Failed to read {}: {}", path.display(), e);
+        PackageErr::IoErr
+    })
+}
+
+fn write(path: &Path, data: &[u8]) -> Result<(), PackageErr> {
+    fs::write(path, data).map_err(|e| {
+        log::error!("Failed to write {}: {}", path.display(), e);
+        PackageErr::IoErr
+    })
+}
+
+pub(crate) fn open(path: &Path) -> Result<File, PackageErr> {
+    File::open(path).map_err(|e| {
+        log::error!("Failed to open {}: {}", path.display(), e);
+        PackageErr::IoErr
+    })
+}
+
+pub fn run(args: &PackArgs) -> Result<Manifest, PackageErr> {
+    let key = signature::private_key_from_pem(&read(&args.key)?).inspect_err(|_| {
+        log::error!("{} is not a PEM private key", args.key.display());
+    })?;
+    let signature = signature::sign(&key, open(&args.image)?)?;
+
+    fs::create_dir_all(&args.out).map_err(|_| PackageErr::IoErr)?;
+    let image_name = format!("{}-{}.bin", args.name, args.version_name);
+    let signature_name = format!("{}.sig", image_name);
+    let image_path = args.out.join(&image_name);
+
+    let mut image = read(&args.image)?;
+    if args.embed_signature {
+        image.extend_from_slice(&signature::trailer(&signature));
+    }
+    write(&image_path, &image)?;
+    write(&args.out.join(&signature_name), &signature)?;
+
+    let release_notes = match &args.release_notes {
+        Some(path) => String::from_utf8(read(path)?).map_err(|_| PackageErr::FormatErr)?,
+        None => String::new(),
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    let (size, sha256) = manifest::sha256(&image[..])?;
+    let (_, sha512) = manifest::sha512(&image[..])?;
+    let mut deltas = Vec::with_capacity(args.deltas.len());
+    for (base_version, base_path) in &args.deltas {
+        let base = read(base_path)?;
+        let patch = delta::diff(&base, &image)?;
+        let patch_name = format!("{}-{}-{}.patch", args.name, base_version, args.version_name);
+        write(&args.out.join(&patch_name), &patch)?;
+        let (base_size, base_sha256) = manifest::sha256(&base[..])?;
+        let (size, sha256) = manifest::sha256(&patch[..])?;
+        log::info!("Patch from {}: {} bytes", base_version, size);
+        deltas.push(Delta { base_version: base_version.clone(), base_size, base_sha256, patch: patch_name, size, sha256 });
+    }
+    let manifest = Manifest {
+        version_name: args.version_name.clone(),
+        version_number: args.version_number,
+        version_min_id: args.version_min_id,
+        hc_types: args.hc_types.clone(),
+        min_version: args.min_version.clone(),
+        allow_downgrade: args.allow_downgrade,
+        release_notes,
+        created_at: now,
+        expires_at: args.expires_days.map(|days| now + days * 24 * 60 * 60),
+        image: image_name.clone(),
+        size,
+        sha256,
+        sha512,
+        signature: signature_name.clone(),
+        embedded_signature: args.embed_signature,
+        deltas,
+    };
+    let signed = SignedManifest::sign(&manifest, &key).inspect_err(|_| {
+        log::error!("Incomplete manifest {:?}", manifest);
+    })?;
+    write(&args.out.join(MANIFEST_FILE), &signed.to_json()?)?;
+
+    let base_url = args.base_url.trim_end_matches('/');
+    let download_id = args.download_id.unwrap_or(now);
+    let entry = UpdateEntry {
+        version_number: manifest.version_number,
+        version_name: manifest.version_name.clone(),
+        version_min_id: manifest.version_min_id,
+        download_id,
+        link: format!("{}/{}", base_url, image_name),
+        checksum: manifest.checksum(),
+        // An embedded signature covers the image without its trailer, the
+        // detached one would not match the downloaded file
+        signature_link: if args.embed_signature { String::new() } else { format!("{}/{}", base_url, signature_name) },
+        manifest: signed,
+    };
+    let entry = serde_json::to_vec_pretty(&entry).map_err(|_| PackageErr::FormatErr)?;
+    write(&args.out.join(UPDATE_FILE), &entry)?;
+
+    log::info!("Packed {} ({} bytes) into {}", manifest.version_name, manifest.size, args.out.display());
+    Ok(manifest)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::verify::{self, VerifyArgs};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+
+    /// Temporary directory holding an image and an EC key pair.
+    pub(crate) fn setup(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ota-pack-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        fs::write(dir.join("private_key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        fs::write(dir.join("public_key.pem"), key.public_key_to_pem().unwrap()).unwrap();
+        let image: Vec<u8> = (0..100_000).map(|i| (i % 241) as u8).collect();
+        fs::write(dir.join("image.bin"), image).unwrap();
+        dir
+    }
+
+    pub(crate) fn pack_args(dir: &Path, embed_signature: bool) -> PackArgs {
+        PackArgs {
+            image: dir.join("image.bin"),
+            version_name: "1.2.0".to_string(),
+            version_number: 12,
+            version_min_id: 3,
+            hc_types: vec!["Hc01".to_string()],
+            min_version: "1.0.0".to_string(),
+            allow_downgrade: false,
+            release_notes: None,
+            expires_days: Some(30),
+            key: dir.join("private_key.pem"),
+            out: dir.join("dist"),
+            base_url: "https://updates.example.com/hc/".to_string(),
+            download_id: Some(42),
+            embed_signature,
+            name: "hc".to_string(),
+            deltas: Vec::new(),
+        }
+    }
+
+    pub(crate) fn verify_args(dir: &Path) -> VerifyArgs {
+        VerifyArgs { package: dir.join("dist"), key: dir.join("public_key.pem") }
+    }
+
+    #[test]
+    fn test_pack_detached() {
+        let dir = setup("detached");
+        let manifest = run(&pack_args(&dir, false)).unwrap();
+        assert_eq!(fs::read(dir.join("dist/hc-1.2.0.bin")).unwrap(), fs::read(dir.join("image.bin")).unwrap());
+        assert_eq!(manifest.size, 100_000);
+
+        let entry: UpdateEntry = serde_json::from_slice(&fs::read(dir.join("dist").join(UPDATE_FILE)).unwrap()).unwrap();
+        let signed = SignedManifest::from_json(&fs::read(dir.join("dist").join(MANIFEST_FILE)).unwrap()).unwrap();
+        assert_eq!(entry, UpdateEntry {
+            version_number: 12,
+            version_name: "1.2.0".to_string(),
+            version_min_id: 3,
+            download_id: 42,
+            link: "https://updates.example.com/hc/hc-1.2.0.bin".to_string(),
+            checksum: manifest.checksum(),
+            signature_link: "https://updates.example.com/hc/hc-1.2.0.bin.sig".to_string(),
+            manifest: signed,
+        });
+        assert_eq!(manifest.hc_types, vec!["Hc01".to_string()]);
+        assert_eq!(manifest.expires_at, Some(manifest.created_at + 30 * 24 * 60 * 60));
+        assert_eq!(verify::run(&verify_args(&dir)), Ok(()));
+    }
+
+    #[test]
+    fn test_pack_embedded() {
+        let dir = setup("embedded");
+        let manifest = run(&pack_args(&dir, true)).unwrap();
+        assert!(manifest.size > 100_000);
+        fs::write(dir.join("notes.txt"), "Faster boot").unwrap();
+        let args = PackArgs { release_notes: Some(dir.join("notes.txt")), ..pack_args(&dir, true) };
+        let manifest = run(&args).unwrap();
+        assert_eq!(manifest.release_notes, "Faster boot");
+        let entry: UpdateEntry = serde_json::from_slice(&fs::read(dir.join("dist").join(UPDATE_FILE)).unwrap()).unwrap();
+        assert_eq!(entry.signature_link, "");
+        assert_eq!(verify::run(&verify_args(&dir)), Ok(()));
+    }
+
+    #[test]
+    fn test_pack_delta() {
+        let dir = setup("delta");
+        let base: Vec<u8> = (0..100_000).map(|i| (i % 241) as u8 ^ u8::from(i % 5_000 == 0)).collect();
+        fs::write(dir.join("base.bin"), &base).unwrap();
+        let args = PackArgs { deltas: vec![("1.1.0".to_string(), dir.join("base.bin"))], ..pack_args(&dir, true) };
+        let manifest = run(&args).unwrap();
+
+        let delta = &manifest.deltas[0];
+        assert_eq!((delta.base_version.as_str(), delta.base_size), ("1.1.0", 100_000));
+        assert_eq!(delta.patch, "hc-1.1.0-1.2.0.patch");
+        assert!(delta.size < 10_000);
+        let mut patched = Vec::new();
+        delta::apply(&base, open(&dir.join("dist").join(&delta.patch)).unwrap(), &mut patched).unwrap();
+        assert_eq!(patched, fs::read(dir.join("dist").join(&manifest.image)).unwrap());
+        assert_eq!(verify::run(&verify_args(&dir)), Ok(()));
+
+        assert!(parse_delta("1.1.0").is_err());
+        assert_eq!(parse_delta("1.1.0=old.bin"), Ok(("1.1.0".to_string(), PathBuf::from("old.bin"))));
+    }
+
+    #[test]
+    fn test_bad_key() {
+        let dir = setup("badkey");
+        let args = PackArgs { key: dir.join("public_key.pem"), ..pack_args(&dir, false) };
+        assert_eq!(run(&args).err(), Some(PackageErr::KeyErr));
+    }
+}
@@ -0,0 +1,111 @@
+use std::io::Read;
+use std::path::PathBuf;
+use clap::Args;
+use ota_package::manifest::{self, SignedManifest, MANIFEST_FILE};
+use ota_package::signature;
+use ota_package::PackageErr;
+
+use crate::pack::{open, read, UpdateEntry, UPDATE_FILE};
+
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    /// Package directory written by `pack`
+    #[arg(long)]
+    pub package: PathBuf,
+    /// Vendor public key (PEM)
+    #[arg(long, env = "OTA_VERIFY_KEY")]
+    pub key: PathBuf,
+}
+
+/// Checks the image against the manifest and its signature the way a
+/// controller would, and that `update.json` points at this package.
+pub fn run(args: &VerifyArgs) -> Result<(), PackageErr> {
+    let key = signature::public_key_from_pem(&read(&args.key)?).inspect_err(|_| {
+        log::error!("{} is not a PEM public key", args.key.display());
+    })?;
+    let signed = SignedManifest::from_json(&read(&args.package.join(MANIFEST_FILE))?)?;
+    let manifest = signed.open(&key).inspect_err(|e| {
+        log::error!("{} is not a valid signed manifest: {:?}", MANIFEST_FILE, e);
+    })?;
+    let image_path = args.package.join(&manifest.image);
+
+    let (size, sha256) = manifest::sha256(open(&image_path)?)?;
+    let (_, sha512) = manifest::sha512(open(&image_path)?)?;
+    if size != manifest.size || sha256 != manifest.sha256 || sha512 != manifest.sha512 {
+        log::error!("{} does not match the manifest", image_path.display());
+        return Err(PackageErr::ChecksumErr);
+    }
+
+    let detached = read(&args.package.join(&manifest.signature))?;
+    if manifest.embedded_signature {
+        let mut image = open(&image_path)?;
+        let (image_len, embedded) = signature::read_trailer(&mut image, size)?.ok_or_else(|| {
+            log::error!("{} has no signature trailer", image_path.display());
+            PackageErr::UnsignedErr
+        })?;
+        if embedded != detached {
+            log::error!("Embedded and detached signatures differ");
+            return Err(PackageErr::VerifyNotEqualErr);
+        }
+        signature::verify(&key, open(&image_path)?.take(image_len), &embedded)?;
+    } else {
+        signature::verify(&key, open(&image_path)?, &detached)?;
+    }
+
+    for delta in &manifest.deltas {
+        let (size, sha256) = manifest::sha256(open(&args.package.join(&delta.patch))?)?;
+        if size != delta.size || sha256 != delta.sha256 {
+            log::error!("Patch {} does not match the manifest", delta.patch);
+            return Err(PackageErr::ChecksumErr);
+        }
+    }
+
+    let entry: UpdateEntry = serde_json::from_slice(&read(&args.package.join(UPDATE_FILE))?)
+        .map_err(|_| PackageErr::FormatErr)?;
+    if entry.version_name != manifest.version_name
+        || entry.checksum != manifest.checksum()
+        || entry.manifest != signed
+        || !entry.link.ends_with(&format!("/{}", manifest.image))
+    {
+        log::error!("{} does not describe this package", UPDATE_FILE);
+        return Err(PackageErr::FormatErr);
+    }
+
+    log::info!("{} {} verified", manifest.image, manifest.version_name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use crate::pack::{self, test::{pack_args, setup, verify_args}};
+
+    #[test]
+    fn test_tampered_image() {
+        let dir = setup("tampered");
+        let manifest = pack::run(&pack_args(&dir, false)).unwrap();
+        let image_path = dir.join("dist").join(&manifest.image);
+        let mut image = fs::read(&image_path).unwrap();
+        image[10] ^= 1;
+        fs::write(&image_path, &image).unwrap();
+        assert_eq!(run(&verify_args(&dir)), Err(PackageErr::ChecksumErr));
+
+        // Manifest edited to match, its signature no longer does
+        let manifest_path = dir.join("dist").join(MANIFEST_FILE);
+        let signed = SignedManifest::from_json(&fs::read(&manifest_path).unwrap()).unwrap();
+        let (_, sha256) = manifest::sha256(&image[..]).unwrap();
+        let signed = SignedManifest { payload: signed.payload.replace(&manifest.sha256, &sha256), ..signed };
+        fs::write(&manifest_path, signed.to_json().unwrap()).unwrap();
+        assert_eq!(run(&verify_args(&dir)), Err(PackageErr::VerifyNotEqualErr));
+    }
+
+    #[test]
+    fn test_other_key() {
+        let dir = setup("otherkey");
+        pack::run(&pack_args(&dir, true)).unwrap();
+        let other = setup("otherkey-other");
+        let args = VerifyArgs { key: other.join("public_key.pem"), ..verify_args(&dir) };
+        assert_eq!(run(&args), Err(PackageErr::VerifyNotEqualErr));
+    }
+}